@@ -40,6 +40,7 @@ i18n
           "add-data-source": "Add data source",
           "edit-data-source": "Edit data source",
           "data-source-title": "Data source",
+          "data-task-name": "Name",
           "data-share-link": "Share link",
           "data-share-link-help": "How to get share link",
           "data-share-link-ondrive-not-working":
@@ -57,11 +58,22 @@ i18n
             "You cannot select a time that is in the future.",
           "error-upload-token":
             "Failed to load uploaded file, please reupload and try again.",
+          "error-upload-quota":
+            "Out of storage space, please delete some snapshots and try again.",
           "error-data-share-link": "The given share link is invalid",
           "error-data-folder-structure": "The given share link is invalid",
+          "error-data-task-name": "The name must be 1 to 64 characters",
+          "error-data-too-many-tasks": "You can't add more data sources",
+          "error-notification-webhook-url":
+            "The webhook URL must be a public http(s) address",
           "error-unknown": "Unknown Error",
           "error-title": "Error",
           "success-title": "Success",
+          "notification-title": "Notifications",
+          "notification-by-email": "Email me when syncing keeps failing",
+          "notification-webhook-url": "Webhook URL",
+          "notification-webhook-secret": "Webhook secret",
+          "notification-saved": "Saved",
           "sync-status-running": "Running",
           "sync-status-paused": "Paused",
           "sync-status-stopped": "Stopped",
@@ -81,6 +93,10 @@ i18n
           "snapshot-list-title": "Snapshots",
           "snapshot-list-date": "Date",
           "snapshot-list-note": "Note",
+          "snapshot-list-explored-area": "Explored area",
+          "snapshot-list-explored-area-km2": "{{area}} km²",
+          "snapshot-list-explored-area-details":
+            "Level {{level}}, {{tiles}} tiles, {{blocks}} blocks",
           "snapshot-list-source": "Source",
           "snapshot-list-source-sync": "Sync",
           "snapshot-list-source-upload": "Upload",
@@ -105,6 +121,28 @@ i18n
           "log-list-snapshot-id": "Snapshot id",
           "log-list-succeed": "Succeedd",
           "log-list-details": "Details",
+          "log-report-lock-no_lock_file": "No lock file",
+          "log-report-lock-in_progress": "Fog of World is syncing",
+          "log-report-lock-idle": "Fog of World is idle",
+          "log-report-lock-lock_file":
+            "Unknown lock file, modified {{minutes}} min ago, treated as {{status}}",
+          "log-report-lock-locked": "locked",
+          "log-report-lock-expired": "expired",
+          "log-report-unexpected-folder": "Unexpected folder: {{name}}",
+          "log-report-unexpected-file": "Unexpected file: {{name}}",
+          "log-report-invalid-file": "Invalid file, skipped: {{name}} ({{reason}})",
+          "log-report-synced":
+            "New files: {{newFiles}}/{{files}}, downloaded {{size}} KiB",
+          "log-report-unchanged": "No change since last sync",
+          "log-report-locked": "Fog of World is syncing, failed to sync",
+          "log-report-interrupted": "Interrupted by server shutdown",
+          "log-report-error-transient": "Error",
+          "log-report-error-throttled": "Too many requests",
+          "log-report-error-locked": "Locked",
+          "log-report-error-permanent": "Error",
+          "log-report-error-quota_exceeded": "Out of storage",
+          "log-report-retry": "Retrying in {{minutes}} min",
+          "log-report-stopped": "Task stopped",
           "error-page-404-text": "Page not found (404)",
           "error-page-404-button": "Back to home",
         },
@@ -126,6 +164,7 @@ i18n
           "add-data-source": "添加数据源",
           "edit-data-source": "编辑数据源",
           "data-source-title": "数据源",
+          "data-task-name": "名称",
           "data-share-link": "共享链接",
           "data-share-link-help": "如何获取共享链接",
           "data-share-link-ondrive-not-working": "OneDrive共享链接暂不可用",
@@ -139,11 +178,20 @@ i18n
           "data-upload-success": "上传成功!",
           "error-upload-timestamp": "不能选择未来的时间.",
           "error-upload-token": "上传文件失败，请重试.",
+          "error-upload-quota": "存储空间不足，请删除一些快照后重试.",
           "error-data-share-link": "共享链接无效",
           "error-data-folder-structure": "共享链接无效",
+          "error-data-task-name": "名称长度须为1到64个字符",
+          "error-data-too-many-tasks": "无法添加更多数据源",
+          "error-notification-webhook-url": "Webhook地址须为公网的http(s)地址",
           "error-unknown": "未知错误",
           "error-title": "错误",
           "success-title": "成功",
+          "notification-title": "通知",
+          "notification-by-email": "同步持续失败时发送邮件",
+          "notification-webhook-url": "Webhook地址",
+          "notification-webhook-secret": "Webhook密钥",
+          "notification-saved": "已保存",
           "sync-status-running": "运行中",
           "sync-status-paused": "暂停",
           "sync-status-stopped": "停止",
@@ -163,6 +211,10 @@ i18n
           "snapshot-list-title": "快照列表",
           "snapshot-list-date": "时间",
           "snapshot-list-note": "备注",
+          "snapshot-list-explored-area": "探索面积",
+          "snapshot-list-explored-area-km2": "{{area}} 平方公里",
+          "snapshot-list-explored-area-details":
+            "等级 {{level}}，{{tiles}} 个瓦片，{{blocks}} 个区块",
           "snapshot-list-source": "来源",
           "snapshot-list-source-sync": "同步",
           "snapshot-list-source-upload": "上传",
@@ -185,6 +237,28 @@ i18n
           "log-list-snapshot-id": "快照ID",
           "log-list-succeed": "任务结果",
           "log-list-details": "日志",
+          "log-report-lock-no_lock_file": "无锁文件",
+          "log-report-lock-in_progress": "世界迷雾正在同步",
+          "log-report-lock-idle": "世界迷雾未在同步",
+          "log-report-lock-lock_file":
+            "无法识别的锁文件，修改于 {{minutes}} 分钟前，视为{{status}}",
+          "log-report-lock-locked": "已锁定",
+          "log-report-lock-expired": "已过期",
+          "log-report-unexpected-folder": "未知文件夹：{{name}}",
+          "log-report-unexpected-file": "未知文件：{{name}}",
+          "log-report-invalid-file": "文件损坏，已跳过：{{name}}（{{reason}}）",
+          "log-report-synced":
+            "新文件：{{newFiles}}/{{files}}，已下载 {{size}} KiB",
+          "log-report-unchanged": "自上次同步以来没有变化",
+          "log-report-locked": "世界迷雾正在同步，同步失败",
+          "log-report-interrupted": "服务器关闭，同步中断",
+          "log-report-error-transient": "错误",
+          "log-report-error-throttled": "请求过于频繁",
+          "log-report-error-locked": "已锁定",
+          "log-report-error-permanent": "错误",
+          "log-report-error-quota_exceeded": "存储空间不足",
+          "log-report-retry": "{{minutes}} 分钟后重试",
+          "log-report-stopped": "任务已停止",
           "error-page-404-text": "页面无法找到 (404)",
           "error-page-404-button": "返回主页",
         },
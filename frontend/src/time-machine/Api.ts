@@ -28,7 +28,13 @@ type UnknownError = {
 
 type Result<T> = Ok<T> | Error | UnknownError;
 
-type UserInfo = { language: "zh-cn" | "en-us" };
+export type UserInfo = {
+  language: "zh-cn" | "en-us";
+  notifyByEmail: boolean;
+  notificationWebhookUrl: string | null;
+  // webhook calls are signed with it, see `X-Fog-Machine-Signature`
+  notificationWebhookSecret: string;
+};
 
 type GithubSsoResponse = {
   login: boolean;
@@ -38,10 +44,16 @@ type GithubSsoResponse = {
 };
 
 export type SnapshotTask = {
+  id: number;
+  name: string;
   errorCount: number;
   interval: number;
   lastSuccessSync: Date | null;
-  source: { OneDrive: { shareUrl: string } };
+  // credentials are never sent back
+  source:
+    | { OneDrive: { shareUrl: string } }
+    | { WebDav: { url: string; username: string } }
+    | { LocalPath: { path: string } };
   status: "Running" | "Paused" | "Stopped";
 };
 
@@ -50,6 +62,11 @@ export type Snapshot = {
   timestamp: Date;
   sourceKind: "Sync" | "Upload";
   note: string | null;
+  // `null` for old snapshots until the server fills them in
+  exploredAreaKm2: number | null;
+  tileCount: number | null;
+  blockCount: number | null;
+  level: number | null;
 };
 
 export type SnapshotList = {
@@ -58,9 +75,31 @@ export type SnapshotList = {
   snapshots: Snapshot[];
 };
 
+export type TaskLogReport = {
+  outcome: "synced" | "unchanged" | "locked" | "failed" | "interrupted";
+  lock:
+    | { kind: "no_lock_file" | "in_progress" | "idle" }
+    | { kind: "lock_file"; ageMinutes: number; locked: boolean }
+    | null;
+  files: number;
+  newFiles: number;
+  bytesDownloaded: number;
+  durationMs: number;
+  unexpectedFiles: string[];
+  unexpectedFolders: string[];
+  invalidFiles: { name: string; reason: string }[];
+  error: {
+    class: "transient" | "throttled" | "locked" | "permanent" | "quota_exceeded";
+    message: string;
+  } | null;
+  retry: { kind: "after"; minutes: number } | { kind: "stopped" } | null;
+};
+
 export type TaskLog = {
   id: number;
   details: string | null;
+  // `null` for old logs, `details` is the plain text version
+  report: TaskLogReport | null;
   snapshotId: number | null;
   succeed: boolean;
   timestamp: Date;
@@ -69,6 +108,7 @@ export type TaskLog = {
 
 export type TaskLogList = {
   snapshotLogs: TaskLog[];
+  nextCursor: number | null;
 };
 
 export type SnapshotUploadResult = {
@@ -143,12 +183,26 @@ export default class Api {
 
     const result = await this.requestApi("user", "get", true);
     if (result.ok) {
-      return result.ok;
+      return toCamel(result.ok) as any;
     } else {
       return null;
     }
   }
 
+  public static async updateNotificationSettings(
+    notifyByEmail: boolean,
+    notificationWebhookUrl: string | null
+  ): Promise<Result<"ok">> {
+    const data: any = {};
+    data["notify_by_email"] = notifyByEmail;
+    data["notification_webhook_url"] = notificationWebhookUrl;
+    const result = await this.requestApi("user", "patch", true, data);
+    if (result.ok) {
+      result.ok = "ok";
+    }
+    return result;
+  }
+
   public static async githubSso(
     code: string
   ): Promise<Result<GithubSsoResponse>> {
@@ -190,27 +244,32 @@ export default class Api {
     return result;
   }
 
-  public static async getSnapshotTask(): Promise<
-    Result<SnapshotTask | "none">
-  > {
-    let result = await this.requestApi("snapshot_task", "get", true);
+  public static async listSnapshotTasks(): Promise<Result<SnapshotTask[]>> {
+    const result = await this.requestApi("snapshot_task", "get", true);
     if (result.ok) {
-      result.ok = toCamel(result.ok);
-      if (result.ok.source["OneDrive"]) {
-        result.ok.source["OneDrive"] = toCamel(result.ok.source["OneDrive"]);
-      }
-    } else if (result.status == 404) {
-      result = { ok: "none", status: 404 };
+      result.ok = result.ok["snapshot_tasks"].map((task: any) => {
+        task = toCamel(task);
+        // the source is keyed by its kind, which must not be converted
+        for (const kind of Object.keys(task.source)) {
+          task.source[kind] = toCamel(task.source[kind]);
+        }
+        return task;
+      });
     }
     return result;
   }
 
   public static async updateSnapshotTask(
+    taskId: number,
+    name: string | null,
     interval: number | null,
     status: "Running" | "Paused" | null,
     oneDriveShareUrl: string | null
   ): Promise<Result<"ok">> {
     const data: any = {};
+    if (name) {
+      data["name"] = name;
+    }
     if (interval) {
       data["interval"] = interval;
     }
@@ -225,15 +284,26 @@ export default class Api {
       };
     }
 
-    const result = await this.requestApi("snapshot_task", "patch", true, data);
+    const result = await this.requestApi(
+      "snapshot_task/" + String(taskId),
+      "patch",
+      true,
+      data
+    );
     if (result.ok) {
       result.ok = "ok";
     }
     return result;
   }
 
-  public static async listTaskLog(): Promise<Result<TaskLogList>> {
-    const result = await this.requestApi("snapshot_log", "get", true);
+  public static async listTaskLog(
+    snapshotTaskId: number
+  ): Promise<Result<TaskLogList>> {
+    const result = await this.requestApi(
+      "snapshot_log?snapshot_task_id=" + String(snapshotTaskId),
+      "get",
+      true
+    );
     if (result.ok) {
       result.ok = toCamel(result.ok);
     }
@@ -287,10 +357,12 @@ export default class Api {
   }
 
   public static async createSnapshotTask(
+    name: string,
     interval: number,
     oneDriveShareUrl: string
-  ): Promise<Result<"ok">> {
+  ): Promise<Result<number>> {
     const data: any = {};
+    data["name"] = name;
     data["interval"] = interval;
     data["source"] = {
       OneDrive: toSnake({
@@ -300,13 +372,20 @@ export default class Api {
 
     const result = await this.requestApi("snapshot_task", "post", true, data);
     if (result.ok) {
-      result.ok = "ok";
+      result.ok = result.ok.id;
     }
     return result;
   }
 
-  public static async deleteSnapshotTask(): Promise<Result<"ok">> {
-    const result = await this.requestApi("snapshot_task", "delete", true, {});
+  public static async deleteSnapshotTask(
+    taskId: number
+  ): Promise<Result<"ok">> {
+    const result = await this.requestApi(
+      "snapshot_task/" + String(taskId),
+      "delete",
+      true,
+      {}
+    );
     if (result.ok) {
       result.ok = "ok";
     }
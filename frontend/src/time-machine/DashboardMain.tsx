@@ -19,7 +19,7 @@ import {
   Whisper,
   Tooltip,
 } from "rsuite";
-import Api, { SnapshotTask, TaskLog, TaskLogList } from "./Api";
+import Api, { SnapshotTask, TaskLog, TaskLogList, TaskLogReport } from "./Api";
 import PauseIcon from "@rsuite/icons/legacy/Pause";
 import FileTextIcon from "@rsuite/icons/legacy/FileText";
 import PlayIcon from "@rsuite/icons/legacy/Play";
@@ -32,20 +32,41 @@ import HelpOutlineIcon from "@rsuite/icons/HelpOutline";
 import CheckRoundIcon from "@rsuite/icons/CheckRound";
 import WarningRoundIcon from "@rsuite/icons/WarningRound";
 import DashboardSnapshot from "./DashboardSnapshot";
+import DashboardNotification from "./DashboardNotification";
 import { useTranslation } from "react-i18next";
+import { TFunction } from "i18next";
 
 const { Column, HeaderCell, Cell } = Table;
 
 type EditModelState = {
   mode: "edit" | "create";
+  name?: string;
   shareLink?: string;
   interval?: number;
 };
 
+const SourceTag: React.FC<{ source: SnapshotTask["source"] }> = ({
+  source,
+}) => {
+  if ("OneDrive" in source) {
+    return (
+      <a href={source.OneDrive.shareUrl} target="_blank">
+        <Tag color="blue">OneDrive</Tag>
+      </a>
+    );
+  } else if ("WebDav" in source) {
+    return <Tag color="cyan">WebDAV</Tag>;
+  } else {
+    return <Tag color="violet">{source.LocalPath.path}</Tag>;
+  }
+};
+
 const MainStatusPanelContent: React.FC<{
   isLoading: boolean;
   setIsLoading: (isLoading: boolean) => void;
+  snapshotTasks: SnapshotTask[];
   snapshotTask: SnapshotTask | null;
+  setSelectedTaskId: (id: number) => void;
   setOpenEditModel: (isOpen: boolean) => void;
   setOpenLogModel: (isOpen: boolean) => void;
   setEditModelState: (state: EditModelState) => void;
@@ -55,7 +76,9 @@ const MainStatusPanelContent: React.FC<{
 }> = ({
   isLoading,
   setIsLoading,
+  snapshotTasks,
   snapshotTask,
+  setSelectedTaskId,
   setOpenEditModel,
   setOpenLogModel,
   setEditModelState,
@@ -113,7 +136,13 @@ const MainStatusPanelContent: React.FC<{
 
       const updateStatus = async (status: "Running" | "Paused") => {
         setIsLoading(true);
-        const res = await Api.updateSnapshotTask(null, status, null);
+        const res = await Api.updateSnapshotTask(
+          snapshotTask.id,
+          null,
+          null,
+          status,
+          null
+        );
         if (res.ok != "ok") {
           console.log(res);
         }
@@ -134,11 +163,35 @@ const MainStatusPanelContent: React.FC<{
           </>
           <>
             <Stack direction="column" alignItems="flex-start">
+              <Stack spacing={12} direction="row">
+                <SelectPicker
+                  data={snapshotTasks.map((task) => ({
+                    label: task.name,
+                    value: task.id,
+                  }))}
+                  value={snapshotTask.id}
+                  onChange={(id) => {
+                    if (id != null) {
+                      setSelectedTaskId(id);
+                    }
+                  }}
+                  cleanable={false}
+                  searchable={false}
+                />
+                <IconButton
+                  icon={<AddOutlineIcon />}
+                  placement="left"
+                  onClick={() => {
+                    setOpenEditModel(true);
+                    setEditModelState({ mode: "create" });
+                  }}
+                >
+                  {t("add-data-source")}
+                </IconButton>
+              </Stack>
               <Stack spacing={12} direction="row">
                 <h3>{statusText}</h3>
-                <a href={snapshotTask.source.OneDrive.shareUrl} target="_blank">
-                  <Tag color="blue">OneDrive</Tag>
-                </a>
+                <SourceTag source={snapshotTask.source} />
               </Stack>
               <>{nextSyncMsg}</>
               <Stack
@@ -175,7 +228,7 @@ const MainStatusPanelContent: React.FC<{
                     setLogList(null);
                     setOpenLogModel(true);
                     setIsLogListLoading(true);
-                    const result = await Api.listTaskLog();
+                    const result = await Api.listTaskLog(snapshotTask.id);
                     if (result.ok) {
                       setLogList(result.ok);
                     } else {
@@ -193,7 +246,12 @@ const MainStatusPanelContent: React.FC<{
                     setOpenEditModel(true);
                     setEditModelState({
                       mode: "edit",
-                      shareLink: snapshotTask.source.OneDrive.shareUrl,
+                      name: snapshotTask.name,
+                      // only OneDrive sources can be edited here
+                      shareLink:
+                        "OneDrive" in snapshotTask.source
+                          ? snapshotTask.source.OneDrive.shareUrl
+                          : "",
                       interval: snapshotTask.interval,
                     });
                   }}
@@ -209,17 +267,72 @@ const MainStatusPanelContent: React.FC<{
   }
 };
 
+function renderLogReport(t: TFunction, report: TaskLogReport): string {
+  const lines: string[] = [];
+  if (report.lock?.kind === "lock_file") {
+    lines.push(
+      t("log-report-lock-lock_file", {
+        minutes: report.lock.ageMinutes,
+        status: t(
+          report.lock.locked
+            ? "log-report-lock-locked"
+            : "log-report-lock-expired"
+        ),
+      })
+    );
+  } else if (report.lock) {
+    lines.push(t("log-report-lock-" + report.lock.kind));
+  }
+  report.unexpectedFolders.forEach((name) =>
+    lines.push(t("log-report-unexpected-folder", { name }))
+  );
+  report.unexpectedFiles.forEach((name) =>
+    lines.push(t("log-report-unexpected-file", { name }))
+  );
+  report.invalidFiles.forEach(({ name, reason }) =>
+    lines.push(t("log-report-invalid-file", { name, reason }))
+  );
+  if (report.outcome === "synced") {
+    lines.push(
+      t("log-report-synced", {
+        newFiles: report.newFiles,
+        files: report.files,
+        size: (report.bytesDownloaded / 1024).toFixed(1),
+      })
+    );
+  } else if (report.outcome !== "failed") {
+    lines.push(t("log-report-" + report.outcome));
+  }
+  if (report.error) {
+    lines.push(
+      t("log-report-error-" + report.error.class) + ": " + report.error.message
+    );
+  }
+  if (report.retry?.kind === "after") {
+    lines.push(t("log-report-retry", { minutes: report.retry.minutes }));
+  } else if (report.retry?.kind === "stopped") {
+    lines.push(t("log-report-stopped"));
+  }
+  return lines.join("\n");
+}
+
 function DashboardMain() {
   const { t } = useTranslation();
   const [isLoading, setIsLoading] = useState(false);
-  const [snapshotTask, setSnapshotTask] = useState<SnapshotTask | null>(null);
+  const [snapshotTasks, setSnapshotTasks] = useState<SnapshotTask[]>([]);
+  const [selectedTaskId, setSelectedTaskId] = useState<number | null>(null);
+  // the first one if the selected task is gone
+  const snapshotTask =
+    snapshotTasks.find((task) => task.id == selectedTaskId) ||
+    snapshotTasks[0] ||
+    null;
   const loadData = async (showProgress = true) => {
     if (showProgress) {
       setIsLoading(true);
     }
-    const result = await Api.getSnapshotTask();
+    const result = await Api.listSnapshotTasks();
     if (result.ok) {
-      setSnapshotTask(result.ok == "none" ? null : result.ok);
+      setSnapshotTasks(result.ok);
     } else {
       console.log(result);
     }
@@ -266,6 +379,7 @@ function DashboardMain() {
   }));
 
   const [editFormValue, setEditFormValue] = useState({
+    name: "",
     shareLink: "",
     interval: 720,
     sourceType: "onedrive",
@@ -282,18 +396,21 @@ function DashboardMain() {
 
   const [editButtonLoading, setEditButtonLoading] = useState(false);
   let editFormDefaultValue: {
+    name: string;
     interval: number;
     shareLink: string;
     sourceType: string;
   };
   if (editModelState.mode == "edit") {
     editFormDefaultValue = {
+      name: editModelState.name!,
       interval: editModelState.interval!,
       shareLink: editModelState.shareLink!,
       sourceType: "onedrive",
     };
   } else {
     editFormDefaultValue = {
+      name: editFormValue.name,
       interval: editFormValue.interval!,
       shareLink: editFormValue.shareLink!,
       sourceType: "onedrive",
@@ -306,10 +423,18 @@ function DashboardMain() {
       let res;
       if (editModelState.mode == "create") {
         res = await Api.createSnapshotTask(
+          editFormValue.name,
           editFormValue.interval,
           editFormValue.shareLink
         );
+        if (res.ok) {
+          setSelectedTaskId(res.ok);
+        }
       } else {
+        const name =
+          editFormDefaultValue.name == editFormValue.name
+            ? null
+            : editFormValue.name;
         const interval =
           editFormDefaultValue.interval == editFormValue.interval
             ? null
@@ -318,18 +443,36 @@ function DashboardMain() {
           editFormDefaultValue.shareLink == editFormValue.shareLink
             ? null
             : editFormValue.shareLink;
-        console.log(interval, shareLink);
-        if (interval == null && shareLink == null) {
+        if (
+          (name == null && interval == null && shareLink == null) ||
+          !snapshotTask
+        ) {
           res = { ok: "ok" };
         } else {
-          res = await Api.updateSnapshotTask(interval, null, shareLink);
+          res = await Api.updateSnapshotTask(
+            snapshotTask.id,
+            name,
+            interval,
+            null,
+            shareLink
+          );
         }
       }
       if (res.ok) {
         setOpenEditModel(false);
         await loadData();
       } else {
-        if (res.error == "invalid_share") {
+        if (res.error == "invalid_name") {
+          errorToaster.push(errorNotification(t("error-data-task-name")), {
+            placement: "topCenter",
+            duration: 0,
+          });
+        } else if (res.error == "too_many_tasks") {
+          errorToaster.push(
+            errorNotification(t("error-data-too-many-tasks")),
+            { placement: "topCenter", duration: 0 }
+          );
+        } else if (res.error == "invalid_share") {
           errorToaster.push(errorNotification(t("error-data-share-link")), {
             placement: "topCenter",
             duration: 0,
@@ -354,7 +497,11 @@ function DashboardMain() {
 
   const handleDelete = async () => {
     setEditButtonLoading(true);
-    const res = await Api.deleteSnapshotTask();
+    if (!snapshotTask) {
+      setEditButtonLoading(false);
+      return;
+    }
+    const res = await Api.deleteSnapshotTask(snapshotTask.id);
     if (res.ok) {
       setOpenEditModel(false);
       await loadData();
@@ -375,7 +522,9 @@ function DashboardMain() {
             {...{
               isLoading,
               setIsLoading,
+              snapshotTasks,
               snapshotTask,
+              setSelectedTaskId,
               setOpenEditModel,
               setOpenLogModel,
               setEditModelState,
@@ -387,6 +536,7 @@ function DashboardMain() {
         </div>
       </Panel>
       <DashboardSnapshot />
+      <DashboardNotification />
 
       <Modal
         open={openEditModel}
@@ -427,6 +577,10 @@ function DashboardMain() {
               setEditFormValue(formValue);
             }}
           >
+            <Form.Group controlId="group-task-name">
+              <Form.ControlLabel>{t("data-task-name")}</Form.ControlLabel>
+              <Form.Control name="name" />
+            </Form.Group>
             <Form.Group controlId="group-data-source">
               <Form.ControlLabel>{t("data-source-title")}</Form.ControlLabel>
               <Form.Control
@@ -563,13 +717,16 @@ function DashboardMain() {
               <Cell>
                 {(rawData) => {
                   const logs = rawData as TaskLog;
+                  const details = logs.report
+                    ? renderLogReport(t, logs.report)
+                    : logs.details;
                   return (
                     <Whisper
                       placement="bottom"
                       trigger="hover"
-                      speaker={<Tooltip>{logs.details}</Tooltip>}
+                      speaker={<Tooltip>{details}</Tooltip>}
                     >
-                      <div>{logs.details}</div>
+                      <div>{details}</div>
                     </Whisper>
                   );
                 }}
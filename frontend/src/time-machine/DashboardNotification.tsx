@@ -0,0 +1,109 @@
+import { useState, useEffect } from "react";
+import {
+  Button,
+  Panel,
+  Placeholder,
+  Form,
+  Toggle,
+  useToaster,
+  Notification,
+} from "rsuite";
+import Api, { UserInfo } from "./Api";
+import { useTranslation } from "react-i18next";
+
+function DashboardNotification() {
+  const { t } = useTranslation();
+  const [userInfo, setUserInfo] = useState<UserInfo | null>(null);
+  const [formValue, setFormValue] = useState({
+    notifyByEmail: true,
+    webhookUrl: "",
+  });
+  const [isSaving, setIsSaving] = useState(false);
+  const toaster = useToaster();
+
+  useEffect(() => {
+    const loadData = async () => {
+      const userInfo = await Api.getUserInfo();
+      if (userInfo) {
+        setUserInfo(userInfo);
+        setFormValue({
+          notifyByEmail: userInfo.notifyByEmail,
+          webhookUrl: userInfo.notificationWebhookUrl || "",
+        });
+      }
+    };
+    loadData();
+  }, []);
+
+  const handleSubmit = async (checkStatus: boolean) => {
+    if (!checkStatus) {
+      return;
+    }
+    setIsSaving(true);
+    const res = await Api.updateNotificationSettings(
+      formValue.notifyByEmail,
+      formValue.webhookUrl.trim() || null
+    );
+    let message;
+    if (res.ok) {
+      message = (
+        <Notification type={"success"} header={t("success-title")} closable>
+          {t("notification-saved")}
+        </Notification>
+      );
+    } else {
+      message = (
+        <Notification type={"error"} header={t("error-title")} closable>
+          {res.error == "invalid_webhook_url"
+            ? t("error-notification-webhook-url")
+            : t("error-unknown") + ": " + String(res.unknownError)}
+        </Notification>
+      );
+    }
+    toaster.push(message, { placement: "topCenter" });
+    setIsSaving(false);
+  };
+
+  return (
+    <div style={{ marginTop: "2vh" }}>
+      <Panel header={t("notification-title")} bordered>
+        {!userInfo ? (
+          <Placeholder.Paragraph />
+        ) : (
+          <Form
+            fluid
+            formValue={formValue}
+            onChange={(
+              formValue: any // eslint-disable-line @typescript-eslint/no-explicit-any
+            ) => {
+              setFormValue(formValue);
+            }}
+            onSubmit={handleSubmit}
+          >
+            <Form.Group controlId="group-notify-by-email">
+              <Form.ControlLabel>
+                {t("notification-by-email")}
+              </Form.ControlLabel>
+              <Form.Control name="notifyByEmail" accepter={Toggle} />
+            </Form.Group>
+            <Form.Group controlId="group-webhook-url">
+              <Form.ControlLabel>
+                {t("notification-webhook-url")}
+              </Form.ControlLabel>
+              <Form.Control name="webhookUrl" placeholder="https://" />
+              <Form.HelpText>
+                {t("notification-webhook-secret")}:{" "}
+                <code>{userInfo.notificationWebhookSecret}</code>
+              </Form.HelpText>
+            </Form.Group>
+            <Button type="submit" appearance="primary" loading={isSaving}>
+              {t("data-form-submit")}
+            </Button>
+          </Form>
+        )}
+      </Panel>
+    </div>
+  );
+}
+
+export default DashboardNotification;
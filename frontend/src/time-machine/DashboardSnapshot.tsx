@@ -212,6 +212,39 @@ const SnapshotListPanel: React.FC<{
             </Cell>
           </Column>
 
+          <Column flexGrow={7}>
+            <HeaderCell>{t("snapshot-list-explored-area")}</HeaderCell>
+            <Cell>
+              {(rawData) => {
+                const snapshot = rawData as Snapshot;
+                if (snapshot.exploredAreaKm2 === null) {
+                  return <div>-</div>;
+                }
+                return (
+                  <Whisper
+                    placement="top"
+                    trigger="hover"
+                    speaker={
+                      <Tooltip>
+                        {t("snapshot-list-explored-area-details", {
+                          level: snapshot.level,
+                          tiles: snapshot.tileCount,
+                          blocks: snapshot.blockCount,
+                        })}
+                      </Tooltip>
+                    }
+                  >
+                    <div>
+                      {t("snapshot-list-explored-area-km2", {
+                        area: snapshot.exploredAreaKm2.toFixed(2),
+                      })}
+                    </div>
+                  </Whisper>
+                );
+              }}
+            </Cell>
+          </Column>
+
           <Column flexGrow={7}>
             <HeaderCell>{t("snapshot-list-source")}</HeaderCell>
             <Cell>
@@ -679,6 +712,8 @@ function DashboardSnapshot() {
                           errorMessage = t(
                             "snapshot-list-note-edit-err-tolong"
                           );
+                        } else if (result.error == "storage_quota_exceeded") {
+                          errorMessage = t("error-upload-quota");
                         } else if (result.error == "invalid_upload_token") {
                           errorMessage = t("error-upload-token");
                           // TODO: We should reset the `Uploader` here, but currently this cannot be done because
@@ -1,6 +1,10 @@
 pub use sea_orm;
 
+pub mod marker;
 pub mod snapshot;
+pub mod snapshot_job;
 pub mod snapshot_log;
 pub mod snapshot_task;
+pub mod sync_blob;
+pub mod sync_blob_stat;
 pub mod user;
@@ -0,0 +1,16 @@
+use sea_orm::entity::prelude::*;
+
+/// Things the server only has to do once per database, e.g. `sync_file_gc::backfill_all` of the
+/// server. A row means it is done.
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+#[sea_orm(table_name = "markers")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub name: String,
+    pub created_at: DateTimeUtc,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
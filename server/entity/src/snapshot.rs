@@ -27,9 +27,21 @@ pub struct Model {
     pub timestamp: DateTimeUtc,
     #[sea_orm(indexed)]
     pub source_kind: SourceKind,
+    // The task that created this snapshot, `None` for uploads. The task might be deleted already.
+    #[sea_orm(indexed, nullable)]
+    pub snapshot_task_id: Option<i64>,
     pub note: Option<String>,
     pub sync_files: SyncFiles,
-    // TODO: record level? area?
+    // Stats of the explored area, summed up from `sync_blob_stat`. `None` for snapshots created
+    // before we had them until they are backfilled.
+    #[sea_orm(nullable)]
+    pub explored_area_m2: Option<i64>,
+    #[sea_orm(nullable)]
+    pub tile_count: Option<i32>,
+    #[sea_orm(nullable)]
+    pub block_count: Option<i32>,
+    #[sea_orm(nullable)]
+    pub level: Option<i32>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
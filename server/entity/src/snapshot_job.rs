@@ -0,0 +1,40 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq, Eq, EnumIter, DeriveActiveEnum)]
+#[sea_orm(rs_type = "i16", db_type = "TinyInteger")]
+pub enum Status {
+    #[sea_orm(num_value = 0)]
+    Queued,
+    /// a worker picked up the task, the job is done when the worker finishes.
+    #[sea_orm(num_value = 1)]
+    Running,
+    #[sea_orm(num_value = 2)]
+    Done,
+}
+
+/// A sync requested by the user, see `snapshot_task_handler::run`. A job doesn't run by itself,
+/// it just makes the task due and gets attached to the next run of the task.
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize)]
+#[sea_orm(table_name = "snapshot_jobs")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i64,
+    #[sea_orm(indexed)]
+    pub user_id: i64,
+    #[sea_orm(indexed)]
+    pub snapshot_task_id: i64,
+    #[sea_orm(indexed)]
+    pub status: Status,
+    pub created_at: DateTimeUtc,
+    #[sea_orm(nullable)]
+    pub finished_at: Option<DateTimeUtc>,
+    // `None` if the job is not done yet or the result of the run was discarded.
+    #[sea_orm(nullable)]
+    pub snapshot_log_id: Option<i64>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
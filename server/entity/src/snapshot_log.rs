@@ -1,5 +1,165 @@
-use sea_orm::entity::prelude::*;
-use serde::Serialize;
+use sea_orm::{entity::prelude::*, FromJsonQueryResult};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Synced,
+    Unchanged,
+    /// FoW is writing to the `Sync` folder.
+    Locked,
+    Failed,
+    /// the server is shutting down.
+    Interrupted,
+}
+
+/// See `LockDecision` in the server.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum LockStatus {
+    NoLockFile,
+    /// decoded from the content of the lock file.
+    InProgress,
+    Idle,
+    /// we don't understand the lock file, so whether it is locked is decided by how old it is.
+    LockFile {
+        age_minutes: i64,
+        locked: bool,
+    },
+}
+
+/// See `ErrorClass` in the server.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorClass {
+    Transient,
+    Throttled,
+    Locked,
+    Permanent,
+    QuotaExceeded,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Error {
+    pub class: ErrorClass,
+    pub message: String,
+}
+
+/// What the task runner does after a failed sync.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Retry {
+    After { minutes: i64 },
+    Stopped,
+}
+
+/// A sync file that is not a valid tile, it is left out of the snapshot.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InvalidFile {
+    pub name: String,
+    pub reason: String,
+}
+
+/// What happened in a sync. It is stored as json so the UI can render it in the user's language.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
+pub struct Report {
+    pub outcome: Outcome,
+    #[serde(default)]
+    pub lock: Option<LockStatus>,
+    /// sync files in the source
+    #[serde(default)]
+    pub files: u32,
+    /// sync files we didn't have before
+    #[serde(default)]
+    pub new_files: u32,
+    #[serde(default)]
+    pub bytes_downloaded: u64,
+    #[serde(default)]
+    pub duration_ms: u64,
+    #[serde(default)]
+    pub unexpected_files: Vec<String>,
+    #[serde(default)]
+    pub unexpected_folders: Vec<String>,
+    #[serde(default)]
+    pub invalid_files: Vec<InvalidFile>,
+    #[serde(default)]
+    pub error: Option<Error>,
+    #[serde(default)]
+    pub retry: Option<Retry>,
+}
+
+impl Report {
+    pub fn new(outcome: Outcome) -> Report {
+        Report {
+            outcome,
+            lock: None,
+            files: 0,
+            new_files: 0,
+            bytes_downloaded: 0,
+            duration_ms: 0,
+            unexpected_files: Vec::new(),
+            unexpected_folders: Vec::new(),
+            invalid_files: Vec::new(),
+            error: None,
+            retry: None,
+        }
+    }
+}
+
+impl fmt::Display for LockStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockStatus::NoLockFile => write!(f, "lock: no lock file"),
+            LockStatus::InProgress => write!(f, "lock: sync in progress"),
+            LockStatus::Idle => write!(f, "lock: idle"),
+            LockStatus::LockFile {
+                age_minutes,
+                locked,
+            } => write!(
+                f,
+                "lock: unknown lock file, modified {} min ago, treated as {}",
+                age_minutes,
+                if *locked { "locked" } else { "expired" }
+            ),
+        }
+    }
+}
+
+/// The plain text version, one line per thing that happened. This is what `details` used to be.
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut lines = Vec::new();
+        if let Some(lock) = &self.lock {
+            lines.push(lock.to_string());
+        }
+        for name in &self.unexpected_folders {
+            lines.push(format!("unexpected folder: {}", name));
+        }
+        for name in &self.unexpected_files {
+            lines.push(format!("unexpected file: {}", name));
+        }
+        for file in &self.invalid_files {
+            lines.push(format!("invalid file: {}, {}", file.name, file.reason));
+        }
+        match self.outcome {
+            Outcome::Synced => lines.push(format!("new files: {}/{}", self.new_files, self.files)),
+            Outcome::Unchanged => lines.push("no change since last sync".into()),
+            Outcome::Locked => lines.push("Locked, failed to sync.".into()),
+            Outcome::Failed => (),
+            Outcome::Interrupted => lines.push("interrupted by server shutdown".into()),
+        }
+        if let Some(error) = &self.error {
+            lines.push(error.message.clone());
+        }
+        match self.retry {
+            None => (),
+            Some(Retry::After { minutes }) => lines.push(format!("retrying in {} min", minutes)),
+            Some(Retry::Stopped) => lines.push("task stopped".into()),
+        }
+        write!(f, "{}", lines.join("\n"))
+    }
+}
 
 #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize)]
 #[sea_orm(table_name = "snapshot_logs")]
@@ -8,12 +168,19 @@ pub struct Model {
     pub id: i64,
     #[sea_orm(indexed)]
     pub user_id: i64,
+    // `None` for old logs whose task was gone before tasks had their own ids.
+    #[sea_orm(indexed, nullable)]
+    pub snapshot_task_id: Option<i64>,
     #[sea_orm(indexed)]
     pub snapshot_id: Option<i64>,
     pub timestamp: DateTimeUtc,
     pub succeed: bool,
+    // plain text, rendered from `report` for new logs.
     #[sea_orm(column_type = "Text", nullable)]
     pub details: String,
+    // `None` for logs written before we had structured logs, `details` is all we have for them.
+    #[sea_orm(nullable)]
+    pub report: Option<Report>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -1,5 +1,6 @@
 use sea_orm::{entity::prelude::*, FromJsonQueryResult};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 // NOTE: sea_orm doesn't seem to support `u16`
 
@@ -17,30 +18,111 @@ pub enum Status {
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
 pub enum Source {
-    OneDrive { share_url: String },
+    OneDrive {
+        share_url: String,
+    },
+    WebDav {
+        url: String,
+        username: String,
+        password: String,
+    },
+    LocalPath {
+        path: String,
+    },
+}
+
+/// Lets users sync at the time they usually sync their devices. See `schedule` in the server.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
+pub enum Schedule {
+    /// At `time` ("HH:MM") local time and then every `interval`, e.g. 08:00 and 20:00 for a 12
+    /// hours interval.
+    TimeOfDay { time: String, timezone: String },
+    /// A cron expression with 5 fields: minute, hour, day of month, month, day of week.
+    /// `interval` is ignored.
+    Cron {
+        expression: String,
+        timezone: String,
+    },
+}
+
+/// What the last sync of a task saw in the `Sync` folder, so the next sync can skip files that
+/// didn't change. See `data_fetcher::snapshot` in the server.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
+pub struct SyncState {
+    /// By file name. Only files that the source gives a version for are here.
+    pub files: BTreeMap<String, FileVersion>,
+    /// Only for sources that can list what changed since the last sync, i.e. OneDrive.
+    #[serde(default)]
+    pub delta: Option<Delta>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileVersion {
+    /// e.g. the ETag of the file, see `RemoteFile::version` in the server.
+    pub version: String,
+    pub sha256: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Delta {
+    /// Asking it gives everything that changed since the last sync, e.g. the `@odata.deltaLink`
+    /// of OneDrive.
+    pub link: String,
+    /// The `Sync` folder as of the last sync, by item id, changes are applied on top of it.
+    pub items: BTreeMap<String, DeltaItem>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeltaItem {
+    pub name: String,
+    /// `None` for folders.
+    pub file: Option<DeltaFile>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeltaFile {
+    pub last_modified: DateTimeUtc,
+    pub size: u64,
+    pub sha256: String,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, DeriveEntityModel)]
 #[sea_orm(table_name = "snapshot_tasks")]
 pub struct Model {
-    #[sea_orm(primary_key, auto_increment = false)]
+    #[sea_orm(primary_key)]
+    pub id: i64,
+    // a user can have multiple tasks, e.g. one for each device.
+    #[sea_orm(indexed)]
     pub user_id: i64,
+    #[sea_orm(column_type = "Text")]
+    pub name: String,
     #[sea_orm(indexed)]
     pub status: Status,
     // unit: mintue
     pub interval: i16,
     pub source: Source,
-    // I haven't thought really hard about the scheduling, so let's make it simple for now.
-    // Basically we'll try to sync from the source when: current_time >= next_sync && status == Running.
+    // We'll try to sync from the source when: current_time >= next_sync && status == Running.
     // Last sync can be found by looking at the snapshot log table.
-    // The tricky parts here are:
-    // 1. Do users want to have more control on when to sync if we don't allow them to sync very frequently?
-    //    It is very likely that users have some patterns about when they sync data from their devices to the cloud.
-    // 2. Sycning (and maybe retrying) takes a while, so the current naive approach will make the schedule shift a bit
-    //    every single time.
+    // After a sync, the next one is computed from the scheduled time instead of the time the
+    // sync finished, so the schedule doesn't shift, see `schedule::next_sync`.
     #[sea_orm(indexed)]
     pub next_sync: DateTimeUtc,
     pub error_count: i16,
+    // Locked syncs in a row. They are counted on their own and don't stop the task, see
+    // `RetryPolicy` in the server.
+    pub locked_count: i16,
+    // A worker owns the task while it is syncing, and keeps renewing the lease until the job is
+    // done. A task with an expired lease is free to be claimed again (e.g. the worker died).
+    #[sea_orm(column_type = "Text", nullable)]
+    pub lease_owner: Option<String>,
+    #[sea_orm(nullable)]
+    pub lease_expires_at: Option<DateTimeUtc>,
+    // `None` means every `interval` minutes.
+    #[sea_orm(nullable)]
+    pub schedule: Option<Schedule>,
+    // Updated by every successful sync, reset when the source changes.
+    #[sea_orm(nullable)]
+    pub sync_state: Option<SyncState>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -0,0 +1,21 @@
+use sea_orm::entity::prelude::*;
+
+/// A file in the sync file storage of a user, see `sync_blob` of the server. Rows are kept
+/// up to date together with the snapshots that use the files, GC removes the files that are no
+/// longer used.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "sync_blobs")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub user_id: i64,
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub sha256: String,
+    pub size: i64,
+    /// number of snapshots that use the file, GC removes the file once this is 0
+    pub ref_count: i32,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,22 @@
+use sea_orm::entity::prelude::*;
+
+/// Stats of a sync file used as a tile in row `tile_y` of the map, see `snapshot_stats` of the
+/// server. They only depend on the content and the row (the area depends on the latitude), so
+/// each file is only read once.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "sync_blob_stats")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub user_id: i64,
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub sha256: String,
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub tile_y: i32,
+    pub area_m2: f64,
+    pub block_count: i32,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
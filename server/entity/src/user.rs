@@ -26,6 +26,14 @@ pub struct Model {
     #[sea_orm(nullable, unique, indexed)]
     pub github_uid: Option<i64>,
     pub language: Language,
+    // Where to tell the user about failed syncs, see `notification` in the server.
+    pub notify_by_email: bool,
+    #[sea_orm(column_type = "Text", nullable)]
+    pub notification_webhook_url: Option<String>,
+    // overrides the server's default sync file storage quota, there is no API for it, set it in
+    // the database.
+    #[sea_orm(nullable)]
+    pub sync_file_storage_limit_mib: Option<i64>,
     pub created_at: DateTimeUtc,
     pub updated_at: DateTimeUtc,
 }
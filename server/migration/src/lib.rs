@@ -2,6 +2,19 @@ pub use sea_orm_migration::prelude::*;
 
 mod m20220101_000001_create_table;
 mod m20240914_025248_add_index;
+mod m20261018_000002_add_task_lease;
+mod m20261018_000003_create_snapshot_job;
+mod m20261018_000004_add_task_schedule;
+mod m20261018_000005_multiple_snapshot_tasks;
+mod m20261018_000006_add_user_notification_settings;
+mod m20261018_000007_add_snapshot_log_report;
+mod m20261018_000008_create_sync_blob;
+mod m20261018_000009_add_user_storage_limit;
+mod m20261018_000010_add_snapshot_stats;
+mod m20261018_000011_create_sync_blob_stats;
+mod m20261018_000012_add_task_sync_state;
+mod m20261018_000013_add_task_locked_count;
+mod m20261018_000014_create_marker;
 
 pub struct Migrator;
 
@@ -11,6 +24,19 @@ impl MigratorTrait for Migrator {
         vec![
             Box::new(m20220101_000001_create_table::Migration),
             Box::new(m20240914_025248_add_index::Migration),
+            Box::new(m20261018_000002_add_task_lease::Migration),
+            Box::new(m20261018_000003_create_snapshot_job::Migration),
+            Box::new(m20261018_000004_add_task_schedule::Migration),
+            Box::new(m20261018_000005_multiple_snapshot_tasks::Migration),
+            Box::new(m20261018_000006_add_user_notification_settings::Migration),
+            Box::new(m20261018_000007_add_snapshot_log_report::Migration),
+            Box::new(m20261018_000008_create_sync_blob::Migration),
+            Box::new(m20261018_000009_add_user_storage_limit::Migration),
+            Box::new(m20261018_000010_add_snapshot_stats::Migration),
+            Box::new(m20261018_000011_create_sync_blob_stats::Migration),
+            Box::new(m20261018_000012_add_task_sync_state::Migration),
+            Box::new(m20261018_000013_add_task_locked_count::Migration),
+            Box::new(m20261018_000014_create_marker::Migration),
         ]
     }
 }
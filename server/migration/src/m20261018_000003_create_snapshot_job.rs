@@ -0,0 +1,34 @@
+use sea_orm_migration::{
+    prelude::*,
+    sea_orm::{DbBackend, Schema},
+};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        let schema = Schema::new(DbBackend::Postgres);
+        manager
+            .create_table(
+                schema
+                    .create_table_from_entity(entity::snapshot_job::Entity)
+                    .if_not_exists()
+                    .to_owned(),
+            )
+            .await?;
+        for mut stmt in schema.create_index_from_entity(entity::snapshot_job::Entity) {
+            manager
+                .create_index(stmt.if_not_exists().to_owned())
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(entity::snapshot_job::Entity).to_owned())
+            .await
+    }
+}
@@ -0,0 +1,68 @@
+use sea_orm_migration::{
+    prelude::*,
+    sea_orm::{DbBackend, Schema},
+};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // NOTE: the initial migration creates tables from the latest entities, so a new database
+        // already has everything, only the indexes below might be missing.
+        if !manager.has_column("snapshot_tasks", "id").await? {
+            // Tasks used to be keyed by `user_id`, so each existing task is the only task of its
+            // user, which is how the rows below are matched to their task.
+            manager
+                .get_connection()
+                .execute_unprepared(
+                    r#"
+                    ALTER TABLE snapshot_tasks DROP CONSTRAINT snapshot_tasks_pkey;
+                    ALTER TABLE snapshot_tasks ADD COLUMN IF NOT EXISTS id bigserial PRIMARY KEY;
+                    ALTER TABLE snapshot_tasks ADD COLUMN IF NOT EXISTS name text NOT NULL DEFAULT 'default';
+                    ALTER TABLE snapshot_tasks ALTER COLUMN name DROP DEFAULT;
+
+                    ALTER TABLE snapshots ADD COLUMN IF NOT EXISTS snapshot_task_id bigint NULL;
+                    UPDATE snapshots SET snapshot_task_id = snapshot_tasks.id
+                        FROM snapshot_tasks
+                        WHERE snapshots.user_id = snapshot_tasks.user_id
+                            AND snapshots.source_kind = 0;
+
+                    ALTER TABLE snapshot_logs ADD COLUMN IF NOT EXISTS snapshot_task_id bigint NULL;
+                    UPDATE snapshot_logs SET snapshot_task_id = snapshot_tasks.id
+                        FROM snapshot_tasks
+                        WHERE snapshot_logs.user_id = snapshot_tasks.user_id;
+
+                    ALTER TABLE snapshot_jobs ADD COLUMN IF NOT EXISTS snapshot_task_id bigint NULL;
+                    UPDATE snapshot_jobs SET snapshot_task_id = snapshot_tasks.id
+                        FROM snapshot_tasks
+                        WHERE snapshot_jobs.user_id = snapshot_tasks.user_id;
+                    DELETE FROM snapshot_jobs WHERE snapshot_task_id IS NULL;
+                    ALTER TABLE snapshot_jobs ALTER COLUMN snapshot_task_id SET NOT NULL;
+                    "#,
+                )
+                .await?;
+        }
+
+        let schema = Schema::new(DbBackend::Postgres);
+        for mut stmt in schema
+            .create_index_from_entity(entity::snapshot_task::Entity)
+            .into_iter()
+            .chain(schema.create_index_from_entity(entity::snapshot::Entity))
+            .chain(schema.create_index_from_entity(entity::snapshot_log::Entity))
+            .chain(schema.create_index_from_entity(entity::snapshot_job::Entity))
+        {
+            manager
+                .create_index(stmt.if_not_exists().to_owned())
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn down(&self, _manager: &SchemaManager) -> Result<(), DbErr> {
+        Err(DbErr::Migration(
+            "users might have more than one snapshot task now".into(),
+        ))
+    }
+}
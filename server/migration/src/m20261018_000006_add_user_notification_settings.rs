@@ -0,0 +1,42 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // NOTE: the initial migration creates tables from the latest entities, so the columns
+        // might already be there.
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(entity::user::Entity)
+                    .add_column_if_not_exists(
+                        ColumnDef::new(entity::user::Column::NotifyByEmail)
+                            .boolean()
+                            .not_null()
+                            .default(true),
+                    )
+                    .add_column_if_not_exists(
+                        ColumnDef::new(entity::user::Column::NotificationWebhookUrl)
+                            .text()
+                            .null(),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(entity::user::Entity)
+                    .drop_column(entity::user::Column::NotifyByEmail)
+                    .drop_column(entity::user::Column::NotificationWebhookUrl)
+                    .to_owned(),
+            )
+            .await
+    }
+}
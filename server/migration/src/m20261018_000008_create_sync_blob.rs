@@ -0,0 +1,36 @@
+use sea_orm_migration::{
+    prelude::*,
+    sea_orm::{DbBackend, Schema},
+};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // rows of the files stored before this are filled in by `sync_file_gc::backfill_all` on
+        // the next start, it reads the storage which we can't do here.
+        let schema = Schema::new(DbBackend::Postgres);
+        manager
+            .create_table(
+                schema
+                    .create_table_from_entity(entity::sync_blob::Entity)
+                    .if_not_exists()
+                    .to_owned(),
+            )
+            .await?;
+        for mut stmt in schema.create_index_from_entity(entity::sync_blob::Entity) {
+            manager
+                .create_index(stmt.if_not_exists().to_owned())
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(entity::sync_blob::Entity).to_owned())
+            .await
+    }
+}
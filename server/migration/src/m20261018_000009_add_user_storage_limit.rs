@@ -0,0 +1,35 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // NOTE: the initial migration creates tables from the latest entities, so the column
+        // might already be there.
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(entity::user::Entity)
+                    .add_column_if_not_exists(
+                        ColumnDef::new(entity::user::Column::SyncFileStorageLimitMib)
+                            .big_integer()
+                            .null(),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(entity::user::Entity)
+                    .drop_column(entity::user::Column::SyncFileStorageLimitMib)
+                    .to_owned(),
+            )
+            .await
+    }
+}
@@ -0,0 +1,54 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // NOTE: the initial migration creates tables from the latest entities, so the columns
+        // might already be there. Existing snapshots are filled in by `snapshot_stats::backfill`
+        // of the server, it needs the sync files.
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(entity::snapshot::Entity)
+                    .add_column_if_not_exists(
+                        ColumnDef::new(entity::snapshot::Column::ExploredAreaM2)
+                            .big_integer()
+                            .null(),
+                    )
+                    .add_column_if_not_exists(
+                        ColumnDef::new(entity::snapshot::Column::TileCount)
+                            .integer()
+                            .null(),
+                    )
+                    .add_column_if_not_exists(
+                        ColumnDef::new(entity::snapshot::Column::BlockCount)
+                            .integer()
+                            .null(),
+                    )
+                    .add_column_if_not_exists(
+                        ColumnDef::new(entity::snapshot::Column::Level)
+                            .integer()
+                            .null(),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(entity::snapshot::Entity)
+                    .drop_column(entity::snapshot::Column::ExploredAreaM2)
+                    .drop_column(entity::snapshot::Column::TileCount)
+                    .drop_column(entity::snapshot::Column::BlockCount)
+                    .drop_column(entity::snapshot::Column::Level)
+                    .to_owned(),
+            )
+            .await
+    }
+}
@@ -0,0 +1,36 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // NOTE: the initial migration creates tables from the latest entities, so the column
+        // might already be there.
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(entity::snapshot_task::Entity)
+                    .add_column_if_not_exists(
+                        ColumnDef::new(entity::snapshot_task::Column::LockedCount)
+                            .small_integer()
+                            .not_null()
+                            .default(0),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(entity::snapshot_task::Entity)
+                    .drop_column(entity::snapshot_task::Column::LockedCount)
+                    .to_owned(),
+            )
+            .await
+    }
+}
@@ -0,0 +1,166 @@
+use crate::pool::Db;
+use crate::sync_file_scrub;
+use crate::user_handler::User;
+use crate::{APIResponse, ServerState};
+use rocket::http::Status;
+use rocket::outcome::try_outcome;
+use rocket::request::{FromRequest, Outcome, Request};
+use sea_orm_rocket::Connection;
+use serde_json::json;
+
+/// A user listed in `ADMIN_USER_IDS`. In single user no auth mode the only user is an admin.
+pub struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let user = try_outcome!(req.guard::<User>().await);
+        let config = &req.rocket().state::<ServerState>().unwrap().config;
+        let is_admin = config.single_user_no_auth_mode.unwrap_or(false)
+            || config
+                .admin_user_ids
+                .split(',')
+                .any(|id| id.trim().parse() == Ok(user.uid));
+        if is_admin {
+            Outcome::Success(Admin)
+        } else {
+            Outcome::Error((Status::Forbidden, ()))
+        }
+    }
+}
+
+// Re-check the sync files of one user, or start re-checking everyone's in the background (see
+// `scrub_status`).
+#[post("/sync_file_scrub?<user_id>")]
+async fn scrub(
+    conn: Connection<'_, Db>,
+    server_state: &rocket::State<ServerState>,
+    _admin: Admin,
+    user_id: Option<i64>,
+) -> APIResponse {
+    let db = conn.into_inner();
+    match user_id {
+        None => {
+            if server_state
+                .background_scrub
+                .start(db.clone(), server_state.file_storage.clone())
+            {
+                Ok((
+                    Status::Accepted,
+                    json!(server_state.background_scrub.status()),
+                ))
+            } else {
+                Ok((Status::Conflict, json!({"error": "already_running"})))
+            }
+        }
+        Some(uid) => {
+            let mut report = sync_file_scrub::Report::default();
+            sync_file_scrub::scrub(db, &server_state.file_storage, &User { uid }, &mut report)
+                .await?;
+            Ok((Status::Ok, json!(report)))
+        }
+    }
+}
+
+// The last scrub of everyone, the report is there once it is finished.
+#[get("/sync_file_scrub")]
+async fn scrub_status(server_state: &rocket::State<ServerState>, _admin: Admin) -> APIResponse {
+    match server_state.background_scrub.status() {
+        None => Ok((Status::NotFound, json!({}))),
+        Some(status) => Ok((Status::Ok, json!(status))),
+    }
+}
+
+// What the task runner of this server is doing right now.
+#[get("/task_runner")]
+async fn task_runner(server_state: &rocket::State<ServerState>, _admin: Admin) -> APIResponse {
+    Ok((
+        Status::Ok,
+        json!({
+            "workers": server_state.config.task_runner_workers,
+            "in_flight_tasks": server_state.in_flight_tasks.task_ids(),
+        }),
+    ))
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![scrub, scrub_status, task_runner]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{needs_database, TestServer};
+
+    #[tokio::test]
+    #[ignore = needs_database!()]
+    async fn test_sync_file_scrub() {
+        let server = TestServer::new().await;
+        let resp = server
+            .client
+            .post("/api/v1/admin/sync_file_scrub")
+            .header(server.auth(1))
+            .dispatch()
+            .await;
+        assert_eq!(resp.status(), Status::Forbidden);
+
+        // see `ADMIN_USER_IDS` of `TestServer`
+        let resp = server
+            .client
+            .post("/api/v1/admin/sync_file_scrub?user_id=1")
+            .header(server.auth(100))
+            .dispatch()
+            .await;
+        assert_eq!(resp.status(), Status::Ok);
+        let report: serde_json::Value = resp.into_json().await.unwrap();
+        assert_eq!(report["checked_files"], 0);
+        assert_eq!(report["corrupt_files"], json!([]));
+
+        let status = || async {
+            server
+                .client
+                .get("/api/v1/admin/sync_file_scrub")
+                .header(server.auth(100))
+                .dispatch()
+                .await
+        };
+        assert_eq!(status().await.status(), Status::NotFound);
+        let resp = server
+            .client
+            .post("/api/v1/admin/sync_file_scrub")
+            .header(server.auth(100))
+            .dispatch()
+            .await;
+        assert_eq!(resp.status(), Status::Accepted);
+        loop {
+            let resp = status().await;
+            assert_eq!(resp.status(), Status::Ok);
+            let status: serde_json::Value = resp.into_json().await.unwrap();
+            if !status["finished_at"].is_null() {
+                assert_eq!(status["report"]["checked_files"], 0);
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = needs_database!()]
+    async fn test_task_runner() {
+        let server = TestServer::new().await;
+        let server_state = server.client.rocket().state::<ServerState>().unwrap();
+        let _guard = server_state.in_flight_tasks.try_start(42).unwrap();
+        let resp = server
+            .client
+            .get("/api/v1/admin/task_runner")
+            .header(server.auth(100))
+            .dispatch()
+            .await;
+        assert_eq!(resp.status(), Status::Ok);
+        let status: serde_json::Value = resp.into_json().await.unwrap();
+        assert_eq!(status["workers"], 4);
+        assert_eq!(status["in_flight_tasks"], json!([42]));
+    }
+}
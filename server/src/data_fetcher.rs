@@ -1,14 +1,27 @@
 use crate::file_storage;
+use crate::fow_tile;
 use crate::limit;
 use crate::user_handler::User;
 use anyhow::Error;
-use base64::Engine;
+use async_trait::async_trait;
 use chrono::prelude::*;
 use chrono::Duration;
 use entity::snapshot::SyncFiles;
-use entity::snapshot_task::Source;
-use std::collections::HashMap;
-use tokio::time::sleep;
+use entity::snapshot_log;
+use entity::snapshot_task::{Delta, FileVersion, Source, SyncState};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+mod local_path;
+mod lock;
+mod onedrive;
+mod webdav;
+
+pub use lock::LockDecision;
+#[cfg(test)]
+pub use lock::LOCK_FILE_NAME;
 
 #[derive(Debug)]
 pub struct SyncFile {
@@ -84,6 +97,8 @@ impl SyncFile {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_utils::limits;
+    use entity::snapshot_log::{LockStatus, Outcome, Report};
     #[test]
     fn test_sync_file() {
         let filename = "23e4lltkkoke";
@@ -93,6 +108,309 @@ mod tests {
         let error = SyncFile::create_from_filename(filename, "");
         assert!(error.is_err());
     }
+
+    const TEST_FILE_SHA256: &str =
+        "48d7a1b6d4e5c943e1afcf10d094e16e239c71ad7b3e118359936d137781b0cc";
+
+    struct FakeSource {
+        // (age, content) of the lock file
+        lock: Option<(Duration, &'static str)>,
+        // also list a file that isn't a valid tile
+        broken: bool,
+    }
+
+    #[async_trait]
+    impl SyncSource for FakeSource {
+        async fn validate(&self) -> Result<Result<(), ValidationError>, Error> {
+            Ok(Ok(()))
+        }
+
+        async fn list_files(&self) -> Result<Vec<RemoteEntry>, Error> {
+            let mut entries = vec![
+                RemoteEntry::File(RemoteFile {
+                    name: "23e4lltkkoke".into(),
+                    last_modified: Utc::now(),
+                    size: 2784,
+                    sha256: Some(TEST_FILE_SHA256.into()),
+                    version: None,
+                    locator: "../editor/src/__tests__/data/23e4lltkkoke".into(),
+                }),
+                RemoteEntry::Folder {
+                    name: "backup".into(),
+                },
+            ];
+            if self.broken {
+                entries.push(RemoteEntry::File(RemoteFile {
+                    name: "cd36lltksiwo".into(),
+                    last_modified: Utc::now(),
+                    size: 10,
+                    sha256: None,
+                    version: None,
+                    locator: "content:not a tile".into(),
+                }));
+            }
+            if let Some((lock_age, lock_content)) = self.lock {
+                entries.push(RemoteEntry::File(RemoteFile {
+                    name: LOCK_FILE_NAME.into(),
+                    last_modified: Utc::now() - lock_age,
+                    size: lock_content.len() as u64,
+                    sha256: None,
+                    version: None,
+                    locator: format!("content:{}", lock_content),
+                }));
+            }
+            Ok(entries)
+        }
+
+        async fn fetch_file(
+            &self,
+            file: &RemoteFile,
+            path: &Path,
+            _max_size: u64,
+        ) -> Result<String, Error> {
+            match file.locator.strip_prefix("content:") {
+                Some(content) => std::fs::write(path, content)?,
+                None => {
+                    std::fs::copy(&file.locator, path)?;
+                }
+            }
+            Ok(file_storage::sha256_of_file(path)?)
+        }
+    }
+
+    #[test]
+    fn test_classify_error() {
+        let now = Utc.with_ymd_and_hms(2015, 10, 21, 7, 0, 0).unwrap();
+        assert_eq!(parse_retry_after("120", now), Some(Duration::minutes(2)));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT", now),
+            Some(Duration::minutes(28))
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+
+        let http_error = |status: u16, retry_after| -> Error {
+            HttpError {
+                status: reqwest::StatusCode::from_u16(status).unwrap(),
+                retry_after,
+            }
+            .into()
+        };
+        assert_eq!(
+            classify_error(&http_error(429, Some(Duration::minutes(2)))),
+            ErrorClass::Throttled {
+                retry_after: Some(Duration::minutes(2))
+            }
+        );
+        assert_eq!(
+            classify_error(&http_error(503, None)),
+            ErrorClass::Throttled { retry_after: None }
+        );
+        assert_eq!(
+            classify_error(&http_error(502, None)),
+            ErrorClass::Transient
+        );
+        for status in [401, 403, 410] {
+            assert_eq!(
+                classify_error(&http_error(status, None)),
+                ErrorClass::Permanent
+            );
+        }
+        for status in [400, 408, 409] {
+            assert_eq!(
+                classify_error(&http_error(status, None)),
+                ErrorClass::Transient
+            );
+        }
+        assert_eq!(classify_error(&http_error(423, None)), ErrorClass::Locked);
+        assert_eq!(
+            classify_error(&http_error(404, None).context("listing files")),
+            ErrorClass::Permanent
+        );
+        assert_eq!(
+            classify_error(&limit::LimitExceeded("too big".into()).into()),
+            ErrorClass::Permanent
+        );
+        assert_eq!(
+            classify_error(
+                &anyhow::Error::from(limit::QuotaExceeded("full".into())).context("saving files")
+            ),
+            ErrorClass::QuotaExceeded
+        );
+        assert_eq!(
+            classify_error(&std::io::Error::from(std::io::ErrorKind::ConnectionReset).into()),
+            ErrorClass::Transient
+        );
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_internal() {
+        let data_dir = tempfile::tempdir().unwrap();
+        let storage =
+            file_storage::SyncFileStorage::init(data_dir.path().to_str().unwrap()).unwrap();
+        let user = User { uid: 1 };
+
+        let mut report = Report::new(Outcome::Synced);
+        let source = FakeSource {
+            lock: None,
+            broken: false,
+        };
+        // nothing is saved if the new files don't fit
+        let error = snapshot_internal(&source, None, &mut report, &user, &storage, &limits(), 1)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(classify_error(&error), ErrorClass::QuotaExceeded);
+        assert!(!storage.has_file(&user, TEST_FILE_SHA256).await.unwrap());
+
+        let mut report = Report::new(Outcome::Synced);
+        match snapshot_internal(
+            &source,
+            None,
+            &mut report,
+            &user,
+            &storage,
+            &limits(),
+            u64::MAX,
+        )
+        .await
+        .unwrap()
+        {
+            SnapshotResultInternal::Ok(sync_files, new_files, ..) => {
+                assert_eq!(sync_files.0.get(&117660).unwrap(), TEST_FILE_SHA256);
+                assert_eq!(new_files[0].0, TEST_FILE_SHA256);
+                assert_eq!(new_files[0].1, report.bytes_downloaded);
+            }
+            _ => panic!("should be synced"),
+        }
+        assert!(storage.has_file(&user, TEST_FILE_SHA256).await.unwrap());
+        assert_eq!(report.lock, Some(LockStatus::NoLockFile));
+        assert_eq!(report.unexpected_folders, vec!["backup"]);
+        assert_eq!((report.new_files, report.files), (1, 1));
+        assert!(report.bytes_downloaded > 0);
+        assert_eq!(
+            report.to_string(),
+            "lock: no lock file\nunexpected folder: backup\nnew files: 1/1"
+        );
+
+        // old lock files that we don't understand are ignored
+        let mut report = Report::new(Outcome::Synced);
+        let source = FakeSource {
+            lock: Some((Duration::hours(1), "")),
+            broken: false,
+        };
+        let result = snapshot_internal(
+            &source,
+            None,
+            &mut report,
+            &user,
+            &storage,
+            &limits(),
+            u64::MAX,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(result, SnapshotResultInternal::Ok(..)));
+        assert!(matches!(
+            report.lock,
+            Some(LockStatus::LockFile { locked: false, .. })
+        ));
+        assert_eq!((report.new_files, report.files), (0, 1));
+        assert_eq!(report.bytes_downloaded, 0);
+
+        let source = FakeSource {
+            lock: Some((Duration::minutes(1), "")),
+            broken: false,
+        };
+        let mut report = Report::new(Outcome::Synced);
+        let result = snapshot_internal(
+            &source,
+            None,
+            &mut report,
+            &user,
+            &storage,
+            &limits(),
+            u64::MAX,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(result, SnapshotResultInternal::Locked));
+        assert!(matches!(
+            report.lock,
+            Some(LockStatus::LockFile { locked: true, .. })
+        ));
+        assert_eq!(report.files, 0);
+
+        // the content of the lock file wins if we understand it
+        let source = FakeSource {
+            lock: Some((Duration::minutes(1), "unlocked")),
+            broken: false,
+        };
+        let mut report = Report::new(Outcome::Synced);
+        let result = snapshot_internal(
+            &source,
+            None,
+            &mut report,
+            &user,
+            &storage,
+            &limits(),
+            u64::MAX,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(result, SnapshotResultInternal::Ok(..)));
+        assert_eq!(report.lock, Some(LockStatus::Idle));
+
+        let source = FakeSource {
+            lock: Some((Duration::hours(1), "locked")),
+            broken: false,
+        };
+        let mut report = Report::new(Outcome::Synced);
+        let result = snapshot_internal(
+            &source,
+            None,
+            &mut report,
+            &user,
+            &storage,
+            &limits(),
+            u64::MAX,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(result, SnapshotResultInternal::Locked));
+        assert_eq!(report.lock, Some(LockStatus::InProgress));
+
+        // invalid files are skipped, the rest of the snapshot is still good
+        let source = FakeSource {
+            lock: None,
+            broken: true,
+        };
+        let mut report = Report::new(Outcome::Synced);
+        match snapshot_internal(
+            &source,
+            None,
+            &mut report,
+            &user,
+            &storage,
+            &limits(),
+            u64::MAX,
+        )
+        .await
+        .unwrap()
+        {
+            SnapshotResultInternal::Ok(sync_files, new_files, ..) => {
+                assert_eq!(sync_files.0.len(), 1);
+                assert!(sync_files.0.contains_key(&117660));
+                assert!(new_files.is_empty());
+            }
+            _ => panic!("should be synced"),
+        }
+        assert_eq!((report.new_files, report.files), (0, 1));
+        assert_eq!(report.invalid_files.len(), 1);
+        assert_eq!(report.invalid_files[0].name, "cd36lltksiwo");
+        assert!(report.invalid_files[0]
+            .reason
+            .starts_with("invalid zlib data"));
+    }
 }
 
 pub enum ValidationError {
@@ -100,208 +418,491 @@ pub enum ValidationError {
     InvalidFolderStructure,
 }
 
-fn onedrive_api_of_link(url: &str) -> String {
-    format!(
-        "https://api.onedrive.com/v1.0/shares/u!{}",
-        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(url)
-    )
+/// A file in the `Sync` folder of a source.
+pub struct RemoteFile {
+    pub name: String,
+    pub last_modified: DateTime<Utc>,
+    pub size: u64,
+    /// lower case, provided by the source and only used for deduping, we always recompute it
+    /// after download. `None` if the source can't tell us without downloading the file.
+    pub sha256: Option<String>,
+    /// changes whenever the content changes, e.g. the ETag for WebDAV. For sources that don't
+    /// give us `sha256`, files with the same version as in the last sync are not downloaded again.
+    pub version: Option<String>,
+    /// source specific, e.g. the download url for OneDrive.
+    pub locator: String,
 }
 
-async fn onedrive_find_sync_folder_link(url: &str) -> Result<Option<String>, Error> {
-    let resp = reqwest::get(onedrive_api_of_link(url) + "/root/children")
-        .await?
-        .error_for_status()?
-        .json::<serde_json::Value>()
-        .await?;
-    for child in resp["value"]
-        .as_array()
-        .ok_or_else(|| anyhow!("invalid api response"))?
-    {
-        if let Some("Sync") = child["name"].as_str() {
-            return Ok(child["webUrl"].as_str().map(String::from));
-        }
+pub enum RemoteEntry {
+    File(RemoteFile),
+    Folder { name: String },
+}
+
+/// See `SyncSource::list_changes`.
+pub struct Listing {
+    pub entries: Vec<RemoteEntry>,
+    pub delta: Option<Delta>,
+}
+
+/// A place we can sync FoW data from. Implementations only need to know how to talk to the
+/// provider, the snapshotting logic (deduping, size limits, saving files etc) is shared.
+#[async_trait]
+pub trait SyncSource: Send + Sync {
+    /// Check the source is accessible and looks like a FoW folder with a `Sync` folder in it.
+    async fn validate(&self) -> Result<Result<(), ValidationError>, Error>;
+
+    /// List everything in the `Sync` folder.
+    async fn list_files(&self) -> Result<Vec<RemoteEntry>, Error>;
+
+    /// Same as `list_files`, but sources that support it only ask for what changed since `last`
+    /// (the delta of the last sync) and return the delta for the next sync. `None` if nothing
+    /// changed since `last`.
+    async fn list_changes(&self, _last: Option<&Delta>) -> Result<Option<Listing>, Error> {
+        Ok(Some(Listing {
+            entries: self.list_files().await?,
+            delta: None,
+        }))
     }
-    Ok(None)
+
+    /// Download `file` to `path`, fails if it is bigger than `max_size`. Returns the SHA-256
+    /// (lower case) of the downloaded file.
+    async fn fetch_file(
+        &self,
+        file: &RemoteFile,
+        path: &Path,
+        max_size: u64,
+    ) -> Result<String, Error>;
+}
+
+/// Server side settings that some sources need.
+#[derive(Clone, Default)]
+pub struct SourceOptions {
+    /// `Source::LocalPath` of a user must be under `<local_path_root>/<uid>`, it is disabled if
+    /// this is `None`.
+    pub local_path_root: Option<PathBuf>,
+    /// Let `Source::WebDav` point to loopback, private or link-local addresses, see
+    /// `utils::is_public_ip`.
+    pub allow_private_addresses: bool,
 }
 
-pub async fn validate(source: &Source) -> Result<Result<(), ValidationError>, Error> {
+pub fn sync_source_of(
+    source: &Source,
+    options: &SourceOptions,
+    user: &User,
+) -> Box<dyn SyncSource> {
     match source {
-        Source::OneDrive { share_url } => {
-            let resp = reqwest::get(onedrive_api_of_link(share_url) + "/root").await?;
-            if resp.status() != 200 {
-                return Ok(Err(ValidationError::InvalidShare));
-            }
-            let root = resp.json::<serde_json::Value>().await?;
-            match root["name"].as_str() {
-                Some("Fog of World") => (),
-                _ => return Ok(Err(ValidationError::InvalidFolderStructure)),
-            }
-            match onedrive_find_sync_folder_link(share_url).await? {
-                Some(_) => Ok(Ok(())),
-                None => Ok(Err(ValidationError::InvalidFolderStructure)),
-            }
+        Source::OneDrive { share_url } => Box::new(onedrive::OneDrive::new(share_url)),
+        Source::WebDav {
+            url,
+            username,
+            password,
+        } => Box::new(webdav::WebDav::new(
+            url,
+            username,
+            password,
+            options.allow_private_addresses,
+        )),
+        Source::LocalPath { path } => Box::new(local_path::LocalPath::new(
+            path,
+            options
+                .local_path_root
+                .as_ref()
+                .map(|root| root.join(user.uid.to_string()))
+                .as_deref(),
+        )),
+    }
+}
+
+pub async fn validate(
+    source: &Source,
+    options: &SourceOptions,
+    user: &User,
+) -> Result<Result<(), ValidationError>, Error> {
+    sync_source_of(source, options, user).validate().await
+}
+
+/// What kind of failure a sync ran into, decides how it is retried, see `task_runner::RetryPolicy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// network errors, 5xx, 4xx other than the ones below and anything we don't recognize.
+    Transient,
+    /// the source asks us to slow down, `retry_after` comes from the `Retry-After` header.
+    Throttled { retry_after: Option<Duration> },
+    /// FoW is writing to the `Sync` folder (or the source says it is locked, http 423).
+    Locked,
+    /// retrying won't help, e.g. the share is revoked (http 401, 403, 404, 410) or a limit is
+    /// exceeded.
+    Permanent,
+    /// the user is out of sync file storage, retrying won't help until they free up some space.
+    QuotaExceeded,
+}
+
+impl From<ErrorClass> for snapshot_log::ErrorClass {
+    fn from(class: ErrorClass) -> Self {
+        match class {
+            ErrorClass::Transient => snapshot_log::ErrorClass::Transient,
+            ErrorClass::Throttled { .. } => snapshot_log::ErrorClass::Throttled,
+            ErrorClass::Locked => snapshot_log::ErrorClass::Locked,
+            ErrorClass::Permanent => snapshot_log::ErrorClass::Permanent,
+            ErrorClass::QuotaExceeded => snapshot_log::ErrorClass::QuotaExceeded,
         }
     }
 }
 
+/// A non-successful http response from a source. Unlike `reqwest::Response::error_for_status`,
+/// this keeps `Retry-After` so throttling can be handled properly.
+#[derive(Debug)]
+pub struct HttpError {
+    pub status: reqwest::StatusCode,
+    pub retry_after: Option<Duration>,
+}
+
+impl std::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "http error: {}", self.status)
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+/// `Retry-After` is either a number of seconds or an http date.
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    match value.parse::<i64>() {
+        Ok(seconds) => Some(Duration::seconds(seconds.max(0))),
+        Err(_) => {
+            let time = DateTime::parse_from_rfc2822(value).ok()?;
+            Some((time.with_timezone(&Utc) - now).max(Duration::zero()))
+        }
+    }
+}
+
+/// Sources should use this instead of `error_for_status`.
+fn check_status(resp: reqwest::Response) -> Result<reqwest::Response, Error> {
+    let status = resp.status();
+    if status.is_client_error() || status.is_server_error() {
+        let retry_after = resp
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| parse_retry_after(value, Utc::now()));
+        return Err(HttpError {
+            status,
+            retry_after,
+        }
+        .into());
+    }
+    Ok(resp)
+}
+
+pub fn classify_error(error: &Error) -> ErrorClass {
+    use reqwest::StatusCode;
+    for cause in error.chain() {
+        if cause.is::<limit::LimitExceeded>() {
+            return ErrorClass::Permanent;
+        }
+        if cause.is::<limit::QuotaExceeded>() {
+            return ErrorClass::QuotaExceeded;
+        }
+        let (status, retry_after) = if let Some(error) = cause.downcast_ref::<HttpError>() {
+            (error.status, error.retry_after)
+        } else if let Some(status) = cause
+            .downcast_ref::<reqwest::Error>()
+            .and_then(reqwest::Error::status)
+        {
+            (status, None)
+        } else {
+            continue;
+        };
+        return match status {
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => {
+                ErrorClass::Throttled { retry_after }
+            }
+            // WebDAV uses it for locked resources.
+            StatusCode::LOCKED => ErrorClass::Locked,
+            // the share is revoked or gone.
+            StatusCode::UNAUTHORIZED
+            | StatusCode::FORBIDDEN
+            | StatusCode::NOT_FOUND
+            | StatusCode::GONE => ErrorClass::Permanent,
+            // 408, 409, other 4xx and 5xx.
+            _ => ErrorClass::Transient,
+        };
+    }
+    ErrorClass::Transient
+}
+
+// how many files we download at the same time for one snapshot.
+const DOWNLOAD_CONCURRENCY: usize = 4;
+
+/// Stream the body of `resp` to `path`, see `SyncSource::fetch_file`.
+async fn save_response(
+    mut resp: reqwest::Response,
+    path: &Path,
+    max_size: u64,
+) -> Result<String, Error> {
+    if resp.content_length().unwrap_or(0) > max_size {
+        return Err(limit::LimitExceeded(format!(
+            "file is too big. size: {}, limit: {}",
+            file_storage::byte_unit_to_string_hum(resp.content_length().unwrap_or(0)),
+            file_storage::byte_unit_to_string_hum(max_size)
+        ))
+        .into());
+    }
+    let mut writer = file_storage::HashingFileWriter::create(path, max_size).await?;
+    while let Some(chunk) = resp.chunk().await? {
+        writer.write(&chunk).await?;
+    }
+    Ok(writer.finish().await?)
+}
+
 enum SnapshotResultInternal {
-    Ok(SyncFiles, DateTime<Utc>),
+    /// sync files, new files (see `SnapshotOutput`), time, sync state
+    Ok(SyncFiles, Vec<(String, u64)>, DateTime<Utc>, SyncState),
+    Unchanged,
     Locked,
 }
 
 async fn snapshot_internal(
-    source: &Source,
-    logs: &mut Vec<String>,
+    sync_source: &dyn SyncSource,
+    last_sync_state: Option<&SyncState>,
+    report: &mut snapshot_log::Report,
     user: &User,
     sync_file_storage: &file_storage::SyncFileStorage,
+    limits: &limit::Limits,
+    quota_left: u64,
 ) -> Result<SnapshotResultInternal, Error> {
-    match source {
-        Source::OneDrive { share_url } => {
-            // we trust the hash provided by onedrive for deduping, but we recompute it after download.
-            let link_for_sync_folder = onedrive_find_sync_folder_link(share_url)
-                .await?
-                .ok_or_else(|| anyhow!("missing sync folder"))?;
-            let time = Utc::now();
-
-            let mut files = Vec::new();
-            let mut total_size: u64 = 0;
-            let mut next_link =
-                Some(onedrive_api_of_link(&link_for_sync_folder) + "/root/children");
-            loop {
-                match &next_link {
-                    None => break,
-                    Some(current_link) => {
-                        let resp = reqwest::get(current_link)
-                            .await?
-                            .error_for_status()?
-                            .json::<serde_json::Value>()
-                            .await?;
-                        next_link = resp["@odata.nextLink"].as_str().map(str::to_string);
-                        for child in resp["value"]
-                            .as_array()
-                            .ok_or_else(|| anyhow!("invalid api response"))?
-                        {
-                            let name = child["name"]
-                                .as_str()
-                                .ok_or_else(|| anyhow!("invalid api response"))?;
-                            if name == "FoW-Sync-Lock" {
-                                let last_modified_time: DateTime<Utc> =
-                                    serde_json::from_value(child["lastModifiedDateTime"].clone())?;
-                                // TODO: starting from a recent FoW version, the semantic of lock file changed.
-                                // It doesn't remove the lock file after finishing syncing, I guess it might encode
-                                // the lock status inside the file, I haven't figure out the new behavior, but in order
-                                // to make things work, let's just ignore locks that are older than 15 minutes.
-                                if time - last_modified_time <= Duration::minutes(15) {
-                                    return Ok(SnapshotResultInternal::Locked);
-                                }
-                            }
-                            if child["file"].is_null() {
-                                logs.push(format!("unexpected folder: {}", name));
-                            } else {
-                                let sha256_lowercase = child["file"]["hashes"]["sha256Hash"]
-                                    .as_str()
-                                    .ok_or_else(|| anyhow!("invalid api response"))?
-                                    .to_lowercase();
-                                let download_url = child["@content.downloadUrl"]
-                                    .as_str()
-                                    .ok_or_else(|| anyhow!("invalid api response"))?;
-                                let file_size = child["size"]
-                                    .as_i64()
-                                    .ok_or_else(|| anyhow!("invalid api response"))?;
-                                match SyncFile::create_from_filename(name, &sha256_lowercase) {
-                                    Err(_) => {
-                                        logs.push(format!("unexpected file: {}", name));
-                                    }
-                                    Ok(sync_file) => {
-                                        total_size += file_size as u64;
-                                        files.push((sync_file, String::from(download_url)));
-                                    }
-                                }
-                            }
-                        }
+    let time = Utc::now();
+    let last_delta = last_sync_state.and_then(|state| state.delta.as_ref());
+    let Listing { entries, delta } = match sync_source.list_changes(last_delta).await? {
+        None => return Ok(SnapshotResultInternal::Unchanged),
+        Some(listing) => listing,
+    };
+    let tmp_dir = sync_file_storage.get_tmp_dir()?;
+    let lock_decision = LockDecision::detect(sync_source, &entries, time, tmp_dir.path()).await?;
+    report.lock = Some(lock_decision.status());
+    if lock_decision.is_locked() {
+        return Ok(SnapshotResultInternal::Locked);
+    }
+
+    let mut files = Vec::new();
+    let mut total_size: u64 = 0;
+    for entry in &entries {
+        match entry {
+            RemoteEntry::Folder { name } => {
+                report.unexpected_folders.push(name.clone());
+            }
+            RemoteEntry::File(file) => {
+                let sha256_lowercase = file.sha256.as_deref().unwrap_or("");
+                match SyncFile::create_from_filename(&file.name, sha256_lowercase) {
+                    Err(_) => {
+                        report.unexpected_files.push(file.name.clone());
+                    }
+                    Ok(sync_file) => {
+                        total_size += file.size;
+                        files.push((sync_file, file));
                     }
                 }
             }
+        }
+    }
 
-            // validate size
-            if total_size > limit::SYNC_FILE_LIMIT_PER_SNAPSHOT {
-                return Err(anyhow!(
-                    "snapshot is too big. size: {}, limit: {}",
-                    file_storage::byte_unit_to_string_hum(total_size),
-                    file_storage::byte_unit_to_string_hum(limit::SYNC_FILE_LIMIT_PER_SNAPSHOT)
-                ));
-            }
+    // validate size
+    if total_size > limits.sync_file_per_snapshot {
+        return Err(limit::LimitExceeded(format!(
+            "snapshot is too big. size: {}, limit: {}",
+            file_storage::byte_unit_to_string_hum(total_size),
+            file_storage::byte_unit_to_string_hum(limits.sync_file_per_snapshot)
+        ))
+        .into());
+    }
 
-            // download file
-            // TODO: parallel?
-            let tmp_dir = sync_file_storage.get_tmp_dir()?;
-            let mut downloaded = Vec::new();
-            for (sync_file, download_url) in &files {
-                if !sync_file_storage.has_file(user, &sync_file.sha256) {
-                    let resp = reqwest::get(download_url.to_owned()).await?;
-                    // NOTE: id is used for the tmp file name because it is unique. sha256 might not be
-                    // unique. we don't handle it in a smart way, but it should be fine, `sync_file_storage`
-                    // can handle this.
-                    let tmp_file_path = tmp_dir.path().join(sync_file.id.to_string());
-                    // sha256 will be validated later
-                    // TODO: async?
-                    std::fs::write(&tmp_file_path, resp.bytes().await?)?;
-                    downloaded.push((sync_file.sha256.as_str(), tmp_file_path));
+    // download file
+    let mut pending_downloads = Vec::new();
+    for (sync_file, file) in files.iter_mut() {
+        let file: &RemoteFile = file;
+        // if the source doesn't know the hash, we have to download the file to find out whether
+        // we already have it, unless it is the same version that we saw last time.
+        if file.sha256.is_none() {
+            let last_version = last_sync_state.and_then(|state| state.files.get(&file.name));
+            if let (Some(version), Some(last_version)) = (&file.version, last_version) {
+                if *version == last_version.version {
+                    sync_file.sha256 = last_version.sha256.clone();
                 }
             }
+        }
+        if !sync_file.sha256.is_empty()
+            && sync_file_storage.has_file(user, &sync_file.sha256).await?
+        {
+            continue;
+        }
+        // NOTE: id is used for the tmp file name because it is unique. sha256 might not be
+        // unique. we don't handle it in a smart way, but it should be fine, `sync_file_storage`
+        // can handle this.
+        let tmp_file_path = tmp_dir.path().join(sync_file.id.to_string());
+        pending_downloads.push(async move {
+            let sha256 = sync_source
+                .fetch_file(file, &tmp_file_path, limits.sync_file_per_file)
+                .await?;
+            let size = tokio::fs::metadata(&tmp_file_path).await?.len();
+            Ok::<_, Error>((sync_file, file, tmp_file_path, sha256, size))
+        });
+    }
+    let fetched: Vec<_> = stream::iter(pending_downloads)
+        .buffer_unordered(DOWNLOAD_CONCURRENCY)
+        .try_collect()
+        .await?;
 
-            logs.push(format!("new files: {}/{}", downloaded.len(), files.len()));
-            // save files
-            sync_file_storage.add_files(user, &downloaded[..])?;
-
-            let mut sync_files: HashMap<u32, String> = HashMap::new();
-            for (sync_file, _) in files {
-                sync_files.insert(sync_file.id, sync_file.sha256);
+    let mut downloaded = Vec::new();
+    let mut downloaded_sizes = Vec::new();
+    let mut invalid_ids = HashSet::new();
+    for (sync_file, file, tmp_file_path, sha256, size) in fetched {
+        report.bytes_downloaded += size;
+        // a broken file is left out instead of failing the whole sync, it may never get fixed.
+        if let Err(error) = fow_tile::validate(&tokio::fs::read(&tmp_file_path).await?) {
+            report.invalid_files.push(snapshot_log::InvalidFile {
+                name: file.name.clone(),
+                reason: error.to_string(),
+            });
+            invalid_ids.insert(sync_file.id);
+            continue;
+        }
+        if file.sha256.is_none() {
+            sync_file.sha256 = sha256;
+            if sync_file_storage.has_file(user, &sync_file.sha256).await? {
+                continue;
             }
-            Ok(SnapshotResultInternal::Ok(SyncFiles(sync_files), time))
         }
+        // sha256 provided by the source will be validated later
+        downloaded.push((sync_file.sha256.clone(), tmp_file_path));
+        downloaded_sizes.push(size);
     }
+
+    files.retain(|(sync_file, _)| !invalid_ids.contains(&sync_file.id));
+    report.files = files.len() as u32;
+    report.new_files = downloaded.len() as u32;
+    // files that fit are checked again when they are recorded, see `sync_blob::add_files`.
+    let new_size: u64 = downloaded_sizes.iter().sum();
+    if new_size > quota_left {
+        return Err(limit::QuotaExceeded(format!(
+            "out of sync file storage quota. need: {}, left: {}",
+            file_storage::byte_unit_to_string_hum(new_size),
+            file_storage::byte_unit_to_string_hum(quota_left)
+        ))
+        .into());
+    }
+    // save files
+    let new_files = sync_file_storage.add_files(user, &downloaded[..]).await?;
+
+    let mut sync_files: HashMap<u32, String> = HashMap::new();
+    let mut sync_state = SyncState {
+        files: Default::default(),
+        delta,
+    };
+    for (sync_file, file) in files {
+        if let Some(version) = &file.version {
+            sync_state.files.insert(
+                file.name.clone(),
+                FileVersion {
+                    version: version.clone(),
+                    sha256: sync_file.sha256.clone(),
+                },
+            );
+        }
+        sync_files.insert(sync_file.id, sync_file.sha256);
+    }
+    Ok(SnapshotResultInternal::Ok(
+        SyncFiles(sync_files),
+        new_files,
+        time,
+        sync_state,
+    ))
+}
+
+#[derive(Debug)]
+pub enum SnapshotOutput {
+    Synced {
+        sync_files: SyncFiles,
+        /// `[(sha-256, size)]` of the files added to the storage, they are not recorded in
+        /// `sync_blob` yet.
+        new_files: Vec<(String, u64)>,
+        time: DateTime<Utc>,
+        /// should be passed to the next sync of the same source
+        sync_state: SyncState,
+    },
+    /// nothing changed since the sync that produced `last_sync_state`
+    Unchanged,
 }
 
 #[derive(Debug)]
 pub struct SnapshotResult {
-    pub result: Result<(SyncFiles, DateTime<Utc>), ()>,
-    pub logs: Vec<String>,
+    pub result: Result<SnapshotOutput, ErrorClass>,
+    pub report: snapshot_log::Report,
 }
 
+impl SnapshotResult {
+    /// Turn the result into a failure, for errors found after syncing.
+    pub fn fail(&mut self, class: ErrorClass, message: String) {
+        self.report.outcome = snapshot_log::Outcome::Failed;
+        self.report.error = Some(snapshot_log::Error {
+            class: class.into(),
+            message,
+        });
+        self.result = Err(class);
+    }
+}
+
+/// Sync once. Nothing is retried here, the caller is expected to requeue the task according to
+/// the returned `ErrorClass`.
 pub async fn snapshot(
     source: &Source,
+    last_sync_state: Option<&SyncState>,
     user: &User,
     sync_file_storage: &file_storage::SyncFileStorage,
+    options: &SourceOptions,
+    limits: &limit::Limits,
+    quota_left: u64,
 ) -> SnapshotResult {
-    let mut logs = Vec::new();
-
-    for n in 1..=3 {
-        match snapshot_internal(source, &mut logs, user, sync_file_storage).await {
-            Ok(SnapshotResultInternal::Ok(sync_files, time)) => {
-                return SnapshotResult {
-                    result: Ok((sync_files, time)),
-                    logs,
-                }
-            }
-            Ok(SnapshotResultInternal::Locked) => {
-                if n < 3 {
-                    logs.push("Locked, trying again in 2 min.".into());
-                    sleep(std::time::Duration::from_secs(120)).await;
-                }
-            }
-            Err(error) => {
-                logs.push(error.to_string());
-                return SnapshotResult {
-                    result: Err(()),
-                    logs,
-                };
-            }
+    let start = Instant::now();
+    let mut report = snapshot_log::Report::new(snapshot_log::Outcome::Synced);
+    let sync_source = sync_source_of(source, options, user);
+    let result = match snapshot_internal(
+        sync_source.as_ref(),
+        last_sync_state,
+        &mut report,
+        user,
+        sync_file_storage,
+        limits,
+        quota_left,
+    )
+    .await
+    {
+        Ok(SnapshotResultInternal::Ok(sync_files, new_files, time, sync_state)) => {
+            Ok(SnapshotOutput::Synced {
+                sync_files,
+                new_files,
+                time,
+                sync_state,
+            })
         }
-    }
-    logs.push("Still locked, failed to sync.".into());
-    SnapshotResult {
-        result: Err(()),
-        logs,
-    }
+        Ok(SnapshotResultInternal::Unchanged) => {
+            report.outcome = snapshot_log::Outcome::Unchanged;
+            Ok(SnapshotOutput::Unchanged)
+        }
+        Ok(SnapshotResultInternal::Locked) => {
+            report.outcome = snapshot_log::Outcome::Locked;
+            Err(ErrorClass::Locked)
+        }
+        Err(error) => {
+            let class = classify_error(&error);
+            report.outcome = snapshot_log::Outcome::Failed;
+            report.error = Some(snapshot_log::Error {
+                class: class.into(),
+                message: error.to_string(),
+            });
+            Err(class)
+        }
+    };
+    report.duration_ms = start.elapsed().as_millis() as u64;
+    SnapshotResult { result, report }
 }
@@ -0,0 +1,262 @@
+use super::{RemoteEntry, RemoteFile, SyncSource, ValidationError};
+use crate::file_storage;
+use anyhow::Error;
+use async_trait::async_trait;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncReadExt;
+
+/// A folder on the server's own disk, e.g. kept in sync with the phone by Syncthing. `path` should
+/// point to the "Fog of World" folder and must be inside `root`.
+#[derive(Clone)]
+pub struct LocalPath {
+    path: PathBuf,
+    root: Option<PathBuf>,
+}
+
+impl LocalPath {
+    pub fn new(path: &str, root: Option<&Path>) -> LocalPath {
+        LocalPath {
+            path: PathBuf::from(path),
+            root: root.map(Path::to_path_buf),
+        }
+    }
+
+    /// `None` if the path doesn't exist or is not under `root`. Symlinks are resolved before
+    /// checking, so they cannot be used to escape `root`.
+    fn resolve_allowed(&self, path: &Path) -> Option<PathBuf> {
+        let root = fs::canonicalize(self.root.as_ref()?).ok()?;
+        let path = fs::canonicalize(path).ok()?;
+        if path.starts_with(root) {
+            Some(path)
+        } else {
+            None
+        }
+    }
+
+    fn find_sync_folder(&self) -> Option<PathBuf> {
+        let sync_folder = self.resolve_allowed(&self.resolve_allowed(&self.path)?.join("Sync"))?;
+        if sync_folder.is_dir() {
+            Some(sync_folder)
+        } else {
+            None
+        }
+    }
+
+    fn list_files_blocking(&self) -> Result<Vec<RemoteEntry>, Error> {
+        let sync_folder = self
+            .find_sync_folder()
+            .ok_or_else(|| anyhow!("missing sync folder"))?;
+        let mut entries = Vec::new();
+        for dir_entry in fs::read_dir(sync_folder)? {
+            let dir_entry = dir_entry?;
+            let name = dir_entry
+                .file_name()
+                .into_string()
+                .map_err(|name| anyhow!("invalid file name: {:?}", name))?;
+            let path = self
+                .resolve_allowed(&dir_entry.path())
+                .ok_or_else(|| anyhow!("path is not allowed: {}", name))?;
+            let metadata = fs::metadata(&path)?;
+            if metadata.is_dir() {
+                entries.push(RemoteEntry::Folder { name });
+            } else {
+                // hashing a local file is cheap, and it saves us from copying files we already have.
+                let sha256 = file_storage::sha256_of_file(&path)?;
+                entries.push(RemoteEntry::File(RemoteFile {
+                    name,
+                    last_modified: metadata.modified()?.into(),
+                    size: metadata.len(),
+                    sha256: Some(sha256),
+                    version: None,
+                    locator: path
+                        .to_str()
+                        .ok_or_else(|| anyhow!("invalid path"))?
+                        .to_string(),
+                }));
+            }
+        }
+        Ok(entries)
+    }
+}
+
+#[async_trait]
+impl SyncSource for LocalPath {
+    async fn validate(&self) -> Result<Result<(), ValidationError>, Error> {
+        match self.resolve_allowed(&self.path) {
+            Some(path) if path.is_dir() => (),
+            _ => return Ok(Err(ValidationError::InvalidShare)),
+        }
+        match self.find_sync_folder() {
+            Some(_) => Ok(Ok(())),
+            None => Ok(Err(ValidationError::InvalidFolderStructure)),
+        }
+    }
+
+    async fn list_files(&self) -> Result<Vec<RemoteEntry>, Error> {
+        let local_path = self.clone();
+        tokio::task::spawn_blocking(move || local_path.list_files_blocking()).await?
+    }
+
+    async fn fetch_file(
+        &self,
+        file: &RemoteFile,
+        path: &Path,
+        max_size: u64,
+    ) -> Result<String, Error> {
+        // the folder may have changed since it was listed, e.g. the file is now a symlink.
+        let local_path = self.clone();
+        let locator = PathBuf::from(&file.locator);
+        let source_path = tokio::task::spawn_blocking(move || local_path.resolve_allowed(&locator))
+            .await?
+            .ok_or_else(|| anyhow!("path is not allowed: {}", file.name))?;
+        // we copy instead of link, the original file may be changed by the sync tool at any time.
+        let mut source = tokio::fs::File::open(source_path).await?;
+        let mut writer = file_storage::HashingFileWriter::create(path, max_size).await?;
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = source.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            writer.write(&buf[..n]).await?;
+        }
+        Ok(writer.finish().await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{snapshot_internal, SnapshotResultInternal, LOCK_FILE_NAME};
+    use super::*;
+    use crate::file_storage::SyncFileStorage;
+    use crate::test_utils::limits;
+    use crate::user_handler::User;
+    use entity::snapshot_log::{Outcome, Report};
+
+    fn setup_fog_of_world_folder() -> tempfile::TempDir {
+        let root = tempfile::tempdir().unwrap();
+        let sync_folder = root.path().join("Fog of World").join("Sync");
+        fs::create_dir_all(&sync_folder).unwrap();
+        fs::copy(
+            "../editor/src/__tests__/data/23e4lltkkoke",
+            sync_folder.join("23e4lltkkoke"),
+        )
+        .unwrap();
+        root
+    }
+
+    #[tokio::test]
+    async fn test_validate() {
+        let root = setup_fog_of_world_folder();
+        let fog_of_world = root.path().join("Fog of World");
+        let fog_of_world = fog_of_world.to_str().unwrap();
+
+        let local_path = LocalPath::new(fog_of_world, Some(root.path()));
+        assert!(matches!(local_path.validate().await.unwrap(), Ok(())));
+
+        let local_path = LocalPath::new(root.path().to_str().unwrap(), Some(root.path()));
+        assert!(matches!(
+            local_path.validate().await.unwrap(),
+            Err(ValidationError::InvalidFolderStructure)
+        ));
+
+        // not configured
+        let local_path = LocalPath::new(fog_of_world, None);
+        assert!(matches!(
+            local_path.validate().await.unwrap(),
+            Err(ValidationError::InvalidShare)
+        ));
+
+        // escaping the root
+        let other_root = tempfile::tempdir().unwrap();
+        let local_path = LocalPath::new(fog_of_world, Some(other_root.path()));
+        assert!(matches!(
+            local_path.validate().await.unwrap(),
+            Err(ValidationError::InvalidShare)
+        ));
+        let link = other_root.path().join("link");
+        std::os::unix::fs::symlink(fog_of_world, &link).unwrap();
+        let local_path = LocalPath::new(link.to_str().unwrap(), Some(other_root.path()));
+        assert!(matches!(
+            local_path.validate().await.unwrap(),
+            Err(ValidationError::InvalidShare)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_snapshot() {
+        let root = setup_fog_of_world_folder();
+        let fog_of_world = root.path().join("Fog of World");
+        let local_path = LocalPath::new(fog_of_world.to_str().unwrap(), Some(root.path()));
+
+        let data_dir = tempfile::tempdir().unwrap();
+        let storage = SyncFileStorage::init(data_dir.path().to_str().unwrap()).unwrap();
+        let user = User { uid: 1 };
+
+        let lock_file = fog_of_world.join("Sync").join(LOCK_FILE_NAME);
+        fs::write(&lock_file, "").unwrap();
+        let result = snapshot_internal(
+            &local_path,
+            None,
+            &mut Report::new(Outcome::Synced),
+            &user,
+            &storage,
+            &limits(),
+            u64::MAX,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(result, SnapshotResultInternal::Locked));
+        fs::remove_file(&lock_file).unwrap();
+
+        let mut report = Report::new(Outcome::Synced);
+        let result = snapshot_internal(
+            &local_path,
+            None,
+            &mut report,
+            &user,
+            &storage,
+            &limits(),
+            u64::MAX,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(result, SnapshotResultInternal::Ok(..)));
+        assert!(storage
+            .has_file(
+                &user,
+                "48d7a1b6d4e5c943e1afcf10d094e16e239c71ad7b3e118359936d137781b0cc"
+            )
+            .await
+            .unwrap());
+        assert_eq!(report.to_string(), "lock: no lock file\nnew files: 1/1");
+    }
+    #[tokio::test]
+    async fn test_fetch_file_checks_path_again() {
+        let root = setup_fog_of_world_folder();
+        let fog_of_world = root.path().join("Fog of World");
+        let local_path = LocalPath::new(fog_of_world.to_str().unwrap(), Some(root.path()));
+        let file = match local_path.list_files().await.unwrap().pop().unwrap() {
+            RemoteEntry::File(file) => file,
+            RemoteEntry::Folder { .. } => panic!("expecting a file"),
+        };
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("file");
+        assert!(local_path
+            .fetch_file(&file, &path, 1024 * 1024)
+            .await
+            .is_ok());
+
+        // replaced by a link to somewhere else after listing
+        let outside = tempfile::tempdir().unwrap();
+        let secret = outside.path().join("secret");
+        fs::write(&secret, "secret").unwrap();
+        fs::remove_file(&file.locator).unwrap();
+        std::os::unix::fs::symlink(&secret, &file.locator).unwrap();
+        assert!(local_path
+            .fetch_file(&file, &path, 1024 * 1024)
+            .await
+            .is_err());
+    }
+}
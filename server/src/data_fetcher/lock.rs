@@ -0,0 +1,163 @@
+use super::{RemoteEntry, SyncSource};
+use anyhow::Error;
+use chrono::prelude::*;
+use chrono::Duration;
+use entity::snapshot_log::LockStatus;
+use std::path::Path;
+
+pub const LOCK_FILE_NAME: &str = "FoW-Sync-Lock";
+
+// the lock file is tiny, anything bigger is not a lock file we understand.
+const LOCK_FILE_SIZE_LIMIT: u64 = 64 * 1024;
+
+// Old FoW versions remove the lock file after syncing, newer ones keep it around, so when we
+// can't tell the status from the content, we ignore locks that are older than this.
+const LOCK_EXPIRY_MINUTES: i64 = 15;
+
+/// Whether FoW is writing to the `Sync` folder and why we think so. This ends up in the snapshot
+/// log so we can figure out what FoW is doing when a sync goes wrong.
+#[derive(Debug, PartialEq, Eq)]
+pub enum LockDecision {
+    NoLockFile,
+    /// decoded from the content of the lock file
+    InProgress,
+    /// decoded from the content of the lock file
+    Idle,
+    /// we don't understand the content, so we decide by how old the lock file is.
+    LockFile {
+        age: Duration,
+    },
+}
+
+impl LockDecision {
+    /// The lock file is downloaded to `tmp_dir` and decoded, see `decode_lock_file`.
+    pub async fn detect(
+        sync_source: &dyn SyncSource,
+        entries: &[RemoteEntry],
+        now: DateTime<Utc>,
+        tmp_dir: &Path,
+    ) -> Result<LockDecision, Error> {
+        let lock_file = entries.iter().find_map(|entry| match entry {
+            RemoteEntry::File(file) if file.name == LOCK_FILE_NAME => Some(file),
+            _ => None,
+        });
+        let lock_file = match lock_file {
+            None => return Ok(LockDecision::NoLockFile),
+            Some(lock_file) => lock_file,
+        };
+        let path = tmp_dir.join(LOCK_FILE_NAME);
+        sync_source
+            .fetch_file(lock_file, &path, LOCK_FILE_SIZE_LIMIT)
+            .await?;
+        Ok(match decode_lock_file(&tokio::fs::read(&path).await?) {
+            Some(true) => LockDecision::InProgress,
+            Some(false) => LockDecision::Idle,
+            None => LockDecision::LockFile {
+                age: now - lock_file.last_modified,
+            },
+        })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        match self {
+            LockDecision::NoLockFile | LockDecision::Idle => false,
+            LockDecision::InProgress => true,
+            LockDecision::LockFile { age } => *age <= Duration::minutes(LOCK_EXPIRY_MINUTES),
+        }
+    }
+
+    pub fn status(&self) -> LockStatus {
+        match self {
+            LockDecision::NoLockFile => LockStatus::NoLockFile,
+            LockDecision::InProgress => LockStatus::InProgress,
+            LockDecision::Idle => LockStatus::Idle,
+            LockDecision::LockFile { age } => LockStatus::LockFile {
+                age_minutes: age.num_minutes(),
+                locked: self.is_locked(),
+            },
+        }
+    }
+}
+
+fn status_of_value(value: &str) -> Option<bool> {
+    match value.trim().to_lowercase().as_str() {
+        "1" | "true" | "locked" | "syncing" => Some(true),
+        "0" | "false" | "unlocked" | "idle" => Some(false),
+        _ => None,
+    }
+}
+
+/// The format is not documented, newer FoW versions keep the lock file and write the status
+/// into it. We accept the status as the whole content (`locked`/`unlocked`, `1`/`0`), as a
+/// `key=value` line, or as a JSON object, with `locked` or `status` as the key. `Some(true)`
+/// means a sync is in progress, `None` means we don't understand the content (e.g. it is empty).
+fn decode_lock_file(content: &[u8]) -> Option<bool> {
+    const KEYS: [&str; 2] = ["locked", "status"];
+    let content = String::from_utf8_lossy(content);
+    let content = content.trim_start_matches('\u{feff}').trim();
+    if let Ok(serde_json::Value::Object(object)) = serde_json::from_str(content) {
+        return KEYS
+            .iter()
+            .find_map(|key| object.get(*key))
+            .and_then(|value| match value {
+                serde_json::Value::Bool(locked) => Some(*locked),
+                serde_json::Value::String(value) => status_of_value(value),
+                _ => None,
+            });
+    }
+    if let Some(status) = status_of_value(content) {
+        return Some(status);
+    }
+    content.lines().find_map(|line| {
+        let (key, value) = line.split_once('=')?;
+        if KEYS.contains(&key.trim().to_lowercase().as_str()) {
+            status_of_value(value)
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_lock_file() {
+        assert_eq!(decode_lock_file(b""), None);
+        assert_eq!(decode_lock_file(b"\n"), None);
+        assert_eq!(decode_lock_file(b"\x00\x01garbage"), None);
+        assert_eq!(decode_lock_file(b"locked\n"), Some(true));
+        assert_eq!(decode_lock_file(b"0"), Some(false));
+        assert_eq!(decode_lock_file(b"device=iPhone\nlocked=1\n"), Some(true));
+        assert_eq!(decode_lock_file(b"status = idle"), Some(false));
+        assert_eq!(decode_lock_file(br#"{"locked": false}"#), Some(false));
+        assert_eq!(
+            decode_lock_file(br#"{"device": "iPhone", "status": "syncing"}"#),
+            Some(true)
+        );
+        assert_eq!(decode_lock_file(br#"{"device": "iPhone"}"#), None);
+    }
+
+    #[test]
+    fn test_lock_decision() {
+        assert!(!LockDecision::NoLockFile.is_locked());
+        assert!(LockDecision::InProgress.is_locked());
+        assert!(!LockDecision::Idle.is_locked());
+        let decision = LockDecision::LockFile {
+            age: Duration::minutes(3),
+        };
+        assert!(decision.is_locked());
+        assert_eq!(
+            decision.status(),
+            LockStatus::LockFile {
+                age_minutes: 3,
+                locked: true
+            }
+        );
+        assert!(!LockDecision::LockFile {
+            age: Duration::hours(2)
+        }
+        .is_locked());
+    }
+}
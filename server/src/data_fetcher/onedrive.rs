@@ -0,0 +1,536 @@
+use super::{
+    check_status, save_response, Listing, RemoteEntry, RemoteFile, SyncSource, ValidationError,
+};
+use anyhow::Error;
+use async_trait::async_trait;
+use base64::Engine;
+use chrono::prelude::*;
+use entity::snapshot_task::{Delta, DeltaFile, DeltaItem};
+use std::collections::BTreeMap;
+use std::path::Path;
+use tokio::sync::OnceCell;
+
+const API_BASE: &str = "https://api.onedrive.com/v1.0";
+
+/// A shared "Fog of World" folder, accessed anonymously through its sharing link.
+///
+/// The `Sync` folder is listed with `/delta`. The first sync enumerates the whole folder, later
+/// syncs only ask for what changed since the `@odata.deltaLink` of the last sync and apply it to
+/// the listing kept in `SyncState::delta`. If the delta link expired we start over. Files we
+/// already have are not downloaded again, they are deduped by the SHA-256 that onedrive gives us.
+pub struct OneDrive {
+    api_base: String,
+    share_url: String,
+    sync_folder: OnceCell<SyncFolder>,
+}
+
+struct SyncFolder {
+    link: String,
+    id: String,
+}
+
+impl OneDrive {
+    pub fn new(share_url: &str) -> OneDrive {
+        OneDrive::with_api_base(API_BASE, share_url)
+    }
+
+    /// `api_base` is configurable so we can test against a fake server.
+    pub fn with_api_base(api_base: &str, share_url: &str) -> OneDrive {
+        OneDrive {
+            api_base: String::from(api_base),
+            share_url: String::from(share_url),
+            sync_folder: OnceCell::new(),
+        }
+    }
+
+    fn api_of_link(&self, url: &str) -> String {
+        format!(
+            "{}/shares/u!{}",
+            self.api_base,
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(url)
+        )
+    }
+
+    async fn find_sync_folder(&self) -> Result<Option<SyncFolder>, Error> {
+        let resp = check_status(
+            reqwest::get(self.api_of_link(&self.share_url) + "/root/children").await?,
+        )?
+        .json::<serde_json::Value>()
+        .await?;
+        for child in resp["value"]
+            .as_array()
+            .ok_or_else(|| anyhow!("invalid api response"))?
+        {
+            if let Some("Sync") = child["name"].as_str() {
+                return Ok(match (child["webUrl"].as_str(), child["id"].as_str()) {
+                    (Some(link), Some(id)) => Some(SyncFolder {
+                        link: String::from(link),
+                        id: String::from(id),
+                    }),
+                    _ => None,
+                });
+            }
+        }
+        Ok(None)
+    }
+
+    /// same as `find_sync_folder` but fails if it is missing, and only asks onedrive once.
+    async fn sync_folder(&self) -> Result<&SyncFolder, Error> {
+        self.sync_folder
+            .get_or_try_init(|| async {
+                self.find_sync_folder()
+                    .await?
+                    .ok_or_else(|| anyhow!("missing sync folder"))
+            })
+            .await
+    }
+
+    /// Follow `link` until the end of the delta and apply the changes to `items`. Returns the
+    /// delta link for next time, or `None` if `link` expired.
+    async fn apply_delta(
+        &self,
+        sync_folder: &SyncFolder,
+        link: &str,
+        items: &mut BTreeMap<String, DeltaItem>,
+    ) -> Result<Option<String>, Error> {
+        let mut current_link = String::from(link);
+        loop {
+            let resp = reqwest::get(&current_link).await?;
+            if resp.status() == reqwest::StatusCode::GONE {
+                return Ok(None);
+            }
+            let resp = check_status(resp)?.json::<serde_json::Value>().await?;
+            for item in resp["value"]
+                .as_array()
+                .ok_or_else(|| anyhow!("invalid api response"))?
+            {
+                let id = item["id"]
+                    .as_str()
+                    .ok_or_else(|| anyhow!("invalid api response"))?;
+                // the delta covers everything under the folder (and the folder itself), we only
+                // care about its children. An item moved somewhere else looks like a deletion.
+                if !item["deleted"].is_null()
+                    || item["parentReference"]["id"].as_str() != Some(&sync_folder.id)
+                {
+                    items.remove(id);
+                } else {
+                    items.insert(String::from(id), parse_item(item)?);
+                }
+            }
+            match (
+                resp["@odata.nextLink"].as_str(),
+                resp["@odata.deltaLink"].as_str(),
+            ) {
+                (Some(next_link), _) => current_link = String::from(next_link),
+                (None, Some(delta_link)) => return Ok(Some(String::from(delta_link))),
+                (None, None) => return Err(anyhow!("invalid api response")),
+            }
+        }
+    }
+
+    fn to_entries(
+        &self,
+        sync_folder: &SyncFolder,
+        items: &BTreeMap<String, DeltaItem>,
+    ) -> Vec<RemoteEntry> {
+        items
+            .iter()
+            .map(|(id, item)| match &item.file {
+                None => RemoteEntry::Folder {
+                    name: item.name.clone(),
+                },
+                Some(file) => RemoteEntry::File(RemoteFile {
+                    name: item.name.clone(),
+                    last_modified: file.last_modified,
+                    size: file.size,
+                    sha256: Some(file.sha256.clone()),
+                    version: None,
+                    // download urls in the api response expire, this one doesn't.
+                    locator: format!(
+                        "{}/items/{}/content",
+                        self.api_of_link(&sync_folder.link),
+                        id
+                    ),
+                }),
+            })
+            .collect()
+    }
+}
+
+fn parse_item(item: &serde_json::Value) -> Result<DeltaItem, Error> {
+    let name = item["name"]
+        .as_str()
+        .ok_or_else(|| anyhow!("invalid api response"))?;
+    if item["file"].is_null() {
+        return Ok(DeltaItem {
+            name: String::from(name),
+            file: None,
+        });
+    }
+    let last_modified: DateTime<Utc> =
+        serde_json::from_value(item["lastModifiedDateTime"].clone())?;
+    let sha256 = item["file"]["hashes"]["sha256Hash"]
+        .as_str()
+        .ok_or_else(|| anyhow!("invalid api response"))?
+        .to_lowercase();
+    let size = item["size"]
+        .as_i64()
+        .ok_or_else(|| anyhow!("invalid api response"))?;
+    Ok(DeltaItem {
+        name: String::from(name),
+        file: Some(DeltaFile {
+            last_modified,
+            size: size as u64,
+            sha256,
+        }),
+    })
+}
+
+#[async_trait]
+impl SyncSource for OneDrive {
+    async fn validate(&self) -> Result<Result<(), ValidationError>, Error> {
+        let resp = reqwest::get(self.api_of_link(&self.share_url) + "/root").await?;
+        if resp.status() != 200 {
+            return Ok(Err(ValidationError::InvalidShare));
+        }
+        let root = resp.json::<serde_json::Value>().await?;
+        match root["name"].as_str() {
+            Some("Fog of World") => (),
+            _ => return Ok(Err(ValidationError::InvalidFolderStructure)),
+        }
+        match self.find_sync_folder().await? {
+            Some(_) => Ok(Ok(())),
+            None => Ok(Err(ValidationError::InvalidFolderStructure)),
+        }
+    }
+
+    async fn list_files(&self) -> Result<Vec<RemoteEntry>, Error> {
+        Ok(self
+            .list_changes(None)
+            .await?
+            .map(|listing| listing.entries)
+            .unwrap_or_default())
+    }
+
+    async fn list_changes(&self, last: Option<&Delta>) -> Result<Option<Listing>, Error> {
+        // we trust the hash provided by onedrive for deduping, but we recompute it after download.
+        let sync_folder = self.sync_folder().await?;
+        if let Some(last) = last {
+            let mut items = last.items.clone();
+            if let Some(link) = self
+                .apply_delta(sync_folder, &last.link, &mut items)
+                .await?
+            {
+                if items == last.items {
+                    return Ok(None);
+                }
+                return Ok(Some(Listing {
+                    entries: self.to_entries(sync_folder, &items),
+                    delta: Some(Delta { link, items }),
+                }));
+            }
+        }
+        let mut items = BTreeMap::new();
+        let link = self
+            .apply_delta(
+                sync_folder,
+                &(self.api_of_link(&sync_folder.link) + "/root/delta"),
+                &mut items,
+            )
+            .await?
+            .ok_or_else(|| anyhow!("invalid api response"))?;
+        Ok(Some(Listing {
+            entries: self.to_entries(sync_folder, &items),
+            delta: Some(Delta { link, items }),
+        }))
+    }
+
+    async fn fetch_file(
+        &self,
+        file: &RemoteFile,
+        path: &Path,
+        max_size: u64,
+    ) -> Result<String, Error> {
+        let resp = check_status(reqwest::get(&file.locator).await?)?;
+        save_response(resp, path, max_size).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{snapshot_internal, SnapshotResultInternal};
+    use super::*;
+    use crate::test_utils::limits;
+    use entity::snapshot_log::{Outcome, Report};
+    use entity::snapshot_task::SyncState;
+    use serde_json::json;
+
+    const SYNC_URL: &str = "https://1drv.ms/f/s!sync";
+
+    fn share_path(url: &str) -> String {
+        format!(
+            "/shares/u!{}",
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(url)
+        )
+    }
+
+    async fn mock_fog_of_world_share(server: &mut mockito::Server) -> OneDrive {
+        let share_url = "https://1drv.ms/f/s!fog-of-world";
+        server
+            .mock("GET", format!("{}/root", share_path(share_url)).as_str())
+            .with_body(json!({ "name": "Fog of World" }).to_string())
+            .create_async()
+            .await;
+        server
+            .mock(
+                "GET",
+                format!("{}/root/children", share_path(share_url)).as_str(),
+            )
+            .with_body(
+                json!({ "value": [
+                    { "id": "MODEL", "name": "Model", "folder": {} },
+                    { "id": "SYNC", "name": "Sync", "folder": {}, "webUrl": SYNC_URL },
+                ]})
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        OneDrive::with_api_base(&server.url(), share_url)
+    }
+
+    fn sync_file_item() -> serde_json::Value {
+        json!({
+            "id": "FILE",
+            "name": "23e4lltkkoke",
+            "parentReference": { "id": "SYNC" },
+            "file": { "hashes": { "sha256Hash":
+                "48D7A1B6D4E5C943E1AFCF10D094E16E239C71AD7B3E118359936D137781B0CC" } },
+            "size": 2784,
+            "lastModifiedDateTime": "2024-01-01T00:00:00Z",
+        })
+    }
+
+    #[tokio::test]
+    async fn test_validate() {
+        let mut server = mockito::Server::new_async().await;
+        let onedrive = mock_fog_of_world_share(&mut server).await;
+        assert!(matches!(onedrive.validate().await.unwrap(), Ok(())));
+
+        let onedrive = OneDrive::with_api_base(&server.url(), "https://1drv.ms/f/s!unknown");
+        server
+            .mock(
+                "GET",
+                format!("{}/root", share_path("https://1drv.ms/f/s!unknown")).as_str(),
+            )
+            .with_status(404)
+            .create_async()
+            .await;
+        assert!(matches!(
+            onedrive.validate().await.unwrap(),
+            Err(ValidationError::InvalidShare)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_list_files_follows_next_link() {
+        let mut server = mockito::Server::new_async().await;
+        let onedrive = mock_fog_of_world_share(&mut server).await;
+        server
+            .mock(
+                "GET",
+                format!("{}/root/delta", share_path(SYNC_URL)).as_str(),
+            )
+            .with_body(
+                json!({
+                    "value": [
+                        { "id": "SYNC", "name": "Sync", "folder": {},
+                          "parentReference": { "id": "FOG_OF_WORLD" } },
+                        sync_file_item(),
+                    ],
+                    "@odata.nextLink": format!("{}/page2", server.url()),
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        server
+            .mock("GET", "/page2")
+            .with_body(
+                json!({
+                    "value": [
+                        { "id": "BACKUP", "name": "backup", "folder": {},
+                          "parentReference": { "id": "SYNC" } },
+                        { "id": "NESTED", "name": "nested", "folder": {},
+                          "parentReference": { "id": "BACKUP" } },
+                    ],
+                    "@odata.deltaLink": format!("{}/delta", server.url()),
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+
+        let entries = onedrive.list_files().await.unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(matches!(&entries[0], RemoteEntry::Folder { name } if name == "backup"));
+        match &entries[1] {
+            RemoteEntry::File(file) => {
+                assert_eq!(file.name, "23e4lltkkoke");
+                assert_eq!(
+                    file.sha256.as_deref(),
+                    Some("48d7a1b6d4e5c943e1afcf10d094e16e239c71ad7b3e118359936d137781b0cc")
+                );
+                assert_eq!(file.size, 2784);
+                assert_eq!(
+                    file.locator,
+                    format!(
+                        "{}{}/items/FILE/content",
+                        server.url(),
+                        share_path(SYNC_URL)
+                    )
+                );
+            }
+            RemoteEntry::Folder { .. } => panic!("expecting a file"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_file() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/download")
+            .with_body("fog of world")
+            .create_async()
+            .await;
+        let onedrive = OneDrive::with_api_base(&server.url(), "");
+        let file = RemoteFile {
+            name: "23e4lltkkoke".into(),
+            last_modified: Utc::now(),
+            size: 12,
+            sha256: None,
+            version: None,
+            locator: format!("{}/download", server.url()),
+        };
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("file");
+
+        let sha256 = onedrive.fetch_file(&file, &path, 1024).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"fog of world");
+        assert_eq!(sha256, crate::file_storage::sha256_of_file(&path).unwrap());
+
+        assert!(onedrive.fetch_file(&file, &path, 4).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_delta() {
+        let mut server = mockito::Server::new_async().await;
+        let onedrive = mock_fog_of_world_share(&mut server).await;
+        let sync_path = share_path(SYNC_URL);
+        let full_listing = server
+            .mock("GET", format!("{}/root/delta", sync_path).as_str())
+            .with_body(
+                json!({
+                    "value": [sync_file_item()],
+                    "@odata.deltaLink": format!("{}/delta1", server.url()),
+                })
+                .to_string(),
+            )
+            .expect(2)
+            .create_async()
+            .await;
+        let download = server
+            .mock("GET", format!("{}/items/FILE/content", sync_path).as_str())
+            .with_body(std::fs::read("../editor/src/__tests__/data/23e4lltkkoke").unwrap())
+            .expect(1)
+            .create_async()
+            .await;
+        let data_dir = tempfile::tempdir().unwrap();
+        let storage =
+            crate::file_storage::SyncFileStorage::init(data_dir.path().to_str().unwrap()).unwrap();
+        let user = crate::user_handler::User { uid: 1 };
+        let sync = |sync_state: Option<SyncState>| {
+            let (onedrive, storage, user) = (&onedrive, &storage, &user);
+            async move {
+                snapshot_internal(
+                    onedrive,
+                    sync_state.as_ref(),
+                    &mut Report::new(Outcome::Synced),
+                    user,
+                    storage,
+                    &limits(),
+                    u64::MAX,
+                )
+                .await
+                .unwrap()
+            }
+        };
+
+        let sync_state = match sync(None).await {
+            SnapshotResultInternal::Ok(sync_files, _, _, sync_state) => {
+                assert_eq!(sync_files.0.len(), 1);
+                sync_state
+            }
+            _ => panic!("should be synced"),
+        };
+        let delta = sync_state.delta.clone().unwrap();
+        assert_eq!(delta.link, format!("{}/delta1", server.url()));
+        assert_eq!(delta.items.len(), 1);
+
+        // nothing changed
+        let unchanged = server
+            .mock("GET", "/delta1")
+            .with_body(
+                json!({
+                    "value": [{ "id": "SYNC", "name": "Sync", "folder": {},
+                                "parentReference": { "id": "FOG_OF_WORLD" } }],
+                    "@odata.deltaLink": format!("{}/delta2", server.url()),
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        assert!(matches!(
+            sync(Some(sync_state.clone())).await,
+            SnapshotResultInternal::Unchanged
+        ));
+
+        // the file is deleted
+        unchanged.remove_async().await;
+        server
+            .mock("GET", "/delta1")
+            .with_body(
+                json!({
+                    "value": [{ "id": "FILE", "deleted": {} }],
+                    "@odata.deltaLink": format!("{}/delta2", server.url()),
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        match sync(Some(sync_state.clone())).await {
+            SnapshotResultInternal::Ok(sync_files, _, _, sync_state) => {
+                assert!(sync_files.0.is_empty());
+                let delta = sync_state.delta.unwrap();
+                assert_eq!(delta.link, format!("{}/delta2", server.url()));
+                assert!(delta.items.is_empty());
+            }
+            _ => panic!("should be synced"),
+        }
+
+        // the delta link expired, start over
+        server
+            .mock("GET", "/expired")
+            .with_status(410)
+            .create_async()
+            .await;
+        let mut expired = sync_state.clone();
+        expired.delta.as_mut().unwrap().link = format!("{}/expired", server.url());
+        assert!(matches!(
+            sync(Some(expired)).await,
+            SnapshotResultInternal::Ok(..)
+        ));
+
+        full_listing.assert_async().await;
+        download.assert_async().await;
+    }
+}
@@ -0,0 +1,487 @@
+use super::{check_status, save_response, RemoteEntry, RemoteFile, SyncSource, ValidationError};
+use crate::utils;
+use anyhow::Error;
+use async_trait::async_trait;
+use chrono::prelude::*;
+use reqwest::{Method, StatusCode, Url};
+use std::path::Path;
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8" ?>
+<d:propfind xmlns:d="DAV:">
+  <d:prop>
+    <d:resourcetype/>
+    <d:getcontentlength/>
+    <d:getlastmodified/>
+    <d:getetag/>
+  </d:prop>
+</d:propfind>"#;
+
+/// e.g. Nextcloud/ownCloud. `url` should point to the "Fog of World" folder, something like
+/// `https://cloud.example.com/remote.php/dav/files/<user>/Apps/Fog of World/`.
+/// WebDAV doesn't give us hashes, so files are not downloaded again if their ETag is the same as
+/// in the last sync.
+pub struct WebDav {
+    url: String,
+    username: String,
+    password: String,
+    allow_private_addresses: bool,
+    client: reqwest::Client,
+}
+
+struct DavEntry {
+    url: Url,
+    name: String,
+    is_collection: bool,
+    size: Option<u64>,
+    last_modified: Option<DateTime<Utc>>,
+    etag: Option<String>,
+}
+
+impl WebDav {
+    pub fn new(url: &str, username: &str, password: &str, allow_private_addresses: bool) -> WebDav {
+        // relative hrefs are resolved against this, so it must be treated as a folder.
+        let url = if url.ends_with('/') {
+            String::from(url)
+        } else {
+            format!("{}/", url)
+        };
+        WebDav {
+            url,
+            username: String::from(username),
+            password: String::from(password),
+            allow_private_addresses,
+            client: utils::public_http_client(allow_private_addresses),
+        }
+    }
+
+    fn check_url(&self, url: &str) -> Result<(), Error> {
+        if self.allow_private_addresses {
+            return Ok(());
+        }
+        utils::check_url_host(&Url::parse(url)?)
+    }
+
+    async fn propfind(&self, url: &str) -> Result<reqwest::Response, Error> {
+        self.check_url(url)?;
+        Ok(self
+            .client
+            .request(Method::from_bytes(b"PROPFIND")?, url)
+            .basic_auth(&self.username, Some(&self.password))
+            .header("Depth", "1")
+            .header("Content-Type", "application/xml")
+            .body(PROPFIND_BODY)
+            .send()
+            .await?)
+    }
+
+    /// list direct children of the folder at `url`
+    async fn list_folder(&self, url: &str) -> Result<Vec<DavEntry>, Error> {
+        let resp = check_status(self.propfind(url).await?)?;
+        if resp.status() != StatusCode::MULTI_STATUS {
+            return Err(anyhow!("invalid webdav response"));
+        }
+        parse_multistatus(&Url::parse(url)?, &resp.text().await?)
+    }
+
+    async fn find_sync_folder_link(&self) -> Result<Option<String>, Error> {
+        Ok(sync_folder_link(self.list_folder(&self.url).await?))
+    }
+}
+
+fn sync_folder_link(entries: Vec<DavEntry>) -> Option<String> {
+    entries
+        .into_iter()
+        .find(|entry| entry.is_collection && entry.name == "Sync")
+        .map(|entry| entry.url.to_string())
+}
+
+fn dav_child<'a, 'input>(
+    node: roxmltree::Node<'a, 'input>,
+    name: &str,
+) -> Option<roxmltree::Node<'a, 'input>> {
+    node.descendants()
+        .find(|n| n.tag_name().namespace() == Some("DAV:") && n.tag_name().name() == name)
+}
+
+fn parse_multistatus(folder_url: &Url, body: &str) -> Result<Vec<DavEntry>, Error> {
+    let doc = roxmltree::Document::parse(body)?;
+    let decoded_folder_path = percent_encoding::percent_decode_str(folder_url.path())
+        .decode_utf8()?
+        .trim_end_matches('/')
+        .to_string();
+    let mut entries = Vec::new();
+    for response in doc
+        .root_element()
+        .children()
+        .filter(|n| n.tag_name().namespace() == Some("DAV:") && n.tag_name().name() == "response")
+    {
+        let href = dav_child(response, "href")
+            .and_then(|n| n.text())
+            .ok_or_else(|| anyhow!("invalid webdav response"))?;
+        let url = folder_url.join(href.trim())?;
+        // hrefs can be absolute, we must not send the credentials anywhere else.
+        if url.origin() != folder_url.origin() {
+            return Err(anyhow!("webdav response points to another server: {}", url));
+        }
+        let decoded_path = percent_encoding::percent_decode_str(url.path()).decode_utf8()?;
+        let decoded_path = decoded_path.trim_end_matches('/');
+        // the folder itself is also in the response
+        if decoded_path == decoded_folder_path {
+            continue;
+        }
+        let name = decoded_path
+            .rsplit('/')
+            .next()
+            .map(String::from)
+            .ok_or_else(|| anyhow!("invalid webdav response"))?;
+        let is_collection = dav_child(response, "resourcetype")
+            .map(|n| dav_child(n, "collection").is_some())
+            .unwrap_or(false);
+        let size = match dav_child(response, "getcontentlength").and_then(|n| n.text()) {
+            None => None,
+            Some(size) => Some(size.trim().parse()?),
+        };
+        let last_modified = match dav_child(response, "getlastmodified").and_then(|n| n.text()) {
+            None => None,
+            Some(time) => Some(DateTime::parse_from_rfc2822(time.trim())?.with_timezone(&Utc)),
+        };
+        let etag = dav_child(response, "getetag")
+            .and_then(|n| n.text())
+            .map(|etag| etag.trim().to_string());
+        entries.push(DavEntry {
+            url,
+            name,
+            is_collection,
+            size,
+            last_modified,
+            etag,
+        });
+    }
+    Ok(entries)
+}
+
+#[async_trait]
+impl SyncSource for WebDav {
+    async fn validate(&self) -> Result<Result<(), ValidationError>, Error> {
+        if !self.allow_private_addresses && !utils::is_public_url(&self.url).await {
+            return Ok(Err(ValidationError::InvalidShare));
+        }
+        let resp = self.propfind(&self.url).await?;
+        if resp.status() != StatusCode::MULTI_STATUS {
+            return Ok(Err(ValidationError::InvalidShare));
+        }
+        let entries = parse_multistatus(&Url::parse(&self.url)?, &resp.text().await?)?;
+        match sync_folder_link(entries) {
+            Some(_) => Ok(Ok(())),
+            None => Ok(Err(ValidationError::InvalidFolderStructure)),
+        }
+    }
+
+    async fn list_files(&self) -> Result<Vec<RemoteEntry>, Error> {
+        let link_for_sync_folder = self
+            .find_sync_folder_link()
+            .await?
+            .ok_or_else(|| anyhow!("missing sync folder"))?;
+        let mut entries = Vec::new();
+        for entry in self.list_folder(&link_for_sync_folder).await? {
+            if entry.is_collection {
+                entries.push(RemoteEntry::Folder { name: entry.name });
+            } else {
+                let last_modified = entry
+                    .last_modified
+                    .ok_or_else(|| anyhow!("invalid webdav response"))?;
+                let size = entry
+                    .size
+                    .ok_or_else(|| anyhow!("invalid webdav response"))?;
+                // servers without ETags still have the modification time and the size.
+                let version = entry
+                    .etag
+                    .unwrap_or_else(|| format!("{} {}", last_modified.to_rfc3339(), size));
+                entries.push(RemoteEntry::File(RemoteFile {
+                    name: entry.name,
+                    last_modified,
+                    size,
+                    // WebDAV doesn't give us a hash
+                    sha256: None,
+                    version: Some(version),
+                    locator: entry.url.to_string(),
+                }));
+            }
+        }
+        Ok(entries)
+    }
+
+    async fn fetch_file(
+        &self,
+        file: &RemoteFile,
+        path: &Path,
+        max_size: u64,
+    ) -> Result<String, Error> {
+        self.check_url(&file.locator)?;
+        let resp = check_status(
+            self.client
+                .get(&file.locator)
+                .basic_auth(&self.username, Some(&self.password))
+                .send()
+                .await?,
+        )?;
+        save_response(resp, path, max_size).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{snapshot_internal, SnapshotResultInternal, LOCK_FILE_NAME};
+    use super::*;
+    use crate::file_storage::SyncFileStorage;
+    use crate::test_utils::limits;
+    use crate::user_handler::User;
+    use entity::snapshot_log::{Outcome, Report};
+
+    const ROOT_PATH: &str = "/remote.php/dav/files/alice/Fog%20of%20World/";
+    const SYNC_PATH: &str = "/remote.php/dav/files/alice/Fog%20of%20World/Sync/";
+
+    fn multistatus(responses: &[(&str, bool, u64, &str)]) -> String {
+        let mut body = String::from(r#"<?xml version="1.0"?><d:multistatus xmlns:d="DAV:">"#);
+        for (href, is_collection, size, last_modified) in responses {
+            let resourcetype = if *is_collection {
+                "<d:collection/>"
+            } else {
+                ""
+            };
+            body += &format!(
+                "<d:response><d:href>{href}</d:href><d:propstat><d:prop>\
+                 <d:resourcetype>{resourcetype}</d:resourcetype>\
+                 <d:getcontentlength>{size}</d:getcontentlength>\
+                 <d:getlastmodified>{last_modified}</d:getlastmodified>\
+                 </d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response>"
+            );
+        }
+        body + "</d:multistatus>"
+    }
+
+    fn mock_root_folder(server: &mut mockito::Server) -> mockito::Mock {
+        let old = "Mon, 01 Jan 2024 00:00:00 GMT";
+        server
+            .mock("PROPFIND", ROOT_PATH)
+            .match_header("depth", "1")
+            .match_header("authorization", "Basic YWxpY2U6c2VjcmV0")
+            .with_status(207)
+            .with_body(multistatus(&[
+                (ROOT_PATH, true, 0, old),
+                (SYNC_PATH, true, 0, old),
+                (
+                    "/remote.php/dav/files/alice/Fog%20of%20World/Model/",
+                    true,
+                    0,
+                    old,
+                ),
+            ]))
+    }
+
+    fn fog_of_world_folder(server: &mockito::Server) -> WebDav {
+        WebDav::new(
+            &format!("{}/remote.php/dav/files/alice/Fog of World", server.url()),
+            "alice",
+            "secret",
+            true,
+        )
+    }
+
+    async fn mock_fog_of_world_folder(server: &mut mockito::Server) -> WebDav {
+        mock_root_folder(server).create_async().await;
+        fog_of_world_folder(server)
+    }
+
+    #[tokio::test]
+    async fn test_validate() {
+        let mut server = mockito::Server::new_async().await;
+        // one PROPFIND is enough
+        let root_folder = mock_root_folder(&mut server).expect(1).create_async().await;
+        let webdav = fog_of_world_folder(&server);
+        assert!(matches!(webdav.validate().await.unwrap(), Ok(())));
+        root_folder.assert_async().await;
+
+        server
+            .mock("PROPFIND", "/remote.php/dav/files/alice/Other/")
+            .with_status(207)
+            .with_body(multistatus(&[(
+                "/remote.php/dav/files/alice/Other/",
+                true,
+                0,
+                "Mon, 01 Jan 2024 00:00:00 GMT",
+            )]))
+            .create_async()
+            .await;
+        let webdav = WebDav::new(
+            &format!("{}/remote.php/dav/files/alice/Other/", server.url()),
+            "alice",
+            "secret",
+            true,
+        );
+        assert!(matches!(
+            webdav.validate().await.unwrap(),
+            Err(ValidationError::InvalidFolderStructure)
+        ));
+
+        server
+            .mock("PROPFIND", "/remote.php/dav/files/alice/Missing/")
+            .with_status(404)
+            .create_async()
+            .await;
+        let webdav = WebDav::new(
+            &format!("{}/remote.php/dav/files/alice/Missing", server.url()),
+            "alice",
+            "secret",
+            true,
+        );
+        assert!(matches!(
+            webdav.validate().await.unwrap(),
+            Err(ValidationError::InvalidShare)
+        ));
+
+        // the mock server is on loopback
+        let webdav = WebDav::new(
+            &format!("{}/remote.php/dav/files/alice/Fog of World", server.url()),
+            "alice",
+            "secret",
+            false,
+        );
+        assert!(matches!(
+            webdav.validate().await.unwrap(),
+            Err(ValidationError::InvalidShare)
+        ));
+        assert!(webdav.list_files().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_snapshot() {
+        let mut server = mockito::Server::new_async().await;
+        let webdav = mock_fog_of_world_folder(&mut server).await;
+        let data = std::fs::read("../editor/src/__tests__/data/23e4lltkkoke").unwrap();
+        let now = Utc::now().to_rfc2822();
+        let sync_listing = server
+            .mock("PROPFIND", SYNC_PATH)
+            .with_status(207)
+            .with_body(multistatus(&[
+                (SYNC_PATH, true, 0, &now),
+                (
+                    &format!("{SYNC_PATH}23e4lltkkoke"),
+                    false,
+                    data.len() as u64,
+                    &now,
+                ),
+                (&format!("{SYNC_PATH}{LOCK_FILE_NAME}"), false, 0, &now),
+            ]))
+            .create_async()
+            .await;
+        server
+            .mock("GET", format!("{SYNC_PATH}{LOCK_FILE_NAME}").as_str())
+            .with_body("")
+            .create_async()
+            .await;
+        let download = server
+            .mock("GET", format!("{SYNC_PATH}23e4lltkkoke").as_str())
+            .with_body(data)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let data_dir = tempfile::tempdir().unwrap();
+        let storage = SyncFileStorage::init(data_dir.path().to_str().unwrap()).unwrap();
+        let user = User { uid: 1 };
+
+        // the lock file is fresh and empty
+        let result = snapshot_internal(
+            &webdav,
+            None,
+            &mut Report::new(Outcome::Synced),
+            &user,
+            &storage,
+            &limits(),
+            u64::MAX,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(result, SnapshotResultInternal::Locked));
+
+        // the lock file is gone
+        sync_listing.remove_async().await;
+        server
+            .mock("PROPFIND", SYNC_PATH)
+            .with_status(207)
+            .with_body(multistatus(&[
+                (SYNC_PATH, true, 0, &now),
+                (&format!("{SYNC_PATH}23e4lltkkoke"), false, 2784, &now),
+            ]))
+            .create_async()
+            .await;
+        let mut report = Report::new(Outcome::Synced);
+        let sync_state = match snapshot_internal(
+            &webdav,
+            None,
+            &mut report,
+            &user,
+            &storage,
+            &limits(),
+            u64::MAX,
+        )
+        .await
+        .unwrap()
+        {
+            SnapshotResultInternal::Ok(sync_files, _, _, sync_state) => {
+                assert_eq!(
+                    sync_files.0.get(&117660).unwrap(),
+                    "48d7a1b6d4e5c943e1afcf10d094e16e239c71ad7b3e118359936d137781b0cc"
+                );
+                sync_state
+            }
+            _ => panic!("should be synced"),
+        };
+        assert_eq!(report.to_string(), "lock: no lock file\nnew files: 1/1");
+
+        // the file didn't change, so it is not downloaded again
+        let mut report = Report::new(Outcome::Synced);
+        let result = snapshot_internal(
+            &webdav,
+            Some(&sync_state),
+            &mut report,
+            &user,
+            &storage,
+            &limits(),
+            u64::MAX,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(result, SnapshotResultInternal::Ok(..)));
+        assert_eq!(report.to_string(), "lock: no lock file\nnew files: 0/1");
+        download.assert_async().await;
+    }
+
+    #[test]
+    fn test_reject_other_servers() {
+        let folder_url = Url::parse("https://cloud.example.com/dav/Fog%20of%20World/").unwrap();
+        let body = multistatus(&[
+            (
+                "/dav/Fog%20of%20World/",
+                true,
+                0,
+                "Mon, 01 Jan 2024 00:00:00 GMT",
+            ),
+            (
+                "https://attacker.example.com/dav/Sync/",
+                true,
+                0,
+                "Mon, 01 Jan 2024 00:00:00 GMT",
+            ),
+        ]);
+        assert!(parse_multistatus(&folder_url, &body).is_err());
+        let body = multistatus(&[(
+            "https://cloud.example.com/dav/Fog%20of%20World/Sync/",
+            true,
+            0,
+            "Mon, 01 Jan 2024 00:00:00 GMT",
+        )]);
+        assert_eq!(parse_multistatus(&folder_url, &body).unwrap().len(), 1);
+    }
+}
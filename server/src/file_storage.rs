@@ -1,12 +1,35 @@
 use crate::limit;
 use crate::user_handler::User;
 use anyhow::Error;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use futures::TryStreamExt;
 use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
 use std::{fs, io};
 use tempfile::TempDir;
+use tokio::io::AsyncWriteExt;
 
-// TODO: unit tests
+mod filesystem;
+mod s3;
+
+pub use filesystem::Filesystem;
+pub use s3::{S3Config, S3};
+
+/// The content of an object, chunk by chunk.
+pub type ByteStream = BoxStream<'static, Result<Bytes, Error>>;
+
+pub async fn read_to_end(mut stream: ByteStream) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::new();
+    while let Some(chunk) = stream.try_next().await? {
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
 
 pub fn byte_unit_to_string_hum(bytes: u64) -> String {
     byte_unit::Byte::from_u64(bytes)
@@ -14,106 +37,319 @@ pub fn byte_unit_to_string_hum(bytes: u64) -> String {
         .to_string()
 }
 
-fn dir_size(path: impl Into<PathBuf>) -> io::Result<u64> {
-    fn dir_size(mut dir: fs::ReadDir) -> io::Result<u64> {
-        dir.try_fold(0, |acc, file| {
-            let file = file?;
-            let size = match file.metadata()? {
-                data if data.is_dir() => dir_size(fs::read_dir(file.path())?)?,
-                data => data.len(),
-            };
-            Ok(acc + size)
+pub fn sha256_of_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut file = fs::File::open(path)?;
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Write a file chunk by chunk while computing its SHA-256, so we don't need to read it again or
+/// keep the whole thing in memory. Fails once the file grows bigger than `max_size`.
+pub struct HashingFileWriter {
+    file: tokio::fs::File,
+    hasher: Sha256,
+    size: u64,
+    max_size: u64,
+}
+
+impl HashingFileWriter {
+    pub async fn create(path: &Path, max_size: u64) -> io::Result<HashingFileWriter> {
+        Ok(HashingFileWriter {
+            file: tokio::fs::File::create(path).await?,
+            hasher: Sha256::new(),
+            size: 0,
+            max_size,
         })
     }
-    dir_size(fs::read_dir(path.into())?)
+
+    pub async fn write(&mut self, chunk: &[u8]) -> Result<(), Error> {
+        self.size += chunk.len() as u64;
+        if self.size > self.max_size {
+            return Err(limit::LimitExceeded(format!(
+                "file is too big. limit: {}",
+                byte_unit_to_string_hum(self.max_size)
+            ))
+            .into());
+        }
+        self.hasher.update(chunk);
+        self.file.write_all(chunk).await?;
+        Ok(())
+    }
+
+    /// returns the SHA-256 (lower case) of the file
+    pub async fn finish(mut self) -> io::Result<String> {
+        self.file.flush().await?;
+        Ok(format!("{:x}", self.hasher.finalize()))
+    }
+}
+
+pub struct Object {
+    pub key: String,
+    pub size: u64,
+    pub last_modified: SystemTime,
+}
+
+/// Where sync files are kept. Keys are `/` separated paths, e.g. `users/1/sync_files/<sha-256>`.
+/// Objects are never modified once they are stored.
+#[async_trait]
+pub trait Backend: Send + Sync {
+    async fn has(&self, key: &str) -> Result<bool, Error>;
+
+    /// Store the local file at `path` as `key`. The file may be moved away.
+    async fn put(&self, key: &str, path: &Path) -> Result<(), Error>;
+
+    /// The content is streamed, a download can contain lots of files.
+    async fn open(&self, key: &str) -> Result<ByteStream, Error>;
+
+    /// deleting something that doesn't exist is not an error.
+    async fn delete(&self, key: &str) -> Result<(), Error>;
+
+    /// all objects whose key starts with `prefix`.
+    async fn list(&self, prefix: &str) -> Result<Vec<Object>, Error>;
+
+    /// total size in bytes of the objects whose key starts with `prefix`.
+    async fn usage(&self, prefix: &str) -> Result<u64, Error>;
 }
 
 /// we use SHA-256 (lower case!!!) as the key of a file. We don't share file between users (I don't
 /// think different users will have a same file) so we don't really need to worry about hash collision.
 #[derive(Clone)]
 pub struct SyncFileStorage {
+    // only used for tmp files, files are stored in `backend`.
     data_base_dir: String,
+    backend: Arc<dyn Backend>,
 }
 
-// TODO: GC
 impl SyncFileStorage {
-    fn get_user_path(&self, user: &User) -> PathBuf {
-        Path::new(&self.data_base_dir)
-            .join("users")
-            .join(user.uid.to_string())
-            .join("sync_files")
+    fn user_prefix(user: &User) -> String {
+        format!("users/{}/sync_files/", user.uid)
+    }
+
+    fn key(user: &User, sha256: &str) -> String {
+        format!("{}{}", Self::user_prefix(user), sha256)
     }
 
+    /// Files are stored under `data_base_dir`.
     pub fn init(data_base_dir: &str) -> io::Result<SyncFileStorage> {
+        let backend = Arc::new(Filesystem::new(data_base_dir));
+        Self::init_with_backend(data_base_dir, backend)
+    }
+
+    pub fn init_with_backend(
+        data_base_dir: &str,
+        backend: Arc<dyn Backend>,
+    ) -> io::Result<SyncFileStorage> {
         let data_base_dir = String::from(data_base_dir);
         let tmp_dir = Path::new(&data_base_dir).join("tmp");
         if tmp_dir.exists() {
             fs::remove_dir_all(&tmp_dir)?
         }
         fs::create_dir_all(&tmp_dir)?;
-        Ok(SyncFileStorage { data_base_dir })
+        Ok(SyncFileStorage {
+            data_base_dir,
+            backend,
+        })
     }
 
     pub fn get_tmp_dir(&self) -> io::Result<TempDir> {
         TempDir::new_in(Path::new(&self.data_base_dir).join("tmp"))
     }
 
-    pub fn has_file(&self, user: &User, sha256: &str) -> bool {
-        self.get_user_path(user).join(sha256).exists()
+    pub async fn has_file(&self, user: &User, sha256: &str) -> Result<bool, Error> {
+        self.backend.has(&Self::key(user, sha256)).await
     }
 
-    /// `[(sha-256, file_path)]`, sha-256 will be recomputed from the file.
-    /// file will be moved to the permanent storage, NOTE that we move file by calling `fs::rename`
-    /// so the tmp file must at the same mount point.
-    pub fn add_files<S: AsRef<str>>(
+    /// `[(sha-256, file_path)]`, sha-256 will be recomputed from the file. Returns
+    /// `[(sha-256, size)]`, the quota is not checked here, see `sync_blob::add_files`.
+    /// files may be moved to the permanent storage by calling `fs::rename`, so the tmp file must
+    /// at the same mount point.
+    pub async fn add_files<S: AsRef<str>>(
         &self,
         user: &User,
         files: &[(S, PathBuf)],
-    ) -> Result<(), Error> {
-        // validate sha-256 and size
-        let mut size: u64 = 0;
+    ) -> Result<Vec<(String, u64)>, Error> {
+        // validate sha-256
+        let mut sizes = Vec::new();
         for (sha256, path) in files {
             let sha256 = sha256.as_ref();
-            let mut hasher = Sha256::new();
-            let mut file = fs::File::open(path)?;
-            size += file.metadata()?.len();
-            // TODO: async?
-            io::copy(&mut file, &mut hasher)?;
-            let hash = format!("{:x}", hasher.finalize());
+            let hash = {
+                let path = path.clone();
+                tokio::task::spawn_blocking(move || sha256_of_file(&path)).await??
+            };
             if hash != sha256 {
                 return Err(anyhow!("provided hash does not match the actual file. file: {}, expected_hash: {}, actual_hash: {}",
                                    path.display(), sha256, hash));
             }
+            sizes.push((hash, tokio::fs::metadata(path).await?.len()));
         }
-        let user_path = self.get_user_path(user);
-        fs::create_dir_all(&user_path)?;
-        let current_size = dir_size(&user_path)?;
-        if size + current_size > limit::SYNC_FILE_STORAGE_LIMIT_PER_USER {
-            return Err(anyhow!(
-                "out of sync file storage quota. current: {}, need: {}, limit: {}",
-                byte_unit_to_string_hum(current_size),
-                byte_unit_to_string_hum(size),
-                byte_unit_to_string_hum(limit::SYNC_FILE_STORAGE_LIMIT_PER_USER)
-            ));
-        }
-        // TODO: maybe validate zlib header?
         // all good, let's save files
         for (sha256, path) in files {
-            let target = user_path.join(sha256.as_ref());
-            if !target.exists() {
-                // race-condition should be fine
-                fs::rename(path, target)?
+            let key = Self::key(user, sha256.as_ref());
+            // race-condition should be fine
+            if !self.backend.has(&key).await? {
+                self.backend.put(&key, path).await?;
             }
         }
-        Ok(())
+        Ok(sizes)
+    }
+
+    /// All files of the user, `key`s are the sha-256.
+    pub async fn list_files(&self, user: &User) -> Result<Vec<Object>, Error> {
+        let prefix = Self::user_prefix(user);
+        let mut objects = self.backend.list(&prefix).await?;
+        for object in objects.iter_mut() {
+            object.key = object.key[prefix.len()..].to_string();
+        }
+        Ok(objects)
+    }
+
+    /// Total size of the files of the user in the storage. The quota is checked against the
+    /// records instead (`sync_blob::usage`), this is what they should add up to.
+    pub async fn usage(&self, user: &User) -> Result<u64, Error> {
+        self.backend.usage(&Self::user_prefix(user)).await
+    }
+
+    /// Users that have any files.
+    pub async fn list_users(&self) -> Result<Vec<User>, Error> {
+        let uids: BTreeSet<i64> = self
+            .backend
+            .list("users/")
+            .await?
+            .iter()
+            .filter_map(|object| object.key.split('/').nth(1)?.parse().ok())
+            .collect();
+        Ok(uids.into_iter().map(|uid| User { uid }).collect())
     }
 
-    pub fn open_file(&self, user: &User, sha256: &str) -> Result<std::fs::File, Error> {
-        let path = self.get_user_path(user).join(sha256);
-        let file = std::fs::File::options()
-            .read(true)
-            .write(false)
-            .open(path)?;
-        Ok(file)
+    pub async fn remove_file(&self, user: &User, sha256: &str) -> Result<(), Error> {
+        self.backend.delete(&Self::key(user, sha256)).await
+    }
+
+    pub async fn open_file(&self, user: &User, sha256: &str) -> Result<ByteStream, Error> {
+        self.backend.open(&Self::key(user, sha256)).await
+    }
+
+    pub async fn read_file(&self, user: &User, sha256: &str) -> Result<Vec<u8>, Error> {
+        read_to_end(self.open_file(user, sha256).await?).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    /// Keys are under `root`, so tests sharing a bucket don't see each other's objects.
+    async fn check_backend(backend: &dyn Backend, root: &str, tmp_dir: &Path) {
+        let key = |key: &str| format!("{}{}", root, key);
+        for (name, content) in [
+            ("users/1/sync_files/a", "aaa"),
+            ("users/1/sync_files/b", "bb"),
+            ("users/2/sync_files/c", "c"),
+        ] {
+            let path = tmp_dir.join(content);
+            fs::write(&path, content).unwrap();
+            backend.put(&key(name), &path).await.unwrap();
+        }
+        assert!(backend.has(&key("users/1/sync_files/a")).await.unwrap());
+        assert!(!backend.has(&key("users/1/sync_files/c")).await.unwrap());
+        let stream = backend.open(&key("users/1/sync_files/b")).await.unwrap();
+        assert_eq!(read_to_end(stream).await.unwrap(), b"bb");
+        assert!(backend.open(&key("users/1/sync_files/c")).await.is_err());
+
+        let mut objects = backend.list(&key("users/1/")).await.unwrap();
+        objects.sort_by(|a, b| a.key.cmp(&b.key));
+        let objects: Vec<_> = objects.iter().map(|o| (o.key.clone(), o.size)).collect();
+        assert_eq!(
+            objects,
+            vec![
+                (key("users/1/sync_files/a"), 3),
+                (key("users/1/sync_files/b"), 2)
+            ]
+        );
+        assert_eq!(backend.list(&key("users/")).await.unwrap().len(), 3);
+        assert_eq!(backend.usage(&key("users/1/")).await.unwrap(), 5);
+        assert_eq!(backend.usage(&key("nothing/")).await.unwrap(), 0);
+        // not a whole path segment
+        assert_eq!(backend.list(&key("users/1/sync")).await.unwrap().len(), 2);
+        assert!(backend.list(&key("nothing/")).await.unwrap().is_empty());
+
+        backend.delete(&key("users/1/sync_files/a")).await.unwrap();
+        backend.delete(&key("users/1/sync_files/a")).await.unwrap();
+        assert!(!backend.has(&key("users/1/sync_files/a")).await.unwrap());
+        assert_eq!(backend.list(&key("users/1/")).await.unwrap().len(), 1);
+
+        for name in ["users/1/sync_files/b", "users/2/sync_files/c"] {
+            backend.delete(&key(name)).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_filesystem_backend() {
+        let root = tempfile::tempdir().unwrap();
+        let tmp_dir = root.path().join("tmp");
+        fs::create_dir(&tmp_dir).unwrap();
+        check_backend(&Filesystem::new(root.path()), "", &tmp_dir).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs an S3 compatible storage, e.g. `TEST_S3_ENDPOINT=http://127.0.0.1:9000 cargo test -- --ignored`"]
+    async fn test_s3_backend() {
+        let root = format!("test_{:016x}/", rand::random::<u64>());
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let s3 = S3::new(test_utils::s3_config()).unwrap();
+        check_backend(&s3, &root, tmp_dir.path()).await;
+        assert!(s3.list(&root).await.unwrap().is_empty());
+
+        let wrong_secret = S3::new(S3Config {
+            secret_access_key: "wrong".into(),
+            ..test_utils::s3_config()
+        })
+        .unwrap();
+        assert!(wrong_secret.list(&root).await.is_err());
+        let http_not_allowed = S3::new(S3Config {
+            allow_http: false,
+            ..test_utils::s3_config()
+        })
+        .unwrap();
+        assert!(http_not_allowed.list(&root).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sync_file_storage() {
+        let data_dir = tempfile::tempdir().unwrap();
+        let storage = SyncFileStorage::init(data_dir.path().to_str().unwrap()).unwrap();
+        let user = User { uid: 7 };
+        let tmp_dir = storage.get_tmp_dir().unwrap();
+        let path = tmp_dir.path().join("file");
+        fs::write(&path, "content").unwrap();
+        let sha256 = sha256_of_file(&path).unwrap();
+        assert!(storage
+            .add_files(&user, &[("wrong hash", path.clone())])
+            .await
+            .is_err());
+        assert_eq!(
+            storage.add_files(&user, &[(&sha256, path)]).await.unwrap(),
+            vec![(sha256.clone(), 7)]
+        );
+
+        assert!(storage.has_file(&user, &sha256).await.unwrap());
+        assert_eq!(storage.read_file(&user, &sha256).await.unwrap(), b"content");
+        let files = storage.list_files(&user).await.unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].key, sha256);
+        let users: Vec<_> = storage
+            .list_users()
+            .await
+            .unwrap()
+            .iter()
+            .map(|user| user.uid)
+            .collect();
+        assert_eq!(users, vec![7]);
+        let missing = "0".repeat(64);
+        assert!(!storage.has_file(&user, &missing).await.unwrap());
+        storage.remove_file(&user, &sha256).await.unwrap();
+        assert!(!storage.has_file(&user, &sha256).await.unwrap());
     }
 }
@@ -0,0 +1,102 @@
+use super::{Backend, ByteStream, Object};
+use anyhow::Error;
+use async_trait::async_trait;
+use futures::{StreamExt, TryStreamExt};
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+use tokio_util::io::ReaderStream;
+
+/// Objects are files under `root`, keys are paths relative to it.
+pub struct Filesystem {
+    root: PathBuf,
+}
+
+impl Filesystem {
+    pub fn new(root: impl Into<PathBuf>) -> Filesystem {
+        Filesystem { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+fn list_dir(root: &Path, dir: &Path, objects: &mut Vec<Object>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            list_dir(root, &entry.path(), objects)?;
+        } else {
+            let path = entry.path();
+            let relative = path.strip_prefix(root).unwrap_or(&path);
+            let key: Vec<_> = relative
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect();
+            objects.push(Object {
+                key: key.join("/"),
+                size: metadata.len(),
+                last_modified: metadata.modified()?,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl Backend for Filesystem {
+    async fn has(&self, key: &str) -> Result<bool, Error> {
+        Ok(tokio::fs::try_exists(self.path(key)).await?)
+    }
+
+    /// NOTE: the file is moved by `fs::rename`, so it must be on the same mount point as `root`.
+    async fn put(&self, key: &str, path: &Path) -> Result<(), Error> {
+        let target = self.path(key);
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::rename(path, target).await?;
+        Ok(())
+    }
+
+    async fn open(&self, key: &str) -> Result<ByteStream, Error> {
+        let file = tokio::fs::File::open(self.path(key)).await?;
+        Ok(ReaderStream::new(file).map_err(Error::from).boxed())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        match tokio::fs::remove_file(self.path(key)).await {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<Object>, Error> {
+        // only walk the deepest folder that contains everything with the prefix
+        let dir = match prefix.rfind('/') {
+            None => self.root.clone(),
+            Some(i) => self.path(&prefix[..i]),
+        };
+        let root = self.root.clone();
+        let prefix = prefix.to_string();
+        tokio::task::spawn_blocking(move || {
+            let mut objects = Vec::new();
+            if dir.is_dir() {
+                list_dir(&root, &dir, &mut objects)?;
+            }
+            objects.retain(|object| object.key.starts_with(&prefix));
+            Ok(objects)
+        })
+        .await?
+    }
+
+    async fn usage(&self, prefix: &str) -> Result<u64, Error> {
+        Ok(self
+            .list(prefix)
+            .await?
+            .iter()
+            .map(|object| object.size)
+            .sum())
+    }
+}
@@ -0,0 +1,118 @@
+use super::{Backend, ByteStream, Object};
+use anyhow::Error;
+use async_trait::async_trait;
+use futures::{StreamExt, TryStreamExt};
+use object_store::aws::{AmazonS3, AmazonS3Builder};
+use object_store::buffered::BufWriter;
+use object_store::path::Path as ObjectPath;
+use object_store::ObjectStore;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+
+// Files are uploaded in parts of this size, so we don't need to keep a whole file in memory. It
+// is the smallest part size S3 accepts.
+const PART_SIZE: usize = 5 * 1024 * 1024;
+
+pub struct S3Config {
+    /// e.g. `https://s3.us-east-1.amazonaws.com` or `http://127.0.0.1:9000` for MinIO.
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// plain `http://` endpoints are refused unless this is set, e.g. for a local MinIO.
+    pub allow_http: bool,
+}
+
+/// An S3 compatible object storage. We always use path-style urls (`<endpoint>/<bucket>/<key>`),
+/// which is what MinIO and friends expect.
+pub struct S3 {
+    store: Arc<AmazonS3>,
+}
+
+impl S3 {
+    pub fn new(config: S3Config) -> Result<S3, Error> {
+        let store = AmazonS3Builder::new()
+            .with_endpoint(config.endpoint)
+            .with_allow_http(config.allow_http)
+            .with_bucket_name(config.bucket)
+            .with_region(config.region)
+            .with_access_key_id(config.access_key_id)
+            .with_secret_access_key(config.secret_access_key)
+            .build()?;
+        Ok(S3 {
+            store: Arc::new(store),
+        })
+    }
+}
+
+#[async_trait]
+impl Backend for S3 {
+    async fn has(&self, key: &str) -> Result<bool, Error> {
+        match self.store.head(&ObjectPath::from(key)).await {
+            Ok(_) => Ok(true),
+            Err(object_store::Error::NotFound { .. }) => Ok(false),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    async fn put(&self, key: &str, path: &Path) -> Result<(), Error> {
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut writer =
+            BufWriter::with_capacity(self.store.clone(), ObjectPath::from(key), PART_SIZE);
+        let result = async {
+            tokio::io::copy(&mut file, &mut writer).await?;
+            writer.shutdown().await
+        }
+        .await;
+        if let Err(error) = result {
+            // don't leave an unfinished multipart upload behind
+            let _ = writer.abort().await;
+            return Err(error.into());
+        }
+        Ok(())
+    }
+
+    async fn open(&self, key: &str) -> Result<ByteStream, Error> {
+        let result = self.store.get(&ObjectPath::from(key)).await?;
+        Ok(result.into_stream().map_err(Error::from).boxed())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        match self.store.delete(&ObjectPath::from(key)).await {
+            Err(error) if !matches!(error, object_store::Error::NotFound { .. }) => {
+                Err(error.into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<Object>, Error> {
+        // `ObjectStore::list` only matches whole path segments, so list the deepest folder that
+        // contains everything with the prefix.
+        let dir = prefix.rfind('/').map(|i| ObjectPath::from(&prefix[..i]));
+        let mut stream = self.store.list(dir.as_ref());
+        let mut objects = Vec::new();
+        while let Some(meta) = stream.try_next().await? {
+            let key = meta.location.to_string();
+            if key.starts_with(prefix) {
+                objects.push(Object {
+                    key,
+                    size: meta.size,
+                    last_modified: meta.last_modified.into(),
+                });
+            }
+        }
+        Ok(objects)
+    }
+
+    async fn usage(&self, prefix: &str) -> Result<u64, Error> {
+        Ok(self
+            .list(prefix)
+            .await?
+            .iter()
+            .map(|object| object.size)
+            .sum())
+    }
+}
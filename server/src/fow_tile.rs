@@ -0,0 +1,456 @@
+// The "Fog of World" sync file format, see `FogMap.ts` in the editor. A sync file is a tile, which
+// is a zlib compressed `TILE_WIDTH x TILE_WIDTH` grid of blocks. The data starts with a header of
+// one little endian u16 per block, `0` means the block is not there, otherwise it is the 1-based
+// index of the block in the list of blocks that follows the header. A block is a
+// `BITMAP_WIDTH x BITMAP_WIDTH` bitmap of visited pixels followed by 3 bytes of extra data.
+
+// The server only reads tiles so far, editing them is for what comes next (e.g. merging
+// snapshots).
+#![allow(dead_code)]
+
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+
+// tiles on each side of the map, the map is a web mercator projection and the id of a tile is
+// `y * MAP_WIDTH + x`.
+const MAP_WIDTH: usize = 512;
+const TILE_WIDTH: usize = 128;
+const BITMAP_WIDTH: usize = 64;
+// pixels on each side of a tile
+const TILE_PIXEL_WIDTH: usize = TILE_WIDTH * BITMAP_WIDTH;
+const TILE_HEADER_LEN: usize = TILE_WIDTH * TILE_WIDTH;
+const TILE_HEADER_SIZE: usize = TILE_HEADER_LEN * 2;
+const BLOCK_BITMAP_SIZE: usize = BITMAP_WIDTH * BITMAP_WIDTH / 8;
+const BLOCK_EXTRA_DATA: usize = 3;
+const BLOCK_SIZE: usize = BLOCK_BITMAP_SIZE + BLOCK_EXTRA_DATA;
+// a tile with every block
+const MAX_TILE_SIZE: usize = TILE_HEADER_SIZE + TILE_HEADER_LEN * BLOCK_SIZE;
+const EARTH_CIRCUMFERENCE_M: f64 = 40_075_016.686;
+// same as the app
+const COMPRESSION_LEVEL: u32 = 5;
+
+/// The row of the map the tile `tile_id` is in, tiles in a row have the same latitude.
+pub fn tile_y(tile_id: u32) -> u32 {
+    tile_id / MAP_WIDTH as u32
+}
+
+/// The file is not a well-formed tile, the message says why.
+#[derive(Debug)]
+pub struct InvalidTile(pub String);
+
+impl std::fmt::Display for InvalidTile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for InvalidTile {}
+
+fn decompress(data: &[u8]) -> Result<Vec<u8>, InvalidTile> {
+    let mut decompressed = Vec::new();
+    // never inflate more than a real tile can be
+    ZlibDecoder::new(data)
+        .take(MAX_TILE_SIZE as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|error| InvalidTile(format!("invalid zlib data: {}", error)))?;
+    if decompressed.len() > MAX_TILE_SIZE {
+        return Err(InvalidTile("tile is too big".into()));
+    }
+    Ok(decompressed)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Block {
+    bitmap: [u8; BLOCK_BITMAP_SIZE],
+    // the first 2 bytes and a half are the region of the block, the rest is a checksum which is
+    // the number of visited pixels.
+    extra_data: [u8; BLOCK_EXTRA_DATA],
+}
+
+impl Block {
+    fn from_bytes(data: &[u8]) -> Block {
+        let mut block = Block {
+            bitmap: [0; BLOCK_BITMAP_SIZE],
+            extra_data: [0; BLOCK_EXTRA_DATA],
+        };
+        block.bitmap.copy_from_slice(&data[..BLOCK_BITMAP_SIZE]);
+        block.extra_data.copy_from_slice(&data[BLOCK_BITMAP_SIZE..]);
+        block
+    }
+
+    /// number of visited pixels in row `y`
+    fn row_count(&self, y: usize) -> u32 {
+        let row_size = BITMAP_WIDTH / 8;
+        self.bitmap[y * row_size..(y + 1) * row_size]
+            .iter()
+            .map(|byte| byte.count_ones())
+            .sum()
+    }
+
+    fn bit(x: usize, y: usize) -> (usize, u8) {
+        assert!(x < BITMAP_WIDTH && y < BITMAP_WIDTH);
+        (x / 8 + y * (BITMAP_WIDTH / 8), 1 << (7 - x % 8))
+    }
+
+    fn is_visited(&self, x: usize, y: usize) -> bool {
+        let (i, mask) = Block::bit(x, y);
+        self.bitmap[i] & mask != 0
+    }
+
+    fn set_visited(&mut self, x: usize, y: usize, visited: bool) {
+        let (i, mask) = Block::bit(x, y);
+        if visited {
+            self.bitmap[i] |= mask;
+        } else {
+            self.bitmap[i] &= !mask;
+        }
+        // keep the region bits and update the checksum, same as the editor.
+        let checksum = (u16::from_be_bytes([self.extra_data[1], self.extra_data[2]]) & 0xc000)
+            | (((self.count() as u16) << 1) + 1);
+        self.extra_data[1..].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    /// number of visited pixels
+    fn count(&self) -> u32 {
+        self.bitmap.iter().map(|byte| byte.count_ones()).sum()
+    }
+
+    /// Visited pixels as `(x, y)`, row by row.
+    fn visited_pixels(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        (0..BITMAP_WIDTH)
+            .flat_map(|y| (0..BITMAP_WIDTH).map(move |x| (x, y)))
+            .filter(|&(x, y)| self.is_visited(x, y))
+    }
+}
+
+/// A decoded sync file. Two tiles are equal if they have the same blocks, no matter which file
+/// they came from.
+#[derive(Clone, Debug, Default)]
+pub struct Tile {
+    // keyed by the position in the header so they are written out in the same order as the app
+    blocks: BTreeMap<usize, Block>,
+    // the file the tile was decoded from, until it is modified.
+    original: Option<Vec<u8>>,
+}
+
+impl PartialEq for Tile {
+    fn eq(&self, other: &Tile) -> bool {
+        self.blocks == other.blocks
+    }
+}
+
+impl Eq for Tile {}
+
+impl Tile {
+    pub fn decode(original: &[u8]) -> Result<Tile, InvalidTile> {
+        let data = decompress(original)?;
+        if data.len() < TILE_HEADER_SIZE {
+            return Err(InvalidTile(format!(
+                "truncated header: {} bytes",
+                data.len()
+            )));
+        }
+        let mut block_indices = BTreeMap::new();
+        let mut seen = vec![false; TILE_HEADER_LEN];
+        for (i, chunk) in data[..TILE_HEADER_SIZE].chunks_exact(2).enumerate() {
+            let block_idx = u16::from_le_bytes([chunk[0], chunk[1]]) as usize;
+            if block_idx == 0 {
+                continue;
+            }
+            if block_idx > TILE_HEADER_LEN || seen[block_idx - 1] {
+                return Err(InvalidTile(format!(
+                    "invalid block index {} of block {}",
+                    block_idx, i
+                )));
+            }
+            seen[block_idx - 1] = true;
+            block_indices.insert(i, block_idx);
+        }
+        // block indices are `1..=block_count`, and the blocks are all that follows the header.
+        let block_count = block_indices.len();
+        let expected_size = TILE_HEADER_SIZE + block_count * BLOCK_SIZE;
+        if seen[..block_count].iter().any(|seen| !seen) || data.len() != expected_size {
+            return Err(InvalidTile(format!(
+                "{} blocks but {} bytes of block data",
+                block_count,
+                data.len() - TILE_HEADER_SIZE
+            )));
+        }
+        let blocks = block_indices
+            .into_iter()
+            .map(|(i, block_idx)| {
+                let start = TILE_HEADER_SIZE + (block_idx - 1) * BLOCK_SIZE;
+                (i, Block::from_bytes(&data[start..start + BLOCK_SIZE]))
+            })
+            .collect();
+        Ok(Tile {
+            blocks,
+            original: Some(original.to_vec()),
+        })
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Blocks as `((x, y), block)`.
+    fn blocks(&self) -> impl Iterator<Item = ((usize, usize), &Block)> + '_ {
+        self.blocks
+            .iter()
+            .map(|(i, block)| ((i % TILE_WIDTH, i / TILE_WIDTH), block))
+    }
+
+    /// The uncompressed content of the sync file. Blocks are numbered in the order of their
+    /// position, same as the app.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = vec![0; TILE_HEADER_SIZE];
+        data.reserve(self.blocks.len() * BLOCK_SIZE);
+        for (block_idx, (i, block)) in self.blocks.iter().enumerate() {
+            data[i * 2..i * 2 + 2].copy_from_slice(&(block_idx as u16 + 1).to_le_bytes());
+            data.extend_from_slice(&block.bitmap);
+            data.extend_from_slice(&block.extra_data);
+        }
+        data
+    }
+
+    /// The sync file of the tile. A tile that is not modified is written out as the exact file
+    /// it was decoded from. Otherwise it is compressed again, which gives the same content but
+    /// not the same bytes as the app, our zlib is not the app's.
+    pub fn encode(&self) -> Vec<u8> {
+        if let Some(original) = &self.original {
+            return original.clone();
+        }
+        let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::new(COMPRESSION_LEVEL));
+        // writing to a `Vec` can't fail
+        encoder.write_all(&self.to_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// number of visited pixels
+    pub fn count(&self) -> u64 {
+        self.blocks.values().map(|block| block.count() as u64).sum()
+    }
+
+    /// Visited pixels as `(x, y)` in the tile, i.e. `0..TILE_PIXEL_WIDTH`.
+    pub fn visited_pixels(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.blocks().flat_map(|((block_x, block_y), block)| {
+            block
+                .visited_pixels()
+                .map(move |(x, y)| (block_x * BITMAP_WIDTH + x, block_y * BITMAP_WIDTH + y))
+        })
+    }
+
+    fn block_of_pixel(x: usize, y: usize) -> (usize, usize, usize) {
+        assert!(x < TILE_PIXEL_WIDTH && y < TILE_PIXEL_WIDTH);
+        (
+            x / BITMAP_WIDTH + y / BITMAP_WIDTH * TILE_WIDTH,
+            x % BITMAP_WIDTH,
+            y % BITMAP_WIDTH,
+        )
+    }
+
+    pub fn is_visited(&self, x: usize, y: usize) -> bool {
+        let (i, x, y) = Tile::block_of_pixel(x, y);
+        self.blocks
+            .get(&i)
+            .map(|block| block.is_visited(x, y))
+            .unwrap_or(false)
+    }
+
+    /// Blocks are created as needed, and removed once they are empty.
+    // TODO: the region of new blocks is left empty, same as the editor. The app seems to be
+    // fine with it.
+    pub fn set_visited(&mut self, x: usize, y: usize, visited: bool) {
+        if self.is_visited(x, y) == visited {
+            return;
+        }
+        self.original = None;
+        let (i, x, y) = Tile::block_of_pixel(x, y);
+        if visited {
+            self.blocks
+                .entry(i)
+                .or_insert_with(|| Block::from_bytes(&[0; BLOCK_SIZE]))
+                .set_visited(x, y, true);
+        } else if let Some(block) = self.blocks.get_mut(&i) {
+            block.set_visited(x, y, false);
+            if block.count() == 0 {
+                self.blocks.remove(&i);
+            }
+        }
+    }
+
+    /// Explored area in m² if this is the tile `tile_id`. Pixels are smaller the further they
+    /// are from the equator.
+    pub fn area_m2(&self, tile_id: u32) -> f64 {
+        let tile_y = tile_y(tile_id) as usize;
+        let map_pixel_width = (MAP_WIDTH * TILE_PIXEL_WIDTH) as f64;
+        let pixel_width_at_equator = EARTH_CIRCUMFERENCE_M / map_pixel_width;
+        self.blocks()
+            .flat_map(|((_, block_y), block)| {
+                (0..BITMAP_WIDTH).map(move |y| {
+                    let count = block.row_count(y);
+                    if count == 0 {
+                        return 0.0;
+                    }
+                    // the middle of the row
+                    let map_y =
+                        (tile_y * TILE_PIXEL_WIDTH + block_y * BITMAP_WIDTH + y) as f64 + 0.5;
+                    let lat = (std::f64::consts::PI * (1.0 - 2.0 * map_y / map_pixel_width))
+                        .sinh()
+                        .atan();
+                    let pixel_width = pixel_width_at_equator * lat.cos();
+                    count as f64 * pixel_width * pixel_width
+                })
+            })
+            .sum()
+    }
+}
+
+/// Check that `data` is a well-formed tile file. Returns the number of blocks.
+pub fn validate(data: &[u8]) -> Result<usize, InvalidTile> {
+    Ok(Tile::decode(data)?.block_count())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn read_test_file(filename: &str) -> Vec<u8> {
+        std::fs::read(format!("../editor/src/__tests__/data/{}", filename)).unwrap()
+    }
+
+    #[test]
+    fn test_validate() {
+        for (filename, block_count) in [("23e4lltkkoke", 54), ("cd36lltksiwo", 393)] {
+            let data = std::fs::read(format!("../editor/src/__tests__/data/{}", filename)).unwrap();
+            assert_eq!(validate(&data).unwrap(), block_count);
+        }
+
+        let mut tile = vec![0; TILE_HEADER_SIZE + BLOCK_SIZE];
+        tile[10] = 1;
+        assert_eq!(validate(&compress(&tile)).unwrap(), 1);
+        assert_eq!(
+            validate(&compress(&tile[..TILE_HEADER_SIZE]))
+                .unwrap_err()
+                .to_string(),
+            "1 blocks but 0 bytes of block data"
+        );
+        assert!(validate(&compress(&tile[..100])).is_err());
+        assert!(validate(&tile).is_err());
+        let compressed = compress(&tile);
+        assert!(validate(&compressed[..compressed.len() - 4]).is_err());
+        // block indices must start at 1 and can't repeat
+        tile[10] = 2;
+        assert!(validate(&compress(&tile)).is_err());
+        tile[10] = 1;
+        tile[12] = 1;
+        assert!(validate(&compress(&tile)).is_err());
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for filename in ["23e4lltkkoke", "cd36lltksiwo"] {
+            let data = read_test_file(filename);
+            let mut tile = Tile::decode(&data).unwrap();
+            assert_eq!(tile.encode(), data);
+            // the blocks are laid out the same as the app does
+            assert_eq!(tile.to_bytes(), decompress(&data).unwrap());
+            let (x, y) = tile.visited_pixels().next().unwrap();
+            // not a change
+            tile.set_visited(x, y, true);
+            assert_eq!(tile.encode(), data);
+
+            let mut changed = tile.clone();
+            changed.set_visited(x, y, false);
+            assert_ne!(changed, tile);
+            changed.set_visited(x, y, true);
+            assert_eq!(changed, tile);
+            assert_eq!(changed.to_bytes(), decompress(&data).unwrap());
+            // compressed again, only the content is the same
+            assert_eq!(
+                decompress(&changed.encode()).unwrap(),
+                decompress(&data).unwrap()
+            );
+            assert_eq!(Tile::decode(&changed.encode()).unwrap(), tile);
+            // checksums written by the app are right
+            for (_, block) in tile.blocks() {
+                let checksum = u16::from_be_bytes([block.extra_data[1], block.extra_data[2]]);
+                assert_eq!(checksum & 0x3fff, ((block.count() as u16) << 1) + 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_visited_pixels() {
+        let tile = Tile::decode(&read_test_file("23e4lltkkoke")).unwrap();
+        let pixels: Vec<_> = tile.visited_pixels().collect();
+        assert_eq!(pixels.len() as u64, tile.count());
+        assert!(pixels.iter().all(|&(x, y)| tile.is_visited(x, y)));
+        let (x, y) = pixels[0];
+        assert_eq!(tile.blocks().next().unwrap().0, (x / 64, y / 64));
+
+        let mut tile = Tile::default();
+        assert!(!tile.is_visited(8191, 65));
+        tile.set_visited(8191, 65, true);
+        tile.set_visited(8190, 65, true);
+        assert!(tile.is_visited(8191, 65));
+        assert_eq!(
+            tile.visited_pixels().collect::<Vec<_>>(),
+            vec![(8190, 65), (8191, 65)]
+        );
+        assert_eq!(
+            tile.blocks().map(|(xy, _)| xy).collect::<Vec<_>>(),
+            vec![(127, 1)]
+        );
+        let block = tile.blocks().next().unwrap().1;
+        // bit `7 - x % 8` of byte `x / 8 + y * 8`
+        assert_eq!(block.bitmap[7 + 8], 0b11);
+
+        let data = tile.encode();
+        assert_eq!(validate(&data).unwrap(), 1);
+        assert_eq!(Tile::decode(&data).unwrap(), tile);
+        // the checksum is kept up to date
+        assert_eq!(
+            Tile::decode(&data)
+                .unwrap()
+                .blocks()
+                .next()
+                .unwrap()
+                .1
+                .extra_data,
+            [0, 0, 5]
+        );
+
+        tile.set_visited(8191, 65, false);
+        assert_eq!(tile.count(), 1);
+        tile.set_visited(8190, 65, false);
+        assert_eq!(tile.block_count(), 0);
+        assert_eq!(tile, Tile::default());
+    }
+
+    #[test]
+    fn test_area() {
+        let pixel_area = |tile_y: usize, y: usize| {
+            let mut tile = Tile::default();
+            tile.set_visited(0, y, true);
+            tile.area_m2((tile_y * MAP_WIDTH) as u32)
+        };
+        // about 9.55m x 9.55m at the equator
+        let at_equator = pixel_area(MAP_WIDTH / 2, 0);
+        assert!((at_equator - 91.3).abs() < 0.1, "{}", at_equator);
+        assert!((pixel_area(MAP_WIDTH / 2 - 1, TILE_PIXEL_WIDTH - 1) - at_equator).abs() < 1e-9);
+        assert!(pixel_area(MAP_WIDTH / 4, 0) < at_equator / 2.0);
+
+        let tile = Tile::decode(&read_test_file("23e4lltkkoke")).unwrap();
+        let area = tile.area_m2(117660);
+        assert!(area > 0.0 && area < tile.count() as f64 * at_equator);
+    }
+}
@@ -1,8 +1,58 @@
+use crate::Config;
+
 // TODO: tweak the value below. I didn't think really hard on the current values.
-// TODO: maybe this should be in config
 
-// 200 MiB
-pub const SYNC_FILE_STORAGE_LIMIT_PER_USER: u64 = 200 * 1024 * 1024;
+pub const SNAPSHOT_TASK_LIMIT_PER_USER: u64 = 5;
+
+const MIB: u64 = 1024 * 1024;
+
+/// Limits that can be changed in `Config`, see there for the defaults.
+#[derive(Clone, Debug)]
+pub struct Limits {
+    /// default sync file storage quota of a user, see `sync_blob::quota` for the per user value.
+    pub sync_file_storage_per_user: u64,
+    pub sync_file_per_snapshot: u64,
+    /// size of a single downloaded sync file.
+    pub sync_file_per_file: u64,
+    /// size of a zip uploaded for creating a snapshot.
+    pub upload: u64,
+    /// in bytes
+    pub snapshot_note: usize,
+}
+
+impl Limits {
+    pub fn from_config(config: &Config) -> Limits {
+        Limits {
+            sync_file_storage_per_user: config.sync_file_storage_limit_per_user_mib * MIB,
+            sync_file_per_snapshot: config.sync_file_limit_per_snapshot_mib * MIB,
+            sync_file_per_file: config.sync_file_limit_per_file_mib * MIB,
+            upload: config.upload_limit_mib * MIB,
+            snapshot_note: config.snapshot_note_limit_bytes,
+        }
+    }
+}
+
+/// A limit is exceeded. Retrying won't help until the user does something about it.
+#[derive(Debug)]
+pub struct LimitExceeded(pub String);
+
+impl std::fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+/// The user is out of sync file storage, unlike `LimitExceeded` the user is told about it, see
+/// `notification`.
+#[derive(Debug)]
+pub struct QuotaExceeded(pub String);
+
+impl std::fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
 
-// 40 MiB
-pub const SYNC_FILE_LIMIT_PER_SNAPSHOT: u64 = 40 * 1024 * 1024;
+impl std::error::Error for QuotaExceeded {}
@@ -2,7 +2,7 @@ use crate::pool::Db;
 use crate::user_handler::User;
 use crate::{memolanes_archive_handler, snapshot_handler, utils};
 use crate::{APIResponse, InternalError, ServerState};
-use rocket::data::{Data, ToByteUnit};
+use rocket::data::Data;
 use rocket::http::ContentType;
 use rocket::http::Status;
 use rocket::request::Request;
@@ -147,7 +147,11 @@ async fn upload<'r>(
 ) -> APIResponse {
     // TODO: we just save the whole thing in memory for 1 mins. This is bad and one can use this to OOM us.
     // we should do something better.
-    let bytes = data.open(4.mebibytes()).into_bytes().await?.into_inner();
+    let bytes = data
+        .open(server_state.limits.upload.into())
+        .into_bytes()
+        .await?
+        .into_inner();
 
     if bytes.is_empty() {
         return Ok((Status::BadRequest, json!({"error":"empty_file"})));
@@ -0,0 +1,262 @@
+use chrono::prelude::*;
+use chrono::Duration;
+use chrono_tz::Tz;
+use entity::snapshot_task::Schedule;
+use std::str::FromStr;
+
+// The same as the smallest allowed `interval`, cron expressions can't be more frequent than this.
+const MIN_CRON_GAP_MINUTES: i64 = 6 * 60;
+
+fn parse_time(time: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(time, "%H:%M").ok()
+}
+
+fn parse_cron(expression: &str) -> Option<cron::Schedule> {
+    // the cron crate wants seconds as the first field
+    if expression.split_whitespace().count() != 5 {
+        return None;
+    }
+    cron::Schedule::from_str(&format!("0 {}", expression)).ok()
+}
+
+pub fn validate(schedule: &Schedule) -> Result<(), &'static str> {
+    match schedule {
+        Schedule::TimeOfDay { time, timezone } => {
+            Tz::from_str(timezone).map_err(|_| "invalid_timezone")?;
+            parse_time(time).ok_or("invalid_time")?;
+        }
+        Schedule::Cron {
+            expression,
+            timezone,
+        } => {
+            let timezone = Tz::from_str(timezone).map_err(|_| "invalid_timezone")?;
+            let cron = parse_cron(expression).ok_or("invalid_cron")?;
+            // good enough to catch things like "*/5 * * * *"
+            let upcoming: Vec<_> = cron.upcoming(timezone).take(64).collect();
+            if upcoming.is_empty() {
+                return Err("invalid_cron");
+            }
+            if upcoming
+                .windows(2)
+                .any(|w| w[1] - w[0] < Duration::minutes(MIN_CRON_GAP_MINUTES))
+            {
+                return Err("cron_too_frequent");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `timezone.from_local_datetime` but moves forward if the local time doesn't exist (DST).
+fn local_to_utc(timezone: Tz, local: NaiveDateTime) -> DateTime<Utc> {
+    let mut local = local;
+    loop {
+        if let Some(time) = timezone.from_local_datetime(&local).earliest() {
+            return time.with_timezone(&Utc);
+        }
+        local += Duration::minutes(30);
+    }
+}
+
+fn next_time_of_day(
+    interval: Duration,
+    time: NaiveTime,
+    timezone: Tz,
+    now: DateTime<Utc>,
+) -> DateTime<Utc> {
+    let today = now.with_timezone(&timezone).date_naive();
+    let mut day = today - Duration::days(1);
+    loop {
+        let slots: Vec<NaiveDateTime> = if interval >= Duration::days(1) {
+            // anchored to 1970-01-01, so e.g. a 2 days interval always picks the same days.
+            let days = (day - NaiveDate::default()).num_days();
+            if days % interval.num_days() == 0 {
+                vec![day.and_time(time)]
+            } else {
+                vec![]
+            }
+        } else {
+            let mut slots = Vec::new();
+            let mut slot = time;
+            for _ in 0..(Duration::days(1).num_minutes() / interval.num_minutes().max(1)) {
+                slots.push(day.and_time(slot));
+                slot += interval;
+            }
+            slots.sort();
+            slots
+        };
+        for slot in slots {
+            let slot = local_to_utc(timezone, slot);
+            if slot > now {
+                return slot;
+            }
+        }
+        day += Duration::days(1);
+    }
+}
+
+/// When to sync next. `last_scheduled` is the `next_sync` of the sync that just happened, plain
+/// intervals are anchored to it, so the time spent on syncing doesn't shift the schedule.
+pub fn next_sync(
+    interval: i16,
+    schedule: Option<&Schedule>,
+    last_scheduled: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> DateTime<Utc> {
+    let interval = Duration::minutes(interval.max(1).into());
+    let fallback = || {
+        let missed = (now - last_scheduled).num_minutes().max(0) / interval.num_minutes();
+        last_scheduled + interval * (missed as i32 + 1)
+    };
+    match schedule {
+        None => fallback(),
+        // schedules are validated when they are set, but falling back is better than getting stuck.
+        Some(Schedule::TimeOfDay { time, timezone }) => {
+            match (parse_time(time), Tz::from_str(timezone)) {
+                (Some(time), Ok(timezone)) => next_time_of_day(interval, time, timezone, now),
+                _ => fallback(),
+            }
+        }
+        Some(Schedule::Cron {
+            expression,
+            timezone,
+        }) => match (parse_cron(expression), Tz::from_str(timezone)) {
+            (Some(cron), Ok(timezone)) => cron
+                .after(&now.with_timezone(&timezone))
+                .next()
+                .map(|time| time.with_timezone(&Utc))
+                .unwrap_or_else(fallback),
+            _ => fallback(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_validate() {
+        let time_of_day = |time: &str, timezone: &str| Schedule::TimeOfDay {
+            time: time.into(),
+            timezone: timezone.into(),
+        };
+        let cron = |expression: &str| Schedule::Cron {
+            expression: expression.into(),
+            timezone: "UTC".into(),
+        };
+        assert_eq!(validate(&time_of_day("08:30", "Asia/Shanghai")), Ok(()));
+        assert_eq!(
+            validate(&time_of_day("8pm", "Asia/Shanghai")),
+            Err("invalid_time")
+        );
+        assert_eq!(
+            validate(&time_of_day("08:30", "Mars/Olympus")),
+            Err("invalid_timezone")
+        );
+        assert_eq!(validate(&cron("30 8 * * Mon-Fri")), Ok(()));
+        assert_eq!(validate(&cron("0 8,20 * * *")), Ok(()));
+        assert_eq!(validate(&cron("*/5 * * * *")), Err("cron_too_frequent"));
+        assert_eq!(validate(&cron("0 8 * *")), Err("invalid_cron"));
+        assert_eq!(validate(&cron("0 25 * * *")), Err("invalid_cron"));
+    }
+
+    #[test]
+    fn test_next_sync_interval() {
+        let last_scheduled = utc("2024-01-01T08:00:00Z");
+        // the time spent on syncing doesn't matter
+        assert_eq!(
+            next_sync(12 * 60, None, last_scheduled, utc("2024-01-01T08:05:00Z")),
+            utc("2024-01-01T20:00:00Z")
+        );
+        // missed runs are skipped
+        assert_eq!(
+            next_sync(12 * 60, None, last_scheduled, utc("2024-01-03T09:00:00Z")),
+            utc("2024-01-03T20:00:00Z")
+        );
+    }
+
+    #[test]
+    fn test_next_sync_time_of_day() {
+        let schedule = Schedule::TimeOfDay {
+            time: "08:00".into(),
+            timezone: "Asia/Shanghai".into(),
+        };
+        let last_scheduled = utc("2024-01-01T00:00:00Z");
+        // 08:00 and 20:00 (or 02:00, 08:00, 14:00 and 20:00) in UTC+8
+        assert_eq!(
+            next_sync(
+                12 * 60,
+                Some(&schedule),
+                last_scheduled,
+                utc("2024-01-01T00:10:00Z")
+            ),
+            utc("2024-01-01T12:00:00Z")
+        );
+        assert_eq!(
+            next_sync(
+                24 * 60,
+                Some(&schedule),
+                last_scheduled,
+                utc("2024-01-01T00:10:00Z")
+            ),
+            utc("2024-01-02T00:00:00Z")
+        );
+        assert_eq!(
+            next_sync(
+                6 * 60,
+                Some(&schedule),
+                last_scheduled,
+                utc("2024-01-01T13:00:00Z")
+            ),
+            utc("2024-01-01T18:00:00Z")
+        );
+        // always the same days for intervals longer than a day
+        let a = next_sync(
+            2 * 24 * 60,
+            Some(&schedule),
+            last_scheduled,
+            utc("2024-01-01T00:10:00Z"),
+        );
+        let b = next_sync(2 * 24 * 60, Some(&schedule), last_scheduled, a);
+        assert_eq!(b - a, Duration::days(2));
+        assert_eq!(a.with_timezone(&chrono_tz::Asia::Shanghai).hour(), 8);
+
+        // 02:30 doesn't exist on the day DST starts
+        let schedule = Schedule::TimeOfDay {
+            time: "02:30".into(),
+            timezone: "Europe/Berlin".into(),
+        };
+        assert_eq!(
+            next_sync(
+                24 * 60,
+                Some(&schedule),
+                last_scheduled,
+                utc("2024-03-30T12:00:00Z")
+            ),
+            utc("2024-03-31T01:00:00Z")
+        );
+    }
+
+    #[test]
+    fn test_next_sync_cron() {
+        let schedule = Schedule::Cron {
+            expression: "30 8 * * Mon".into(),
+            timezone: "Europe/London".into(),
+        };
+        // 2024-01-01 is a Monday
+        assert_eq!(
+            next_sync(
+                6 * 60,
+                Some(&schedule),
+                utc("2024-01-01T08:30:00Z"),
+                utc("2024-01-01T08:31:00Z")
+            ),
+            utc("2024-01-08T08:30:00Z")
+        );
+    }
+}
@@ -1,12 +1,17 @@
 use crate::data_fetcher::{self, SyncFile};
+use crate::limit;
 use crate::misc_handler::{self, DownloadRequest, GeneratedDownloadItem};
 use crate::pool::Db;
+use crate::snapshot_stats::{self, BlobStats};
+use crate::sync_blob;
+use crate::sync_file_gc;
 use crate::user_handler::User;
 use crate::{APIResponse, ServerState};
 use anyhow::Result;
 use chrono::prelude::*;
 use entity::sea_orm;
 use entity::snapshot;
+use futures::TryStreamExt;
 use rocket::http::{ContentType, Status};
 use rocket::serde::json::Json;
 use sea_orm::{entity::*, query::*};
@@ -15,7 +20,7 @@ use serde::{Deserialize, Serialize};
 use serde_json::json;
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
-use std::io::{Seek, Write};
+use std::io::{Read, Seek, Write};
 use std::path::Path;
 use std::{fs, io};
 use tempfile::NamedTempFile;
@@ -25,7 +30,13 @@ struct SnapshotJson {
     pub id: i64,
     pub timestamp: DateTime<Utc>,
     pub source_kind: snapshot::SourceKind,
+    pub snapshot_task_id: Option<i64>,
     pub note: Option<String>,
+    // `None` until the stats are backfilled
+    pub explored_area_km2: Option<f64>,
+    pub tile_count: Option<i32>,
+    pub block_count: Option<i32>,
+    pub level: Option<i32>,
 }
 
 #[get("/?<page>&<page_size>")]
@@ -58,14 +69,24 @@ async fn list_snapshots(
                 user_id: _,
                 timestamp,
                 source_kind,
+                snapshot_task_id,
                 note,
                 sync_files: _,
+                explored_area_m2,
+                tile_count,
+                block_count,
+                level,
             } = snapshot;
             SnapshotJson {
                 id,
                 timestamp,
                 source_kind,
+                snapshot_task_id,
                 note,
+                explored_area_km2: explored_area_m2.map(|m2| m2 as f64 / 1_000_000.0),
+                tile_count,
+                block_count,
+                level,
             }
         })
         .collect();
@@ -99,7 +120,7 @@ async fn create(
     data: Json<CreateData>,
 ) -> APIResponse {
     let note_len = data.note.as_ref().map_or(0, |s| s.len());
-    if note_len > 256 {
+    if note_len > server_state.limits.snapshot_note {
         return Ok((Status::BadRequest, json!({"error":"note_too_long"})));
     }
     let cutoff = Utc::now() + chrono::Duration::seconds(10);
@@ -122,8 +143,7 @@ async fn create(
             // let's put file in a temp dir first, we only save the file when we belive everything is good.
             let tmp_dir = server_state.file_storage.get_tmp_dir()?;
             let mut logs = Vec::new();
-            let mut sync_files: HashMap<u32, String> = HashMap::new();
-            let mut files_to_add = Vec::new();
+            let mut candidates = Vec::new();
             for i in 0..zip.len() {
                 let mut file = zip.by_index(i)?;
                 let filename = file.name().to_lowercase();
@@ -134,7 +154,8 @@ async fn create(
                 }
                 let filename = Path::file_name(Path::new(&filename))
                     .and_then(|x| x.to_str())
-                    .unwrap_or("");
+                    .unwrap_or("")
+                    .to_string();
                 if filename.is_empty() {
                     continue;
                 }
@@ -143,23 +164,58 @@ async fn create(
                 let mut hasher = Sha256::new();
                 // TODO: async?
                 io::copy(&mut file, &mut hasher)?;
-                drop(file);
                 let sha256_lowercase = format!("{:x}", hasher.finalize());
-                match SyncFile::create_from_filename(filename, &sha256_lowercase) {
+                match SyncFile::create_from_filename(&filename, &sha256_lowercase) {
                     Err(_) => {
                         logs.push(format!("unexpected file: {}", filename));
                     }
-                    Ok(sync_file) => {
-                        if !server_state.file_storage.has_file(&user, &sha256_lowercase) {
-                            let tmp_file_path = tmp_dir.path().join(sync_file.id.to_string());
-                            let mut tmp_file = fs::File::create(&tmp_file_path)?;
-                            let mut file = zip.by_index(i)?;
-                            io::copy(&mut file, &mut tmp_file)?;
-                            files_to_add.push((sha256_lowercase, tmp_file_path));
+                    Ok(sync_file) => candidates.push((i, filename, sync_file)),
+                }
+            }
+
+            // checked again under `sync_file_gc::lock_shared` once the files are saved
+            let db = conn.into_inner();
+            let recorded = sync_blob::find_recorded(
+                db,
+                &user,
+                candidates.iter().map(|(_, _, sync_file)| &sync_file.sha256),
+            )
+            .await?;
+            let computed = snapshot_stats::find_computed(
+                db,
+                &user,
+                &candidates
+                    .iter()
+                    .map(|(_, _, sync_file)| (sync_file.id, &sync_file.sha256))
+                    .collect::<Vec<_>>(),
+            )
+            .await?;
+
+            let mut sync_files: HashMap<u32, String> = HashMap::new();
+            let mut files_to_add = Vec::new();
+            let mut new_size: u64 = 0;
+            // the stats are computed from the data we have anyway, only for files without them
+            let mut blob_stats = HashMap::new();
+            for (i, filename, sync_file) in candidates {
+                let is_new = !recorded.contains(&sync_file.sha256);
+                let key = snapshot_stats::blob_stats_key(sync_file.id, &sync_file.sha256);
+                if is_new || !computed.contains(&key) {
+                    let mut data = Vec::new();
+                    zip.by_index(i)?.read_to_end(&mut data)?;
+                    let stats = BlobStats::of_file(&data, sync_file.id);
+                    if is_new {
+                        if let Err(error) = &stats {
+                            logs.push(format!("invalid file: {}, {}", filename, error));
+                            continue;
                         }
-                        sync_files.insert(sync_file.id, sync_file.sha256);
+                        let tmp_file_path = tmp_dir.path().join(sync_file.id.to_string());
+                        new_size += data.len() as u64;
+                        fs::write(&tmp_file_path, data)?;
+                        files_to_add.push((sync_file.sha256.clone(), tmp_file_path));
                     }
+                    blob_stats.insert(key, stats.unwrap_or_default());
                 }
+                sync_files.insert(sync_file.id, sync_file.sha256);
             }
 
             let file_count = sync_files.len();
@@ -167,23 +223,59 @@ async fn create(
                 return Ok((Status::BadRequest, json!({"error": "snapshot_is_empty"})));
             }
             logs.push(format!("new files: {}/{}", files_to_add.len(), file_count));
-            // save files
-            server_state
+            if new_size > sync_blob::quota_left(db, &server_state.limits, &user).await? {
+                return Ok((
+                    Status::BadRequest,
+                    json!({"error": "storage_quota_exceeded"}),
+                ));
+            }
+            // save files before the transaction, it only records them. Same as a sync, the quota
+            // is checked again then and new files that don't fit after all are left for GC.
+            let new_files = server_state
                 .file_storage
-                .add_files(&user, &files_to_add[..])?;
+                .add_files(&user, &files_to_add[..])
+                .await?;
+            let sync_files = entity::snapshot::SyncFiles(sync_files);
+
+            let txn = db.begin().await?;
+            sync_file_gc::lock_shared(&txn, &user).await?;
+            if let Err(error) =
+                sync_blob::add_files(&txn, &user, &new_files, &server_state.limits).await
+            {
+                if error.is::<limit::QuotaExceeded>() {
+                    return Ok((
+                        Status::BadRequest,
+                        json!({"error": "storage_quota_exceeded"}),
+                    ));
+                }
+                return Err(error.into());
+            }
+            // we skipped files we already had, but GC might have removed them since then.
+            if let Some(sha256) =
+                sync_blob::find_missing(&txn, &user, sync_files.0.values()).await?
+            {
+                return Err(anyhow!("sync file removed while uploading: {}", sha256).into());
+            }
+            sync_blob::add_references(&txn, &user, &sync_files).await?;
+            snapshot_stats::set_blob_stats(&txn, &user, &blob_stats).await?;
+            let stats = snapshot_stats::sum(&txn, &user, &sync_files).await?;
 
-            let db = conn.into_inner();
             // save snapshot
             let snapshot = snapshot::ActiveModel {
                 id: NotSet,
                 user_id: Set(user.uid),
                 timestamp: Set(data.timestamp),
-                sync_files: Set(entity::snapshot::SyncFiles(sync_files)),
+                sync_files: Set(sync_files),
                 source_kind: Set(snapshot::SourceKind::Upload),
+                snapshot_task_id: Set(None),
                 note: Set(data.note.to_owned()),
+                ..stats
+                    .map(|stats| stats.to_active_model())
+                    .unwrap_or_default()
             }
-            .insert(db)
+            .insert(&txn)
             .await?;
+            txn.commit().await?;
 
             Ok((
                 Status::Ok,
@@ -201,13 +293,14 @@ struct EditData {
 #[post("/<snapshot_id>", data = "<data>")]
 async fn update(
     conn: Connection<'_, Db>,
+    server_state: &rocket::State<ServerState>,
     user: User,
     snapshot_id: i64,
     data: Json<EditData>,
 ) -> APIResponse {
     let txn = conn.into_inner().begin().await?;
     let note_len = data.note.as_ref().map_or(0, |s| s.len());
-    if note_len > 256 {
+    if note_len > server_state.limits.snapshot_note {
         return Ok((Status::BadRequest, json!({"error":"note_too_long"})));
     }
     match snapshot::Entity::find()
@@ -229,7 +322,12 @@ async fn update(
 }
 
 #[delete("/<snapshot_id>")]
-async fn delete(conn: Connection<'_, Db>, user: User, snapshot_id: i64) -> APIResponse {
+async fn delete(
+    server_state: &rocket::State<ServerState>,
+    conn: Connection<'_, Db>,
+    user: User,
+    snapshot_id: i64,
+) -> APIResponse {
     let db = conn.into_inner();
     let txn = db.begin().await?;
 
@@ -241,8 +339,17 @@ async fn delete(conn: Connection<'_, Db>, user: User, snapshot_id: i64) -> APIRe
         .await?
     {
         Some(snapshot) => {
+            // keeps `sync_file_gc::backfill` from counting references while we change them.
+            sync_file_gc::lock_shared(&txn, &user).await?;
+            sync_blob::remove_references(&txn, &user, &snapshot.sync_files).await?;
             snapshot.delete(&txn).await?;
             txn.commit().await?;
+            // remove the files only used by this snapshot without making the user wait for it.
+            let db = db.clone();
+            let sync_file_storage = server_state.file_storage.clone();
+            tokio::spawn(async move {
+                sync_file_gc::collect_and_log(&db, &sync_file_storage, &user).await;
+            });
             Ok((Status::Ok, json!({})))
         }
         None => Ok((Status::NotFound, json!({}))),
@@ -263,9 +370,10 @@ pub async fn internal_generate_sync_zip_from_sync_files<W: Write + Seek>(
     for (file_id, sha256) in &sync_files.0 {
         let sync_file = data_fetcher::SyncFile::create_from_id(*file_id, sha256)?;
         zip.start_file(format!("Sync/{}", sync_file.filename()), options)?;
-        let mut file = server_state.file_storage.open_file(user, sha256)?;
-        // TODO: async?
-        std::io::copy(&mut file, &mut zip)?;
+        let mut data = server_state.file_storage.open_file(user, sha256).await?;
+        while let Some(chunk) = data.try_next().await? {
+            zip.write_all(&chunk)?;
+        }
     }
     zip.finish()?;
     Ok(())
@@ -8,19 +8,108 @@ use sea_orm::{entity::*, query::*};
 use sea_orm_rocket::Connection;
 use serde_json::json;
 
-#[get("/")]
-async fn get(conn: Connection<'_, Db>, user: User) -> APIResponse {
+// Newest first. `cursor` is the `next_cursor` of the previous page, it is `null` on the last page.
+#[get("/?<snapshot_task_id>&<succeed>&<cursor>&<page_size>")]
+async fn get(
+    conn: Connection<'_, Db>,
+    user: User,
+    snapshot_task_id: Option<i64>,
+    succeed: Option<bool>,
+    cursor: Option<i64>,
+    page_size: Option<u64>,
+) -> APIResponse {
     let db = conn.into_inner();
+    let page_size = page_size.unwrap_or(20);
+    if page_size == 0 || page_size > 200 {
+        return Ok((Status::BadRequest, json!({"error": "invalid_page_size"})));
+    }
 
-    let snapshot_logs = snapshot_log::Entity::find()
+    let mut query = snapshot_log::Entity::find()
         .filter(snapshot_log::Column::UserId.eq(user.uid))
-        .order_by_desc(snapshot_log::Column::Timestamp)
-        .all(db)
-        .await?;
+        .order_by_desc(snapshot_log::Column::Id);
+    if let Some(snapshot_task_id) = snapshot_task_id {
+        query = query.filter(snapshot_log::Column::SnapshotTaskId.eq(snapshot_task_id));
+    }
+    if let Some(succeed) = succeed {
+        query = query.filter(snapshot_log::Column::Succeed.eq(succeed));
+    }
+    if let Some(cursor) = cursor {
+        query = query.filter(snapshot_log::Column::Id.lt(cursor));
+    }
+    // one more to see if there is a next page
+    let mut snapshot_logs = query.limit(page_size + 1).all(db).await?;
+    let next_cursor = if snapshot_logs.len() as u64 > page_size {
+        snapshot_logs.truncate(page_size as usize);
+        snapshot_logs.last().map(|log| log.id)
+    } else {
+        None
+    };
 
-    Ok((Status::Ok, json!({ "snapshot_logs": snapshot_logs })))
+    Ok((
+        Status::Ok,
+        json!({ "snapshot_logs": snapshot_logs, "next_cursor": next_cursor }),
+    ))
 }
 
 pub fn routes() -> Vec<rocket::Route> {
     routes![get]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{needs_database, TestServer};
+    use chrono::Utc;
+
+    async fn get_logs(server: &TestServer, query: &str) -> serde_json::Value {
+        let resp = server
+            .client
+            .get(format!("/api/v1/snapshot_log?{}", query))
+            .header(server.auth(1))
+            .dispatch()
+            .await;
+        assert_eq!(resp.status(), Status::Ok);
+        resp.into_json().await.unwrap()
+    }
+
+    fn ids(logs: &serde_json::Value) -> Vec<i64> {
+        logs["snapshot_logs"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|log| log["id"].as_i64().unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    #[ignore = needs_database!()]
+    async fn test_get() {
+        let server = TestServer::new().await;
+        for (id, user_id, succeed) in [(1, 1, true), (2, 1, false), (3, 2, true), (4, 1, true)] {
+            snapshot_log::ActiveModel {
+                id: Set(id),
+                user_id: Set(user_id),
+                snapshot_task_id: Set(Some(1)),
+                snapshot_id: Set(None),
+                timestamp: Set(Utc::now()),
+                succeed: Set(succeed),
+                details: Set("old text log".into()),
+                report: Set(None),
+            }
+            .insert(&server.conn)
+            .await
+            .unwrap();
+        }
+
+        let page = get_logs(&server, "page_size=2").await;
+        assert_eq!(ids(&page), vec![4, 2]);
+        assert_eq!(page["snapshot_logs"][0]["details"], "old text log");
+        assert_eq!(page["next_cursor"], 2);
+        let page = get_logs(&server, "page_size=2&cursor=2").await;
+        assert_eq!(ids(&page), vec![1]);
+        assert!(page["next_cursor"].is_null());
+
+        assert_eq!(ids(&get_logs(&server, "succeed=false").await), vec![2]);
+        assert_eq!(ids(&get_logs(&server, "succeed=true").await), vec![4, 1]);
+    }
+}
@@ -0,0 +1,394 @@
+use crate::file_storage::SyncFileStorage;
+use crate::fow_tile::{self, InvalidTile, Tile};
+use crate::user_handler::User;
+use anyhow::Result;
+use entity::sea_orm::sea_query::{OnConflict, Query};
+use entity::sea_orm::{entity::*, query::*};
+use entity::sea_orm::{ConnectionTrait, DatabaseConnection};
+use entity::snapshot::{self, SyncFiles};
+use entity::{sync_blob, sync_blob_stat};
+use std::collections::{HashMap, HashSet};
+
+const BACKFILL_BATCH_SIZE: u64 = 100;
+
+/// Stats of a single sync file, stored in `sync_blob_stat` so each file is only read once.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BlobStats {
+    pub area_m2: f64,
+    pub block_count: i32,
+}
+
+impl BlobStats {
+    /// `tile_id` is where the file is used, the area depends on the latitude.
+    pub fn of_file(data: &[u8], tile_id: u32) -> Result<BlobStats, InvalidTile> {
+        let tile = Tile::decode(data)?;
+        Ok(BlobStats {
+            area_m2: tile.area_m2(tile_id),
+            block_count: tile.block_count() as i32,
+        })
+    }
+}
+
+/// `(sha-256, tile_y)`, what the stats of a sync file used as the tile `tile_id` depend on.
+/// Tiles in the same row of the map have the same latitude.
+pub fn blob_stats_key(tile_id: u32, sha256: &str) -> (String, i32) {
+    (sha256.to_string(), fow_tile::tile_y(tile_id) as i32)
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Stats {
+    pub explored_area_m2: i64,
+    pub tile_count: i32,
+    pub block_count: i32,
+    pub level: i32,
+}
+
+impl Stats {
+    /// Only the stats columns are set.
+    pub fn to_active_model(&self) -> snapshot::ActiveModel {
+        snapshot::ActiveModel {
+            explored_area_m2: Set(Some(self.explored_area_m2)),
+            tile_count: Set(Some(self.tile_count)),
+            block_count: Set(Some(self.block_count)),
+            level: Set(Some(self.level)),
+            ..Default::default()
+        }
+    }
+}
+
+// Fog of World doesn't tell how it computes levels, this is our own version: level 2 needs
+// 1 km², and every level after that needs twice the area.
+pub fn level(explored_area_m2: i64) -> i32 {
+    let km2 = explored_area_m2 as f64 / 1_000_000.0;
+    if km2 < 1.0 {
+        1
+    } else {
+        2 + km2.log2().floor() as i32
+    }
+}
+
+/// Stats we have of the files with `sha256s`, by `blob_stats_key`. There can be more than asked
+/// for, the same file might be used in other rows.
+async fn find<C: ConnectionTrait>(
+    db: &C,
+    user: &User,
+    sha256s: HashSet<&String>,
+) -> Result<HashMap<(String, i32), BlobStats>> {
+    if sha256s.is_empty() {
+        return Ok(HashMap::new());
+    }
+    Ok(sync_blob_stat::Entity::find()
+        .filter(sync_blob_stat::Column::UserId.eq(user.uid))
+        .filter(sync_blob_stat::Column::Sha256.is_in(sha256s))
+        .all(db)
+        .await?
+        .into_iter()
+        .map(|stats| {
+            (
+                (stats.sha256, stats.tile_y),
+                BlobStats {
+                    area_m2: stats.area_m2,
+                    block_count: stats.block_count,
+                },
+            )
+        })
+        .collect())
+}
+
+/// Those of `sync_files` (`(tile id, sha-256)`) that have stats, by `blob_stats_key`.
+pub async fn find_computed<C: ConnectionTrait>(
+    db: &C,
+    user: &User,
+    sync_files: &[(u32, &String)],
+) -> Result<HashSet<(String, i32)>> {
+    let stats = find(
+        db,
+        user,
+        sync_files.iter().map(|(_, sha256)| *sha256).collect(),
+    )
+    .await?;
+    Ok(sync_files
+        .iter()
+        .map(|(tile_id, sha256)| blob_stats_key(*tile_id, sha256))
+        .filter(|key| stats.contains_key(key))
+        .collect())
+}
+
+/// Stats of the sync files that don't have them yet, by `blob_stats_key`. This reads the files,
+/// so it should be done before taking any locks. The files must be in the storage, files that
+/// are not valid tiles count as empty.
+pub async fn compute_missing<C: ConnectionTrait>(
+    db: &C,
+    sync_file_storage: &SyncFileStorage,
+    user: &User,
+    sync_files: &SyncFiles,
+) -> Result<HashMap<(String, i32), BlobStats>> {
+    let computed = find_computed(
+        db,
+        user,
+        &sync_files
+            .0
+            .iter()
+            .map(|(id, sha256)| (*id, sha256))
+            .collect::<Vec<_>>(),
+    )
+    .await?;
+    let mut blob_stats = HashMap::new();
+    for (id, sha256) in &sync_files.0 {
+        let key = blob_stats_key(*id, sha256);
+        if computed.contains(&key) || blob_stats.contains_key(&key) {
+            continue;
+        }
+        let data = sync_file_storage.read_file(user, sha256).await?;
+        let stats = BlobStats::of_file(&data, *id).unwrap_or_else(|error| {
+            warn!(
+                "[snapshot_stats] invalid sync file {} of user {}: {}",
+                sha256, user.uid, error
+            );
+            BlobStats::default()
+        });
+        blob_stats.insert(key, stats);
+    }
+    Ok(blob_stats)
+}
+
+/// Store `blob_stats` (by `blob_stats_key`). Stats that are already there are kept.
+pub async fn set_blob_stats<C: ConnectionTrait>(
+    db: &C,
+    user: &User,
+    blob_stats: &HashMap<(String, i32), BlobStats>,
+) -> Result<()> {
+    if blob_stats.is_empty() {
+        return Ok(());
+    }
+    sync_blob_stat::Entity::insert_many(blob_stats.iter().map(|((sha256, tile_y), stats)| {
+        sync_blob_stat::ActiveModel {
+            user_id: Set(user.uid),
+            sha256: Set(sha256.clone()),
+            tile_y: Set(*tile_y),
+            area_m2: Set(stats.area_m2),
+            block_count: Set(stats.block_count),
+        }
+    }))
+    .on_conflict(
+        OnConflict::columns([
+            sync_blob_stat::Column::UserId,
+            sync_blob_stat::Column::Sha256,
+            sync_blob_stat::Column::TileY,
+        ])
+        .do_nothing()
+        .to_owned(),
+    )
+    .exec_without_returning(db)
+    .await?;
+    Ok(())
+}
+
+/// Remove the stats of those of `sha256s` that are no longer recorded in `sync_blob`.
+pub async fn remove_unrecorded<C: ConnectionTrait>(
+    db: &C,
+    user: &User,
+    sha256s: &[String],
+) -> Result<()> {
+    if sha256s.is_empty() {
+        return Ok(());
+    }
+    sync_blob_stat::Entity::delete_many()
+        .filter(sync_blob_stat::Column::UserId.eq(user.uid))
+        .filter(sync_blob_stat::Column::Sha256.is_in(sha256s))
+        .filter(
+            sync_blob_stat::Column::Sha256.not_in_subquery(
+                Query::select()
+                    .column(sync_blob::Column::Sha256)
+                    .from(sync_blob::Entity)
+                    .and_where(sync_blob::Column::UserId.eq(user.uid))
+                    .to_owned(),
+            ),
+        )
+        .exec(db)
+        .await?;
+    Ok(())
+}
+
+/// Sum up the stored stats of the sync files, `None` if some files don't have them.
+pub async fn sum<C: ConnectionTrait>(
+    db: &C,
+    user: &User,
+    sync_files: &SyncFiles,
+) -> Result<Option<Stats>> {
+    let blob_stats = find(db, user, sync_files.0.values().collect()).await?;
+    let mut area_m2 = 0.0;
+    let mut stats = Stats::default();
+    for (id, sha256) in &sync_files.0 {
+        let blob_stats = match blob_stats.get(&blob_stats_key(*id, sha256)) {
+            None => return Ok(None),
+            Some(blob_stats) => blob_stats,
+        };
+        area_m2 += blob_stats.area_m2;
+        if blob_stats.block_count > 0 {
+            stats.tile_count += 1;
+        }
+        stats.block_count += blob_stats.block_count;
+    }
+    stats.explored_area_m2 = area_m2.round() as i64;
+    stats.level = level(stats.explored_area_m2);
+    Ok(Some(stats))
+}
+
+/// Fill in the stats of snapshots created before we had them. Errors are logged, those
+/// snapshots are tried again next time.
+pub async fn backfill(conn: &DatabaseConnection, sync_file_storage: &SyncFileStorage) {
+    let mut last_id = 0;
+    let mut updated = 0;
+    loop {
+        let snapshots: Vec<(i64, i64, SyncFiles)> = match snapshot::Entity::find()
+            .select_only()
+            .columns([
+                snapshot::Column::Id,
+                snapshot::Column::UserId,
+                snapshot::Column::SyncFiles,
+            ])
+            .filter(snapshot::Column::ExploredAreaM2.is_null())
+            .filter(snapshot::Column::Id.gt(last_id))
+            .order_by_asc(snapshot::Column::Id)
+            .limit(BACKFILL_BATCH_SIZE)
+            .into_tuple()
+            .all(conn)
+            .await
+        {
+            Ok(snapshots) => snapshots,
+            Err(error) => {
+                error!("[snapshot_stats] failed to list snapshots: {}", error);
+                break;
+            }
+        };
+        if snapshots.is_empty() {
+            break;
+        }
+        for (id, user_id, sync_files) in snapshots {
+            last_id = id;
+            let user = User { uid: user_id };
+            let result = async {
+                let blob_stats =
+                    compute_missing(conn, sync_file_storage, &user, &sync_files).await?;
+                set_blob_stats(conn, &user, &blob_stats).await?;
+                let stats = sum(conn, &user, &sync_files)
+                    .await?
+                    .ok_or_else(|| anyhow!("sync files without a record"))?;
+                snapshot::Entity::update(snapshot::ActiveModel {
+                    id: Set(id),
+                    ..stats.to_active_model()
+                })
+                .exec(conn)
+                .await?;
+                Ok::<_, anyhow::Error>(())
+            }
+            .await;
+            match result {
+                Ok(()) => updated += 1,
+                Err(error) => error!("[snapshot_stats] failed for snapshot {}: {}", id, error),
+            }
+        }
+    }
+    if updated > 0 {
+        info!("[snapshot_stats] backfilled {} snapshots", updated);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::needs_database;
+
+    #[test]
+    fn test_level() {
+        assert_eq!(level(0), 1);
+        assert_eq!(level(999_999), 1);
+        assert_eq!(level(1_000_000), 2);
+        assert_eq!(level(3_999_999), 3);
+        assert_eq!(level(4_000_000), 4);
+        assert_eq!(level(1_000_000_000), 11);
+    }
+
+    #[tokio::test]
+    #[ignore = needs_database!()]
+    async fn test_backfill() {
+        let server = crate::test_utils::TestServer::new().await;
+        let storage = &server.sync_file_storage;
+        let user = User { uid: 1 };
+        let tmp_dir = storage.get_tmp_dir().unwrap();
+        let mut sync_files = HashMap::new();
+        let mut new_files = Vec::new();
+        for filename in ["23e4lltkkoke", "cd36lltksiwo"] {
+            let path = tmp_dir.path().join(filename);
+            std::fs::copy(format!("../editor/src/__tests__/data/{}", filename), &path).unwrap();
+            let sha256 = crate::file_storage::sha256_of_file(&path).unwrap();
+            storage
+                .add_files(&user, &[(sha256.clone(), path)])
+                .await
+                .unwrap();
+            new_files.push((sha256.clone(), 1));
+            let id = crate::data_fetcher::SyncFile::create_from_filename(filename, "")
+                .unwrap()
+                .id;
+            sync_files.insert(id, sha256);
+        }
+        let sync_files = SyncFiles(sync_files);
+        let txn = server.conn.begin().await.unwrap();
+        crate::sync_blob::add_files(&txn, &user, &new_files, &crate::test_utils::limits())
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+        // no stats until they are computed
+        assert_eq!(sum(&server.conn, &user, &sync_files).await.unwrap(), None);
+
+        let snapshot = crate::test_utils::snapshot(&user, sync_files.clone())
+            .insert(&server.conn)
+            .await
+            .unwrap();
+        assert_eq!(snapshot.explored_area_m2, None);
+
+        backfill(&server.conn, storage).await;
+        let snapshot = snapshot::Entity::find_by_id(snapshot.id)
+            .one(&server.conn)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(snapshot.tile_count, Some(2));
+        assert_eq!(snapshot.block_count, Some(54 + 393));
+        assert!(snapshot.explored_area_m2.unwrap() > 0);
+        let stats = sum(&server.conn, &user, &sync_files)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(Some(stats.explored_area_m2), snapshot.explored_area_m2);
+        assert_eq!(snapshot.level, Some(level(stats.explored_area_m2)));
+        // the files are not read again
+        assert!(compute_missing(&server.conn, storage, &user, &sync_files)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // the same file further north covers less area
+        let (id, sha256) = sync_files.0.iter().next().unwrap();
+        let north = SyncFiles(HashMap::from([(id - 512, sha256.clone())]));
+        let blob_stats = compute_missing(&server.conn, storage, &user, &north)
+            .await
+            .unwrap();
+        assert_eq!(blob_stats.len(), 1);
+        set_blob_stats(&server.conn, &user, &blob_stats)
+            .await
+            .unwrap();
+        let at_north = sum(&server.conn, &user, &north).await.unwrap().unwrap();
+        let here = sum(
+            &server.conn,
+            &user,
+            &SyncFiles(HashMap::from([(*id, sha256.clone())])),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(at_north.block_count, here.block_count);
+        assert!(at_north.explored_area_m2 != here.explored_area_m2);
+    }
+}
@@ -1,12 +1,14 @@
 use crate::data_fetcher;
+use crate::limit;
 use crate::pool::Db;
+use crate::schedule;
 use crate::user_handler::User;
-use crate::APIResponse;
+use crate::{APIResponse, ServerState};
 use anyhow::Error;
 use anyhow::Result;
 use chrono::prelude::*;
 use entity::sea_orm;
-use entity::{snapshot_log, snapshot_task};
+use entity::{snapshot_job, snapshot_log, snapshot_task};
 use rocket::http::Status;
 use rocket::serde::json::Json;
 use sea_orm::{entity::*, query::*, DatabaseTransaction};
@@ -25,10 +27,19 @@ lazy_static! {
 }
 
 async fn validate_input(
+    source_options: &data_fetcher::SourceOptions,
+    user: &User,
+    name: Option<&str>,
     status: Option<entity::snapshot_task::Status>,
     interval: Option<i16>,
     source: Option<&entity::snapshot_task::Source>,
+    schedule: Option<&entity::snapshot_task::Schedule>,
 ) -> Result<Result<(), &'static str>, Error> {
+    if let Some(name) = name {
+        if name.trim().is_empty() || name.chars().count() > 64 {
+            return Ok(Err("invalid_name"));
+        }
+    }
     // `Stopped` status cannot be set by human
     if status == Some(entity::snapshot_task::Status::Stopped) {
         return Ok(Err("invalid_status"));
@@ -41,10 +52,15 @@ async fn validate_input(
             }
         }
     }
+    if let Some(schedule) = schedule {
+        if let Err(error) = schedule::validate(schedule) {
+            return Ok(Err(error));
+        }
+    }
     match source {
         None => (),
         Some(source) => {
-            let res = data_fetcher::validate(source).await?;
+            let res = data_fetcher::validate(source, source_options, user).await?;
             match res {
                 Ok(()) => (),
                 Err(data_fetcher::ValidationError::InvalidShare) => {
@@ -59,15 +75,21 @@ async fn validate_input(
     Ok(Ok(()))
 }
 
+// `task_id` being `None` means any task of the user.
 async fn get_last_sync_time(
     txn: &DatabaseTransaction,
     user: &User,
+    task_id: Option<i64>,
     succeed_only: bool,
 ) -> Result<Option<DateTime<Utc>>> {
     let query = snapshot_log::Entity::find()
         .filter(snapshot_log::Column::UserId.eq(user.uid))
         .order_by_desc(snapshot_log::Column::Timestamp);
 
+    let query = match task_id {
+        None => query,
+        Some(task_id) => query.filter(snapshot_log::Column::SnapshotTaskId.eq(task_id)),
+    };
     let query = if succeed_only {
         query.filter(snapshot_log::Column::Succeed.eq(true))
     } else {
@@ -80,8 +102,12 @@ async fn get_last_sync_time(
     }
 }
 
-async fn get_min_next_sync_time(txn: &DatabaseTransaction, user: &User) -> Result<DateTime<Utc>> {
-    match get_last_sync_time(txn, user, false).await? {
+async fn get_min_next_sync_time(
+    txn: &DatabaseTransaction,
+    user: &User,
+    task_id: Option<i64>,
+) -> Result<DateTime<Utc>> {
+    match get_last_sync_time(txn, user, task_id, false).await? {
         None => Ok(Utc::now()),
         Some(last_time) => Ok(cmp::max(
             last_time + chrono::Duration::minutes(20),
@@ -90,14 +116,121 @@ async fn get_min_next_sync_time(txn: &DatabaseTransaction, user: &User) -> Resul
     }
 }
 
+fn find_task(user: &User, id: i64) -> Select<snapshot_task::Entity> {
+    snapshot_task::Entity::find_by_id(id).filter(snapshot_task::Column::UserId.eq(user.uid))
+}
+
+#[derive(Serialize)]
+struct TaskJson {
+    pub id: i64,
+    pub name: String,
+    pub status: snapshot_task::Status,
+    pub interval: i16,
+    pub source: SourceJson,
+    pub schedule: Option<snapshot_task::Schedule>,
+    pub last_success_sync: Option<DateTime<Utc>>,
+    pub error_count: i16,
+}
+
+/// `snapshot_task::Source` without credentials, they are never sent back.
+#[derive(Serialize)]
+enum SourceJson {
+    OneDrive { share_url: String },
+    WebDav { url: String, username: String },
+    LocalPath { path: String },
+}
+
+impl From<snapshot_task::Source> for SourceJson {
+    fn from(source: snapshot_task::Source) -> Self {
+        match source {
+            snapshot_task::Source::OneDrive { share_url } => SourceJson::OneDrive { share_url },
+            snapshot_task::Source::WebDav {
+                url,
+                username,
+                password: _,
+            } => SourceJson::WebDav { url, username },
+            snapshot_task::Source::LocalPath { path } => SourceJson::LocalPath { path },
+        }
+    }
+}
+
+async fn to_task_json(
+    txn: &DatabaseTransaction,
+    user: &User,
+    task: snapshot_task::Model,
+) -> Result<TaskJson> {
+    let snapshot_task::Model {
+        id,
+        user_id: _,
+        name,
+        status,
+        interval,
+        source,
+        next_sync: _,
+        error_count,
+        locked_count: _,
+        lease_owner: _,
+        lease_expires_at: _,
+        schedule,
+        sync_state: _,
+    } = task;
+    let last_success_sync = get_last_sync_time(txn, user, Some(id), true).await?;
+    Ok(TaskJson {
+        id,
+        name,
+        status,
+        interval,
+        source: source.into(),
+        schedule,
+        last_success_sync,
+        error_count,
+    })
+}
+
+#[get("/")]
+async fn list(conn: Connection<'_, Db>, user: User) -> APIResponse {
+    let db = conn.into_inner();
+    let txn = db.begin().await?;
+
+    let tasks = snapshot_task::Entity::find()
+        .filter(snapshot_task::Column::UserId.eq(user.uid))
+        .order_by_asc(snapshot_task::Column::Id)
+        .all(&txn)
+        .await?;
+    let mut snapshot_tasks = Vec::new();
+    for task in tasks {
+        snapshot_tasks.push(to_task_json(&txn, &user, task).await?);
+    }
+
+    txn.commit().await?;
+    Ok((Status::Ok, json!({ "snapshot_tasks": snapshot_tasks })))
+}
+
 #[derive(Deserialize)]
 struct CreateData {
+    name: String,
     interval: i16,
     source: entity::snapshot_task::Source,
+    #[serde(default)]
+    schedule: Option<entity::snapshot_task::Schedule>,
 }
 #[post("/", data = "<data>")]
-async fn create(conn: Connection<'_, Db>, user: User, data: Json<CreateData>) -> APIResponse {
-    let res = validate_input(None, Some(data.interval), Some(&data.source)).await?;
+async fn create(
+    conn: Connection<'_, Db>,
+    server_state: &rocket::State<ServerState>,
+    user: User,
+    data: Json<CreateData>,
+) -> APIResponse {
+    let res = validate_input(
+        &server_state.source_options,
+        &user,
+        Some(&data.name),
+        None,
+        Some(data.interval),
+        Some(&data.source),
+        data.schedule.as_ref(),
+    )
+    .await?;
     match res {
         Ok(()) => (),
         Err(error) => return Ok((Status::BadRequest, json!({ "error": error }))),
@@ -106,78 +239,86 @@ async fn create(conn: Connection<'_, Db>, user: User, data: Json<CreateData>) ->
     let db = conn.into_inner();
     let txn = db.begin().await?;
 
+    let number_of_tasks = snapshot_task::Entity::find()
+        .filter(snapshot_task::Column::UserId.eq(user.uid))
+        .count(&txn)
+        .await?;
+    if number_of_tasks >= limit::SNAPSHOT_TASK_LIMIT_PER_USER {
+        return Ok((Status::BadRequest, json!({ "error": "too_many_tasks" })));
+    }
+
     let task = snapshot_task::ActiveModel {
+        id: NotSet,
         user_id: Set(user.uid),
+        name: Set(data.name.clone()),
         status: Set(entity::snapshot_task::Status::Running),
         interval: Set(data.interval),
         source: Set(data.source.clone()),
-        next_sync: Set(get_min_next_sync_time(&txn, &user).await?),
+        // the spacing between syncs also applies to tasks that were just deleted and recreated.
+        next_sync: Set(get_min_next_sync_time(&txn, &user, None).await?),
         error_count: Set(0),
+        locked_count: Set(0),
+        lease_owner: Set(None),
+        lease_expires_at: Set(None),
+        schedule: Set(data.schedule.clone()),
+        sync_state: Set(None),
     };
 
-    task.insert(&txn).await?;
+    let task = task.insert(&txn).await?;
 
     txn.commit().await?;
 
-    Ok((Status::Ok, json!({})))
-}
-
-#[derive(Serialize)]
-struct TaskJson {
-    pub status: snapshot_task::Status,
-    pub interval: i16,
-    pub source: snapshot_task::Source,
-    pub last_success_sync: Option<DateTime<Utc>>,
-    pub error_count: i16,
+    Ok((Status::Ok, json!({ "id": task.id })))
 }
 
-#[get("/")]
-async fn get(conn: Connection<'_, Db>, user: User) -> APIResponse {
+#[get("/<id>")]
+async fn get(conn: Connection<'_, Db>, user: User, id: i64) -> APIResponse {
     let db = conn.into_inner();
     let txn = db.begin().await?;
 
-    let task = snapshot_task::Entity::find()
-        .filter(snapshot_task::Column::UserId.eq(user.uid))
-        .one(&txn)
-        .await?;
+    let task = find_task(&user, id).one(&txn).await?;
     match task {
         None => Ok((Status::NotFound, json!({}))),
         Some(task) => {
-            let snapshot_task::Model {
-                user_id: _,
-                status,
-                interval,
-                source,
-                next_sync: _,
-                error_count,
-            } = task;
-            let last_success_sync = get_last_sync_time(&txn, &user, true).await?;
+            let task_json = to_task_json(&txn, &user, task).await?;
 
             txn.commit().await?;
 
-            Ok((
-                Status::Ok,
-                json!(TaskJson {
-                    status,
-                    interval,
-                    source,
-                    last_success_sync,
-                    error_count,
-                }),
-            ))
+            Ok((Status::Ok, json!(task_json)))
         }
     }
 }
 
 #[derive(Deserialize)]
 struct UpdateData {
+    name: Option<String>,
     status: Option<entity::snapshot_task::Status>,
     interval: Option<i16>,
     source: Option<entity::snapshot_task::Source>,
+    // `null` clears the schedule, a missing field leaves it untouched.
+    #[serde(default, deserialize_with = "crate::utils::deserialize_some")]
+    schedule: Option<Option<entity::snapshot_task::Schedule>>,
 }
-#[patch("/", data = "<data>")]
-async fn update(conn: Connection<'_, Db>, user: User, data: Json<UpdateData>) -> APIResponse {
-    let res = validate_input(data.status, data.interval, data.source.as_ref()).await?;
+#[patch("/<id>", data = "<data>")]
+async fn update(
+    conn: Connection<'_, Db>,
+    server_state: &rocket::State<ServerState>,
+    user: User,
+    id: i64,
+    data: Json<UpdateData>,
+) -> APIResponse {
+    let res = validate_input(
+        &server_state.source_options,
+        &user,
+        data.name.as_deref(),
+        data.status,
+        data.interval,
+        data.source.as_ref(),
+        data.schedule
+            .as_ref()
+            .and_then(|schedule| schedule.as_ref()),
+    )
+    .await?;
     match res {
         Ok(()) => (),
         Err(error) => return Ok((Status::BadRequest, json!({ "error": error }))),
@@ -186,25 +327,29 @@ async fn update(conn: Connection<'_, Db>, user: User, data: Json<UpdateData>) ->
     let db = conn.into_inner();
     let txn = db.begin().await?;
 
-    let task = entity::snapshot_task::Entity::find()
-        .filter(entity::snapshot_task::Column::UserId.eq(user.uid))
-        .lock_exclusive()
-        .one(&txn)
-        .await?;
+    let task = find_task(&user, id).lock_exclusive().one(&txn).await?;
     match task {
         None => Ok((Status::NotFound, json!({}))),
         Some(task) => {
+            // NOTE: the lease is left untouched, so even if `next_sync` is reset, a task that is
+            // syncing won't be claimed again until the current job is done.
             // TODO: the reset logic is really naive
             let need_reset = ((task.status != entity::snapshot_task::Status::Running)
                 && (data.status == Some(entity::snapshot_task::Status::Running)))
                 || data.interval.is_some()
-                || data.source.is_some();
+                || data.source.is_some()
+                || data.schedule.is_some();
 
             let mut task_mut: entity::snapshot_task::ActiveModel = task.into();
             if need_reset {
-                task_mut.next_sync = Set(get_min_next_sync_time(&txn, &user).await?);
+                task_mut.next_sync = Set(get_min_next_sync_time(&txn, &user, Some(id)).await?);
                 task_mut.error_count = Set(0);
+                task_mut.locked_count = Set(0);
             }
+            match &data.name {
+                None => (),
+                Some(name) => task_mut.name = Set(name.clone()),
+            };
             match data.status {
                 None => (),
                 Some(status) => task_mut.status = Set(status),
@@ -213,9 +358,16 @@ async fn update(conn: Connection<'_, Db>, user: User, data: Json<UpdateData>) ->
                 None => (),
                 Some(interval) => task_mut.interval = Set(interval),
             };
+            match &data.schedule {
+                None => (),
+                Some(schedule) => task_mut.schedule = Set(schedule.clone()),
+            };
             match &data.source {
                 None => (),
-                Some(source) => task_mut.source = Set(source.clone()),
+                Some(source) => {
+                    task_mut.source = Set(source.clone());
+                    task_mut.sync_state = Set(None);
+                }
             };
             task_mut.update(&txn).await?;
 
@@ -225,20 +377,373 @@ async fn update(conn: Connection<'_, Db>, user: User, data: Json<UpdateData>) ->
     }
 }
 
-#[delete("/")]
-async fn delete(conn: Connection<'_, Db>, user: User) -> APIResponse {
+// Snapshots and logs of the task are kept.
+#[delete("/<id>")]
+async fn delete(conn: Connection<'_, Db>, user: User, id: i64) -> APIResponse {
     let db = conn.into_inner();
-    let res = entity::snapshot_task::Entity::delete_by_id(user.uid)
-        .exec(db)
+    let txn = db.begin().await?;
+    let res = entity::snapshot_task::Entity::delete_many()
+        .filter(snapshot_task::Column::Id.eq(id))
+        .filter(snapshot_task::Column::UserId.eq(user.uid))
+        .exec(&txn)
         .await?;
 
     if res.rows_affected == 1 {
+        snapshot_job::Entity::delete_many()
+            .filter(snapshot_job::Column::SnapshotTaskId.eq(id))
+            .exec(&txn)
+            .await?;
+        txn.commit().await?;
         Ok((Status::Ok, json!({})))
     } else {
         Ok((Status::NotFound, json!({})))
     }
 }
 
+// Sync as soon as possible (but still respecting the minimum spacing between syncs). The returned
+// `job_id` can be used to find out when the sync is done and the result of it.
+#[post("/<id>/run")]
+async fn run(conn: Connection<'_, Db>, user: User, id: i64) -> APIResponse {
+    let db = conn.into_inner();
+    let txn = db.begin().await?;
+
+    let task = find_task(&user, id).lock_exclusive().one(&txn).await?;
+    let task = match task {
+        None => return Ok((Status::NotFound, json!({}))),
+        Some(task) => task,
+    };
+    if task.status != snapshot_task::Status::Running {
+        return Ok((Status::BadRequest, json!({"error": "task_not_running"})));
+    }
+
+    // there is no point to have more than one pending job
+    let pending_job = snapshot_job::Entity::find()
+        .filter(snapshot_job::Column::SnapshotTaskId.eq(id))
+        .filter(snapshot_job::Column::Status.eq(snapshot_job::Status::Queued))
+        .one(&txn)
+        .await?;
+    let job = match pending_job {
+        Some(job) => job,
+        None => {
+            snapshot_job::Entity::delete_many()
+                .filter(snapshot_job::Column::SnapshotTaskId.eq(id))
+                .filter(snapshot_job::Column::Status.eq(snapshot_job::Status::Done))
+                .filter(snapshot_job::Column::CreatedAt.lt(Utc::now() - chrono::Duration::days(7)))
+                .exec(&txn)
+                .await?;
+            snapshot_job::ActiveModel {
+                id: NotSet,
+                user_id: Set(user.uid),
+                snapshot_task_id: Set(id),
+                status: Set(snapshot_job::Status::Queued),
+                created_at: Set(Utc::now()),
+                finished_at: Set(None),
+                snapshot_log_id: Set(None),
+            }
+            .insert(&txn)
+            .await?
+        }
+    };
+
+    let next_sync = cmp::min(
+        task.next_sync,
+        get_min_next_sync_time(&txn, &user, Some(id)).await?,
+    );
+    snapshot_task::Entity::update(snapshot_task::ActiveModel {
+        id: Set(id),
+        next_sync: Set(next_sync),
+        ..Default::default()
+    })
+    .exec(&txn)
+    .await?;
+
+    txn.commit().await?;
+    Ok((
+        Status::Ok,
+        json!({ "job_id": job.id, "next_sync": next_sync }),
+    ))
+}
+
+#[get("/run/<job_id>")]
+async fn get_run(conn: Connection<'_, Db>, user: User, job_id: i64) -> APIResponse {
+    let db = conn.into_inner();
+
+    let job = snapshot_job::Entity::find_by_id(job_id)
+        .filter(snapshot_job::Column::UserId.eq(user.uid))
+        .one(db)
+        .await?;
+    match job {
+        None => Ok((Status::NotFound, json!({}))),
+        Some(job) => {
+            // the log may already be gone, we only keep recent logs.
+            let snapshot_log = match job.snapshot_log_id {
+                None => None,
+                Some(snapshot_log_id) => {
+                    snapshot_log::Entity::find_by_id(snapshot_log_id)
+                        .one(db)
+                        .await?
+                }
+            };
+            Ok((
+                Status::Ok,
+                json!({
+                    "snapshot_task_id": job.snapshot_task_id,
+                    "status": job.status,
+                    "created_at": job.created_at,
+                    "finished_at": job.finished_at,
+                    "snapshot_log": snapshot_log,
+                }),
+            ))
+        }
+    }
+}
+
 pub fn routes() -> Vec<rocket::Route> {
-    routes![create, get, update, delete]
+    routes![list, create, get, update, delete, run, get_run]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task_runner;
+    use crate::test_utils::{needs_database, TestServer};
+    use entity::snapshot;
+
+    async fn create_task(server: &TestServer, name: &str) -> i64 {
+        let resp = server
+            .client
+            .post("/api/v1/snapshot_task")
+            .header(server.auth(1))
+            .json(&json!({ "name": name, "interval": 7 * 24 * 60, "source": server.source }))
+            .dispatch()
+            .await;
+        assert_eq!(resp.status(), Status::Ok);
+        resp.into_json::<serde_json::Value>().await.unwrap()["id"]
+            .as_i64()
+            .unwrap()
+    }
+
+    async fn do_one_task(server: &TestServer) {
+        task_runner::do_one_task(
+            &server.conn,
+            &server.task_runner_context(),
+            &task_runner::InFlightTasks::default(),
+            "test",
+            &server.client.rocket().shutdown(),
+        )
+        .await
+        .unwrap();
+    }
+
+    async fn post_run(server: &TestServer, task_id: i64) -> serde_json::Value {
+        let resp = server
+            .client
+            .post(format!("/api/v1/snapshot_task/{}/run", task_id))
+            .header(server.auth(1))
+            .dispatch()
+            .await;
+        assert_eq!(resp.status(), Status::Ok);
+        resp.into_json().await.unwrap()
+    }
+
+    async fn get_run(server: &TestServer, job_id: i64) -> serde_json::Value {
+        let resp = server
+            .client
+            .get(format!("/api/v1/snapshot_task/run/{}", job_id))
+            .header(server.auth(1))
+            .dispatch()
+            .await;
+        assert_eq!(resp.status(), Status::Ok);
+        resp.into_json().await.unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = needs_database!()]
+    async fn test_run() {
+        let server = TestServer::new().await;
+        let resp = server
+            .client
+            .post("/api/v1/snapshot_task/1/run")
+            .header(server.auth(1))
+            .dispatch()
+            .await;
+        assert_eq!(resp.status(), Status::NotFound);
+
+        let task_id = create_task(&server, "phone").await;
+        snapshot_task::Entity::update(snapshot_task::ActiveModel {
+            id: Set(task_id),
+            next_sync: Set(Utc::now() + chrono::Duration::days(7)),
+            ..Default::default()
+        })
+        .exec(&server.conn)
+        .await
+        .unwrap();
+
+        let job = post_run(&server, task_id).await;
+        let job_id = job["job_id"].as_i64().unwrap();
+        let next_sync: DateTime<Utc> = serde_json::from_value(job["next_sync"].clone()).unwrap();
+        assert!(next_sync <= Utc::now());
+        // a pending job is reused
+        assert_eq!(post_run(&server, task_id).await["job_id"], job_id);
+        assert_eq!(get_run(&server, job_id).await["status"], "Queued");
+
+        do_one_task(&server).await;
+        let job = get_run(&server, job_id).await;
+        assert_eq!(job["status"], "Done");
+        assert_eq!(job["snapshot_task_id"], task_id);
+        assert_eq!(job["snapshot_log"]["succeed"], true);
+
+        // the minimum spacing between syncs still applies
+        let job = post_run(&server, task_id).await;
+        assert_ne!(job["job_id"], job_id);
+        let next_sync: DateTime<Utc> = serde_json::from_value(job["next_sync"].clone()).unwrap();
+        assert!(next_sync > Utc::now() + chrono::Duration::minutes(19));
+
+        // other users can't see the job
+        let resp = server
+            .client
+            .get(format!("/api/v1/snapshot_task/run/{}", job_id))
+            .header(server.auth(2))
+            .dispatch()
+            .await;
+        assert_eq!(resp.status(), Status::NotFound);
+    }
+
+    #[tokio::test]
+    #[ignore = needs_database!()]
+    async fn test_multiple_tasks() {
+        let server = TestServer::new().await;
+        let phone = create_task(&server, "phone").await;
+        let tablet = create_task(&server, "tablet").await;
+        assert_ne!(phone, tablet);
+        for name in ["a", "b", "c"] {
+            create_task(&server, name).await;
+        }
+        let resp = server
+            .client
+            .post("/api/v1/snapshot_task")
+            .header(server.auth(1))
+            .json(&json!({ "name": "d", "interval": 24 * 60, "source": server.source }))
+            .dispatch()
+            .await;
+        assert_eq!(resp.status(), Status::BadRequest);
+
+        let resp = server
+            .client
+            .patch(format!("/api/v1/snapshot_task/{}", tablet))
+            .header(server.auth(1))
+            .json(&json!({ "name": "old tablet", "interval": 24 * 60 }))
+            .dispatch()
+            .await;
+        assert_eq!(resp.status(), Status::Ok);
+        // tasks of other users can't be touched
+        let resp = server
+            .client
+            .patch(format!("/api/v1/snapshot_task/{}", tablet))
+            .header(server.auth(2))
+            .json(&json!({ "interval": 6 * 60 }))
+            .dispatch()
+            .await;
+        assert_eq!(resp.status(), Status::NotFound);
+        let resp = server
+            .client
+            .get(format!("/api/v1/snapshot_task/{}", tablet))
+            .header(server.auth(1))
+            .dispatch()
+            .await;
+        assert_eq!(resp.status(), Status::Ok);
+        let task: serde_json::Value = resp.into_json().await.unwrap();
+        assert_eq!(task["name"], "old tablet");
+        assert_eq!(task["interval"], 24 * 60);
+
+        for task_id in [phone, tablet] {
+            snapshot_task::Entity::update(snapshot_task::ActiveModel {
+                id: Set(task_id),
+                next_sync: Set(Utc::now() - chrono::Duration::days(1)),
+                ..Default::default()
+            })
+            .exec(&server.conn)
+            .await
+            .unwrap();
+            do_one_task(&server).await;
+        }
+        // both tasks sync the same files, each of them still gets its own snapshot.
+        let snapshots = snapshot::Entity::find()
+            .filter(snapshot::Column::UserId.eq(1_i64))
+            .order_by_asc(snapshot::Column::SnapshotTaskId)
+            .all(&server.conn)
+            .await
+            .unwrap();
+        let task_ids: Vec<_> = snapshots.iter().map(|s| s.snapshot_task_id).collect();
+        assert_eq!(task_ids, vec![Some(phone), Some(tablet)]);
+
+        let resp = server
+            .client
+            .delete(format!("/api/v1/snapshot_task/{}", phone))
+            .header(server.auth(1))
+            .dispatch()
+            .await;
+        assert_eq!(resp.status(), Status::Ok);
+        let resp = server
+            .client
+            .get("/api/v1/snapshot_task")
+            .header(server.auth(1))
+            .dispatch()
+            .await;
+        let tasks: serde_json::Value = resp.into_json().await.unwrap();
+        let names: Vec<_> = tasks["snapshot_tasks"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|task| task["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["old tablet", "a", "b", "c"]);
+    }
+
+    #[tokio::test]
+    #[ignore = needs_database!()]
+    async fn test_source_is_redacted() {
+        let server = TestServer::new().await;
+        let task_id = create_task(&server, "nas").await;
+        snapshot_task::Entity::update(snapshot_task::ActiveModel {
+            id: Set(task_id),
+            source: Set(snapshot_task::Source::WebDav {
+                url: "https://cloud.example.com/dav/".into(),
+                username: "alice".into(),
+                password: "secret".into(),
+            }),
+            ..Default::default()
+        })
+        .exec(&server.conn)
+        .await
+        .unwrap();
+        let resp = server
+            .client
+            .get(format!("/api/v1/snapshot_task/{}", task_id))
+            .header(server.auth(1))
+            .dispatch()
+            .await;
+        let task: serde_json::Value = resp.into_json().await.unwrap();
+        assert_eq!(
+            task["source"],
+            json!({ "WebDav": { "url": "https://cloud.example.com/dav/", "username": "alice" } })
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = needs_database!()]
+    async fn test_local_path_is_per_user() {
+        let server = TestServer::new().await;
+        // `server.source` is under the folder of user 1
+        let resp = server
+            .client
+            .post("/api/v1/snapshot_task")
+            .header(server.auth(2))
+            .json(&json!({ "name": "phone", "interval": 24 * 60, "source": server.source }))
+            .dispatch()
+            .await;
+        assert_eq!(resp.status(), Status::BadRequest);
+        let resp: serde_json::Value = resp.into_json().await.unwrap();
+        assert_eq!(resp["error"], "invalid_share");
+    }
 }
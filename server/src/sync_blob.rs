@@ -0,0 +1,297 @@
+use crate::file_storage::byte_unit_to_string_hum;
+use crate::limit;
+use crate::user_handler::User;
+use anyhow::Result;
+use entity::sea_orm::sea_query::{Expr, OnConflict};
+use entity::sea_orm::{self, ConnectionTrait, DbBackend, Statement};
+use entity::sea_orm::{entity::*, query::*};
+use entity::snapshot::SyncFiles;
+use entity::{sync_blob, user};
+use std::collections::HashSet;
+
+// first key of the postgres advisory locks on users' storage quota
+const QUOTA_LOCK_NAMESPACE: i32 = 0x51_55_4f_54;
+
+/// Record files that are (about to be) added to the sync file storage of the user, `files` is
+/// `[(sha-256, size)]` and files we already know are ignored. Fails with `QuotaExceeded` if the
+/// new files don't fit, nothing is recorded in that case and `txn` is still usable.
+/// Files only count towards the quota, they are not used by anything until `add_references`.
+pub async fn add_files(
+    txn: &sea_orm::DatabaseTransaction,
+    user: &User,
+    files: &[(String, u64)],
+    limits: &limit::Limits,
+) -> Result<()> {
+    if files.is_empty() {
+        return Ok(());
+    }
+    let txn = txn.begin().await?;
+    // concurrent syncs and uploads of the same user must not both see the quota that is left.
+    txn.execute(Statement::from_sql_and_values(
+        DbBackend::Postgres,
+        "SELECT pg_advisory_xact_lock($1, $2)",
+        [QUOTA_LOCK_NAMESPACE.into(), (user.uid as i32).into()],
+    ))
+    .await?;
+    let limit = quota(&txn, limits, user).await?;
+    let current_size = usage(&txn, user).await?;
+    sync_blob::Entity::insert_many(files.iter().map(|(sha256, size)| sync_blob::ActiveModel {
+        user_id: Set(user.uid),
+        sha256: Set(sha256.clone()),
+        size: Set(*size as i64),
+        ref_count: Set(0),
+    }))
+    .on_conflict(
+        OnConflict::columns([sync_blob::Column::UserId, sync_blob::Column::Sha256])
+            .do_nothing()
+            .to_owned(),
+    )
+    .exec_without_returning(&txn)
+    .await?;
+    let new_size = usage(&txn, user).await?;
+    if new_size > current_size && new_size > limit {
+        txn.rollback().await?;
+        return Err(limit::QuotaExceeded(format!(
+            "out of sync file storage quota. current: {}, need: {}, limit: {}",
+            byte_unit_to_string_hum(current_size),
+            byte_unit_to_string_hum(new_size - current_size),
+            byte_unit_to_string_hum(limit)
+        ))
+        .into());
+    }
+    txn.commit().await?;
+    Ok(())
+}
+
+/// Those of `sha256s` that we have a record of. GC removes the record together with the file, so
+/// under `sync_file_gc::lock_shared` a record means the file is in the storage and this doesn't
+/// need to touch the storage. Without the lock it is only a hint.
+pub async fn find_recorded<'a, C: ConnectionTrait>(
+    db: &C,
+    user: &User,
+    sha256s: impl IntoIterator<Item = &'a String>,
+) -> Result<HashSet<String>> {
+    let sha256s: HashSet<&String> = sha256s.into_iter().collect();
+    if sha256s.is_empty() {
+        return Ok(HashSet::new());
+    }
+    Ok(sync_blob::Entity::find()
+        .select_only()
+        .column(sync_blob::Column::Sha256)
+        .filter(sync_blob::Column::UserId.eq(user.uid))
+        .filter(sync_blob::Column::Sha256.is_in(sha256s))
+        .into_tuple()
+        .all(db)
+        .await?
+        .into_iter()
+        .collect())
+}
+
+/// One of `sha256s` that we don't have a record of, see `find_recorded`.
+pub async fn find_missing<'a>(
+    txn: &sea_orm::DatabaseTransaction,
+    user: &User,
+    sha256s: impl IntoIterator<Item = &'a String> + Clone,
+) -> Result<Option<&'a String>> {
+    let recorded = find_recorded(txn, user, sha256s.clone()).await?;
+    Ok(sha256s
+        .into_iter()
+        .find(|sha256| !recorded.contains(*sha256)))
+}
+
+async fn update_ref_count(
+    txn: &sea_orm::DatabaseTransaction,
+    user: &User,
+    sync_files: &SyncFiles,
+    delta: i32,
+) -> Result<()> {
+    // a snapshot counts once even if it uses a file for multiple tiles
+    let sha256s: HashSet<&String> = sync_files.0.values().collect();
+    if sha256s.is_empty() {
+        return Ok(());
+    }
+    sync_blob::Entity::update_many()
+        .col_expr(
+            sync_blob::Column::RefCount,
+            Expr::col(sync_blob::Column::RefCount).add(delta),
+        )
+        .filter(sync_blob::Column::UserId.eq(user.uid))
+        .filter(sync_blob::Column::Sha256.is_in(sha256s))
+        .exec(txn)
+        .await?;
+    Ok(())
+}
+
+/// Must be called in the transaction that creates a snapshot with `sync_files`.
+pub async fn add_references(
+    txn: &sea_orm::DatabaseTransaction,
+    user: &User,
+    sync_files: &SyncFiles,
+) -> Result<()> {
+    update_ref_count(txn, user, sync_files, 1).await
+}
+
+/// Must be called in the transaction that deletes a snapshot with `sync_files`.
+pub async fn remove_references(
+    txn: &sea_orm::DatabaseTransaction,
+    user: &User,
+    sync_files: &SyncFiles,
+) -> Result<()> {
+    update_ref_count(txn, user, sync_files, -1).await
+}
+
+/// The sync file storage quota of the user in bytes.
+pub async fn quota<C: ConnectionTrait>(db: &C, limits: &limit::Limits, user: &User) -> Result<u64> {
+    let limit_mib: Option<Option<i64>> = user::Entity::find_by_id(user.uid)
+        .select_only()
+        .column(user::Column::SyncFileStorageLimitMib)
+        .into_tuple()
+        .one(db)
+        .await?;
+    Ok(match limit_mib.flatten() {
+        Some(limit_mib) => limit_mib.max(0) as u64 * 1024 * 1024,
+        None => limits.sync_file_storage_per_user,
+    })
+}
+
+/// What is left of the quota in bytes. Only good for not saving files that won't fit in the first
+/// place, `add_files` is what enforces the quota.
+pub async fn quota_left<C: ConnectionTrait>(
+    db: &C,
+    limits: &limit::Limits,
+    user: &User,
+) -> Result<u64> {
+    Ok(quota(db, limits, user)
+        .await?
+        .saturating_sub(usage(db, user).await?))
+}
+
+/// Total size of the sync files of the user in bytes, this is what the quota is checked against.
+pub async fn usage<C: ConnectionTrait>(db: &C, user: &User) -> Result<u64> {
+    let size: i64 = sync_blob::Entity::find()
+        .select_only()
+        .column_as(Expr::cust("COALESCE(SUM(size), 0)::BIGINT"), "size")
+        .filter(sync_blob::Column::UserId.eq(user.uid))
+        .into_tuple()
+        .one(db)
+        .await?
+        .unwrap_or(0);
+    Ok(size as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{self, limits, needs_database, TestServer};
+    use std::collections::HashMap;
+
+    fn sha256(i: u8) -> String {
+        format!("{:064x}", i)
+    }
+
+    async fn ref_counts(db: &impl ConnectionTrait, user: &User) -> Vec<(String, i32)> {
+        sync_blob::Entity::find()
+            .select_only()
+            .columns([sync_blob::Column::Sha256, sync_blob::Column::RefCount])
+            .filter(sync_blob::Column::UserId.eq(user.uid))
+            .order_by_asc(sync_blob::Column::Sha256)
+            .into_tuple()
+            .all(db)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = needs_database!()]
+    async fn test_sync_blob() {
+        let server = TestServer::new().await;
+        let user = User { uid: 1 };
+        let other_user = User { uid: 2 };
+        let txn = server.conn.begin().await.unwrap();
+        let limits = limits();
+        add_files(&txn, &user, &[(sha256(1), 100), (sha256(2), 20)], &limits)
+            .await
+            .unwrap();
+        // known files don't count twice
+        add_files(&txn, &user, &[(sha256(1), 100)], &limits)
+            .await
+            .unwrap();
+        add_files(&txn, &other_user, &[(sha256(1), 100)], &limits)
+            .await
+            .unwrap();
+        assert_eq!(usage(&txn, &user).await.unwrap(), 120);
+
+        let error = add_files(
+            &txn,
+            &user,
+            &[(sha256(3), limits.sync_file_storage_per_user)],
+            &limits,
+        )
+        .await
+        .unwrap_err();
+        assert!(error.is::<limit::QuotaExceeded>());
+        // the transaction is still good and nothing is recorded
+        assert_eq!(usage(&txn, &user).await.unwrap(), 120);
+
+        let sync_files = SyncFiles(HashMap::from([
+            (1, sha256(1)),
+            (2, sha256(1)),
+            (3, sha256(2)),
+        ]));
+        add_references(&txn, &user, &sync_files).await.unwrap();
+        add_references(&txn, &user, &SyncFiles(HashMap::from([(1, sha256(1))])))
+            .await
+            .unwrap();
+        assert_eq!(
+            ref_counts(&txn, &user).await,
+            vec![(sha256(1), 2), (sha256(2), 1)]
+        );
+        remove_references(&txn, &user, &sync_files).await.unwrap();
+        assert_eq!(
+            ref_counts(&txn, &user).await,
+            vec![(sha256(1), 1), (sha256(2), 0)]
+        );
+        assert_eq!(ref_counts(&txn, &other_user).await, vec![(sha256(1), 0)]);
+        let unknown = sha256(9);
+        assert_eq!(
+            find_missing(&txn, &user, [&sha256(1), &sha256(2)])
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            find_missing(&txn, &user, [&sha256(1), &unknown])
+                .await
+                .unwrap(),
+            Some(&unknown)
+        );
+        txn.commit().await.unwrap();
+        assert_eq!(usage(&server.conn, &user).await.unwrap(), 120);
+
+        // users can have their own quota
+        user::ActiveModel {
+            sync_file_storage_limit_mib: Set(Some(1000)),
+            ..test_utils::user(user.uid)
+        }
+        .insert(&server.conn)
+        .await
+        .unwrap();
+        assert_eq!(
+            quota(&server.conn, &limits, &user).await.unwrap(),
+            1000 * 1024 * 1024
+        );
+        assert_eq!(
+            quota(&server.conn, &limits, &other_user).await.unwrap(),
+            limits.sync_file_storage_per_user
+        );
+        let txn = server.conn.begin().await.unwrap();
+        add_files(
+            &txn,
+            &user,
+            &[(sha256(3), limits.sync_file_storage_per_user)],
+            &limits,
+        )
+        .await
+        .unwrap();
+    }
+}
@@ -0,0 +1,537 @@
+use crate::file_storage::{Object, SyncFileStorage};
+use crate::snapshot_stats;
+use crate::user_handler::User;
+use anyhow::Result;
+use chrono::Utc;
+use entity::sea_orm::sea_query::OnConflict;
+use entity::sea_orm::{self, ConnectionTrait, DatabaseConnection, DbBackend, Statement};
+use entity::sea_orm::{entity::*, query::*};
+use entity::{marker, snapshot, sync_blob};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime};
+
+// Files newer than this are never removed. They are most likely added by a sync or an upload
+// that hasn't saved its snapshot yet, removing them is safe (see `lock_shared`) but would fail
+// that sync for nothing.
+const GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+// first key of the postgres advisory locks on users' sync files
+const LOCK_NAMESPACE: i32 = 0x5f_5f_47_43;
+
+// `marker` of `backfill_all`
+const BACKFILL_MARKER: &str = "sync_blob_backfill";
+
+async fn lock(txn: &sea_orm::DatabaseTransaction, user: &User, shared: bool) -> Result<()> {
+    let sql = if shared {
+        "SELECT pg_advisory_xact_lock_shared($1, $2)"
+    } else {
+        "SELECT pg_advisory_xact_lock($1, $2)"
+    };
+    // the second key is only 32 bits, a collision just means waiting for someone else.
+    txn.execute(Statement::from_sql_and_values(
+        DbBackend::Postgres,
+        sql,
+        [LOCK_NAMESPACE.into(), (user.uid as i32).into()],
+    ))
+    .await?;
+    Ok(())
+}
+
+/// Must be held by anything that creates snapshots from files that are already in the storage,
+/// from checking that the files are there to committing the snapshot. GC holds the exclusive
+/// version of this lock while it runs, so it never removes a file that is about to be
+/// referenced. The lock is released with the transaction.
+pub async fn lock_shared(txn: &sea_orm::DatabaseTransaction, user: &User) -> Result<()> {
+    lock(txn, user, true).await
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub removed_files: u64,
+    pub removed_bytes: u64,
+    /// `sync_blob` rows that didn't match the storage
+    pub fixed_records: u64,
+}
+
+/// Remove files of the user that are no longer referenced, i.e. their `sync_blob.ref_count` is 0.
+/// The storage is listed without holding the lock, the exclusive lock is only held to recheck the
+/// records and remove the files. Fails until `backfill_all` is done.
+pub async fn collect(
+    conn: &DatabaseConnection,
+    sync_file_storage: &SyncFileStorage,
+    user: &User,
+) -> Result<Stats> {
+    if !is_backfilled(conn).await? {
+        return Err(anyhow!("sync blob records are not backfilled yet"));
+    }
+    let cutoff = SystemTime::now() - GRACE_PERIOD;
+    let mut files: HashMap<String, Object> = sync_file_storage
+        .list_files(user)
+        .await?
+        .into_iter()
+        .map(|file| (file.key.clone(), file))
+        .collect();
+    let records: HashMap<String, i32> = sync_blob::Entity::find()
+        .select_only()
+        .columns([sync_blob::Column::Sha256, sync_blob::Column::RefCount])
+        .filter(sync_blob::Column::UserId.eq(user.uid))
+        .into_tuple()
+        .all(conn)
+        .await?
+        .into_iter()
+        .collect();
+    let untracked: Vec<&Object> = files
+        .values()
+        .filter(|file| !records.contains_key(&file.key))
+        .collect();
+    let mut candidates: Vec<String> = files
+        .values()
+        .filter(|file| {
+            file.last_modified < cutoff && records.get(&file.key).is_none_or(|&count| count == 0)
+        })
+        .map(|file| file.key.clone())
+        .collect();
+    let missing: Vec<&String> = records
+        .keys()
+        .filter(|sha256| !files.contains_key(*sha256))
+        .collect();
+    if untracked.is_empty() && candidates.is_empty() && missing.is_empty() {
+        return Ok(Stats::default());
+    }
+
+    let mut stats = Stats::default();
+    let txn = conn.begin().await?;
+    lock(&txn, user, false).await?;
+
+    // Files are recorded before they are referenced (see `lock_shared`), so files without a
+    // record are left by syncs and uploads that didn't finish. Recording them lets the next sync
+    // that skips downloading them use them, until they are old enough to be removed.
+    if !untracked.is_empty() {
+        stats.fixed_records +=
+            sync_blob::Entity::insert_many(untracked.iter().map(|file| sync_blob::ActiveModel {
+                user_id: Set(user.uid),
+                sha256: Set(file.key.clone()),
+                size: Set(file.size as i64),
+                ref_count: Set(0),
+            }))
+            .on_conflict(
+                OnConflict::columns([sync_blob::Column::UserId, sync_blob::Column::Sha256])
+                    .do_nothing()
+                    .to_owned(),
+            )
+            .exec_without_returning(&txn)
+            .await?;
+    }
+
+    // they might have been referenced since we looked
+    if !candidates.is_empty() {
+        candidates = sync_blob::Entity::find()
+            .select_only()
+            .column(sync_blob::Column::Sha256)
+            .filter(sync_blob::Column::UserId.eq(user.uid))
+            .filter(sync_blob::Column::Sha256.is_in(candidates))
+            .filter(sync_blob::Column::RefCount.eq(0))
+            .into_tuple()
+            .all(&txn)
+            .await?;
+    }
+    let mut removed = Vec::new();
+    let mut result = Ok(());
+    for sha256 in candidates {
+        if let Err(error) = sync_file_storage.remove_file(user, &sha256).await {
+            result = Err(error);
+            break;
+        }
+        if let Some(file) = files.remove(&sha256) {
+            stats.removed_bytes += file.size;
+        }
+        stats.removed_files += 1;
+        removed.push(sha256);
+    }
+
+    // records of files that are gone, unless a sync or an upload added the file after we listed
+    // the storage. Then it is referenced already and we only have to double check it.
+    let mut missing_referenced = Vec::new();
+    if !missing.is_empty() {
+        let missing: Vec<(String, i32)> = sync_blob::Entity::find()
+            .select_only()
+            .columns([sync_blob::Column::Sha256, sync_blob::Column::RefCount])
+            .filter(sync_blob::Column::UserId.eq(user.uid))
+            .filter(sync_blob::Column::Sha256.is_in(missing))
+            .into_tuple()
+            .all(&txn)
+            .await?;
+        for (sha256, ref_count) in missing {
+            if ref_count == 0 {
+                stats.fixed_records += 1;
+                removed.push(sha256);
+            } else {
+                missing_referenced.push(sha256);
+            }
+        }
+    }
+    if !removed.is_empty() {
+        sync_blob::Entity::delete_many()
+            .filter(sync_blob::Column::UserId.eq(user.uid))
+            .filter(sync_blob::Column::Sha256.is_in(removed.clone()))
+            .filter(sync_blob::Column::RefCount.eq(0))
+            .exec(&txn)
+            .await?;
+        snapshot_stats::remove_unrecorded(&txn, user, &removed).await?;
+    }
+    txn.commit().await?;
+    result?;
+
+    for sha256 in missing_referenced {
+        if !sync_file_storage.has_file(user, &sha256).await? {
+            warn!(
+                "[sync_file_gc] file {} of user {} is referenced but missing",
+                sha256, user.uid
+            );
+        }
+    }
+    Ok(stats)
+}
+
+/// Make `sync_blob` records of the user match the storage and the snapshots. Only needed for
+/// files stored before we had `sync_blob`, `collect` relies on the records afterwards.
+pub async fn backfill(
+    conn: &DatabaseConnection,
+    sync_file_storage: &SyncFileStorage,
+    user: &User,
+) -> Result<Stats> {
+    let txn = conn.begin().await?;
+    lock(&txn, user, false).await?;
+
+    let mut ref_counts: HashMap<String, i32> = HashMap::new();
+    let all_sync_files: Vec<snapshot::SyncFiles> = snapshot::Entity::find()
+        .select_only()
+        .column(snapshot::Column::SyncFiles)
+        .filter(snapshot::Column::UserId.eq(user.uid))
+        .into_tuple()
+        .all(&txn)
+        .await?;
+    for sync_files in all_sync_files {
+        let sha256s: HashSet<String> = sync_files.0.into_values().collect();
+        for sha256 in sha256s {
+            *ref_counts.entry(sha256).or_default() += 1;
+        }
+    }
+    // `(size, ref_count)` by sha-256
+    let mut records: HashMap<String, (i64, i32)> = sync_blob::Entity::find()
+        .select_only()
+        .columns([
+            sync_blob::Column::Sha256,
+            sync_blob::Column::Size,
+            sync_blob::Column::RefCount,
+        ])
+        .filter(sync_blob::Column::UserId.eq(user.uid))
+        .into_tuple()
+        .all(&txn)
+        .await?
+        .into_iter()
+        .map(|(sha256, size, ref_count)| (sha256, (size, ref_count)))
+        .collect();
+
+    let mut expected_records = Vec::new();
+    for file in sync_file_storage.list_files(user).await? {
+        let expected = (
+            file.size as i64,
+            ref_counts.get(&file.key).copied().unwrap_or(0),
+        );
+        if records.remove(&file.key) != Some(expected) {
+            expected_records.push((file.key, expected));
+        }
+    }
+    // whatever is left is not in the storage (anymore)
+    let stats = Stats {
+        fixed_records: (records.len() + expected_records.len()) as u64,
+        ..Default::default()
+    };
+    if !records.is_empty() {
+        let gone: Vec<String> = records.into_keys().collect();
+        sync_blob::Entity::delete_many()
+            .filter(sync_blob::Column::UserId.eq(user.uid))
+            .filter(sync_blob::Column::Sha256.is_in(gone.clone()))
+            .exec(&txn)
+            .await?;
+        snapshot_stats::remove_unrecorded(&txn, user, &gone).await?;
+    }
+    for (sha256, (size, ref_count)) in expected_records {
+        sync_blob::Entity::insert(sync_blob::ActiveModel {
+            user_id: Set(user.uid),
+            sha256: Set(sha256),
+            size: Set(size),
+            ref_count: Set(ref_count),
+        })
+        .on_conflict(
+            OnConflict::columns([sync_blob::Column::UserId, sync_blob::Column::Sha256])
+                .update_columns([sync_blob::Column::Size, sync_blob::Column::RefCount])
+                .to_owned(),
+        )
+        .exec_without_returning(&txn)
+        .await?;
+    }
+    txn.commit().await?;
+    Ok(stats)
+}
+
+/// Whether `backfill_all` is done, `collect` takes files without a record as unreferenced so it
+/// can't run before that.
+pub async fn is_backfilled<C: ConnectionTrait>(db: &C) -> Result<bool> {
+    Ok(marker::Entity::find_by_id(BACKFILL_MARKER)
+        .one(db)
+        .await?
+        .is_some())
+}
+
+/// `backfill` for every user, only once. It is done when it succeeds for everyone, users that
+/// fail are logged and everyone is tried again the next time.
+pub async fn backfill_all(
+    conn: &DatabaseConnection,
+    sync_file_storage: &SyncFileStorage,
+) -> Result<()> {
+    if is_backfilled(conn).await? {
+        return Ok(());
+    }
+    let mut failed = 0;
+    for user in sync_file_storage.list_users().await? {
+        match backfill(conn, sync_file_storage, &user).await {
+            Ok(stats) => {
+                if stats.fixed_records > 0 {
+                    info!(
+                        "[sync_file_gc] backfilled {} sync blob records of user {}",
+                        stats.fixed_records, user.uid
+                    );
+                }
+            }
+            Err(error) => {
+                error!(
+                    "[sync_file_gc] failed to backfill user {}: {}",
+                    user.uid, error
+                );
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(anyhow!("failed to backfill {} users", failed));
+    }
+    marker::Entity::insert(marker::ActiveModel {
+        name: Set(BACKFILL_MARKER.to_string()),
+        created_at: Set(Utc::now()),
+    })
+    .on_conflict(
+        OnConflict::column(marker::Column::Name)
+            .do_nothing()
+            .to_owned(),
+    )
+    .exec_without_returning(conn)
+    .await?;
+    Ok(())
+}
+
+/// `collect` for every user, errors are logged and don't stop the others.
+pub async fn collect_all(conn: &DatabaseConnection, sync_file_storage: &SyncFileStorage) {
+    match is_backfilled(conn).await {
+        Ok(true) => (),
+        Ok(false) => {
+            warn!("[sync_file_gc] skipped, sync blob records are not backfilled yet");
+            return;
+        }
+        Err(error) => {
+            error!("[sync_file_gc] failed to check the backfill: {}", error);
+            return;
+        }
+    }
+    let users = match sync_file_storage.list_users().await {
+        Ok(users) => users,
+        Err(error) => {
+            error!("[sync_file_gc] failed to list users: {}", error);
+            return;
+        }
+    };
+    for user in users {
+        collect_and_log(conn, sync_file_storage, &user).await;
+    }
+}
+
+pub async fn collect_and_log(
+    conn: &DatabaseConnection,
+    sync_file_storage: &SyncFileStorage,
+    user: &User,
+) {
+    match collect(conn, sync_file_storage, user).await {
+        Ok(stats) => {
+            if stats.removed_files > 0 {
+                info!(
+                    "[sync_file_gc] removed {} files ({} bytes) of user {}",
+                    stats.removed_files, stats.removed_bytes, user.uid
+                );
+            }
+            if stats.fixed_records > 0 {
+                info!(
+                    "[sync_file_gc] fixed {} sync blob records of user {}",
+                    stats.fixed_records, user.uid
+                );
+            }
+        }
+        Err(error) => error!("[sync_file_gc] failed for user {}: {}", user.uid, error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{self, needs_database, TestServer};
+    use std::fs;
+
+    async fn add_files(server: &TestServer, user: &User, contents: &[(&str, bool)]) -> Vec<String> {
+        let tmp_dir = server.sync_file_storage.get_tmp_dir().unwrap();
+        let mut files = Vec::new();
+        for (content, _) in contents {
+            let path = tmp_dir.path().join(content);
+            fs::write(&path, content).unwrap();
+            let sha256 = crate::file_storage::sha256_of_file(&path).unwrap();
+            files.push((sha256, path));
+        }
+        server
+            .sync_file_storage
+            .add_files(user, &files)
+            .await
+            .unwrap();
+        for ((sha256, _), (_, old)) in files.iter().zip(contents) {
+            if *old {
+                let file = fs::File::options()
+                    .write(true)
+                    .open(server.data_dir().join("users/1/sync_files").join(sha256))
+                    .unwrap();
+                file.set_modified(SystemTime::now() - 2 * GRACE_PERIOD)
+                    .unwrap();
+            }
+        }
+        files.into_iter().map(|(sha256, _)| sha256).collect()
+    }
+
+    async fn insert_record(
+        server: &TestServer,
+        user: &User,
+        sha256: &str,
+        size: i64,
+        ref_count: i32,
+    ) {
+        sync_blob::Entity::insert(test_utils::sync_blob(user, sha256, size, ref_count))
+            .exec(&server.conn)
+            .await
+            .unwrap();
+    }
+
+    async fn records(server: &TestServer) -> Vec<(String, i64, i32)> {
+        let mut records: Vec<(String, i64, i32)> = sync_blob::Entity::find()
+            .select_only()
+            .columns([
+                sync_blob::Column::Sha256,
+                sync_blob::Column::Size,
+                sync_blob::Column::RefCount,
+            ])
+            .into_tuple()
+            .all(&server.conn)
+            .await
+            .unwrap();
+        records.sort();
+        records
+    }
+
+    #[tokio::test]
+    #[ignore = needs_database!()]
+    async fn test_collect() {
+        let server = TestServer::new().await;
+        let storage = &server.sync_file_storage;
+        let user = User { uid: 1 };
+        let sha256s = add_files(
+            &server,
+            &user,
+            &[
+                ("kept", true),
+                ("orphaned", true),
+                ("leftover", true),
+                ("new", false),
+            ],
+        )
+        .await;
+        insert_record(&server, &user, &sha256s[0], 4, 1).await;
+        insert_record(&server, &user, &sha256s[1], 8, 0).await;
+        // a record of a file that is gone
+        insert_record(&server, &user, &"0".repeat(64), 1, 0).await;
+
+        let stats = collect(&server.conn, storage, &user).await.unwrap();
+        assert_eq!(
+            stats,
+            Stats {
+                removed_files: 2,
+                removed_bytes: ("orphaned".len() + "leftover".len()) as u64,
+                // "leftover", "new" and the record of the file that is gone
+                fixed_records: 3,
+            }
+        );
+        assert!(storage.has_file(&user, &sha256s[0]).await.unwrap());
+        assert!(!storage.has_file(&user, &sha256s[1]).await.unwrap());
+        assert!(!storage.has_file(&user, &sha256s[2]).await.unwrap());
+        assert!(storage.has_file(&user, &sha256s[3]).await.unwrap());
+        let mut expected = vec![(sha256s[0].clone(), 4, 1), (sha256s[3].clone(), 3, 0)];
+        expected.sort();
+        assert_eq!(records(&server).await, expected);
+
+        let stats = collect(&server.conn, storage, &user).await.unwrap();
+        assert_eq!(stats, Stats::default());
+    }
+
+    #[tokio::test]
+    #[ignore = needs_database!()]
+    async fn test_backfill() {
+        let server = TestServer::new().await;
+        let storage = &server.sync_file_storage;
+        let user = User { uid: 1 };
+        let sha256s = add_files(&server, &user, &[("kept", true), ("orphaned", true)]).await;
+        let sync_files = snapshot::SyncFiles(HashMap::from([(1, sha256s[0].clone())]));
+        test_utils::snapshot(&user, sync_files)
+            .insert(&server.conn)
+            .await
+            .unwrap();
+        insert_record(&server, &user, &sha256s[1], 1, 3).await;
+        insert_record(&server, &user, &"0".repeat(64), 1, 0).await;
+
+        let stats = backfill(&server.conn, storage, &user).await.unwrap();
+        assert_eq!(stats.fixed_records, 3);
+        let mut expected = vec![(sha256s[0].clone(), 4, 1), (sha256s[1].clone(), 8, 0)];
+        expected.sort();
+        assert_eq!(records(&server).await, expected);
+
+        let stats = backfill(&server.conn, storage, &user).await.unwrap();
+        assert_eq!(stats, Stats::default());
+    }
+
+    #[tokio::test]
+    #[ignore = needs_database!()]
+    async fn test_backfill_all_once() {
+        let server = TestServer::new().await;
+        let storage = &server.sync_file_storage;
+        let user = User { uid: 1 };
+        assert!(is_backfilled(&server.conn).await.unwrap());
+        marker::Entity::delete_by_id(BACKFILL_MARKER)
+            .exec(&server.conn)
+            .await
+            .unwrap();
+        assert!(collect(&server.conn, storage, &user).await.is_err());
+
+        let sha256s = add_files(&server, &user, &[("old", true)]).await;
+        backfill_all(&server.conn, storage).await.unwrap();
+        assert!(is_backfilled(&server.conn).await.unwrap());
+        assert_eq!(records(&server).await, vec![(sha256s[0].clone(), 3, 0)]);
+
+        // not again
+        fs::write(server.data_dir().join("users/1/sync_files/untracked"), "").unwrap();
+        backfill_all(&server.conn, storage).await.unwrap();
+        assert_eq!(records(&server).await.len(), 1);
+    }
+}
@@ -0,0 +1,290 @@
+use crate::file_storage::SyncFileStorage;
+use crate::fow_tile;
+use crate::sync_blob;
+use crate::user_handler::User;
+use anyhow::Result;
+use chrono::prelude::*;
+use entity::sea_orm::DatabaseConnection;
+use entity::sea_orm::{entity::*, query::*};
+use entity::snapshot;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Debug, Serialize, PartialEq, Eq)]
+pub struct CorruptFile {
+    pub user_id: i64,
+    pub sha256: String,
+    pub reason: String,
+    /// snapshots that can't be restored because of this file
+    pub snapshot_ids: Vec<i64>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Report {
+    pub checked_files: u64,
+    pub checked_bytes: u64,
+    pub corrupt_files: Vec<CorruptFile>,
+}
+
+/// Why the content of a sync file is bad, `None` if it is good.
+fn check_file(sha256: &str, data: &[u8]) -> Option<String> {
+    let hash = format!("{:x}", Sha256::digest(data));
+    if hash != sha256 {
+        return Some(format!("hash mismatch: {}", hash));
+    }
+    fow_tile::validate(data)
+        .err()
+        .map(|error| error.to_string())
+}
+
+/// Re-check every sync file of the user, and every file used by a snapshot of the user must be
+/// there. Nothing is locked while the files are read, so what looks missing is double checked.
+pub async fn scrub(
+    conn: &DatabaseConnection,
+    sync_file_storage: &SyncFileStorage,
+    user: &User,
+    report: &mut Report,
+) -> Result<()> {
+    // files of snapshots created after this are left for the next scrub
+    let mut snapshot_ids_of_file: BTreeMap<String, Vec<i64>> = BTreeMap::new();
+    let snapshots: Vec<(i64, snapshot::SyncFiles)> = snapshot::Entity::find()
+        .select_only()
+        .columns([snapshot::Column::Id, snapshot::Column::SyncFiles])
+        .filter(snapshot::Column::UserId.eq(user.uid))
+        .order_by_asc(snapshot::Column::Id)
+        .into_tuple()
+        .all(conn)
+        .await?;
+    for (snapshot_id, sync_files) in snapshots {
+        let sha256s: HashSet<String> = sync_files.0.into_values().collect();
+        for sha256 in sha256s {
+            snapshot_ids_of_file
+                .entry(sha256)
+                .or_default()
+                .push(snapshot_id);
+        }
+    }
+
+    // `(sha-256, reason)`
+    let mut corrupt_files = Vec::new();
+    let mut present = HashSet::new();
+    for file in sync_file_storage.list_files(user).await? {
+        let data = match sync_file_storage.read_file(user, &file.key).await {
+            Ok(data) => data,
+            // removed by GC since we listed it
+            Err(_) if !sync_file_storage.has_file(user, &file.key).await? => continue,
+            Err(error) => return Err(error),
+        };
+        report.checked_files += 1;
+        report.checked_bytes += data.len() as u64;
+        if let Some(reason) = check_file(&file.key, &data) {
+            corrupt_files.push((file.key.clone(), reason));
+        }
+        present.insert(file.key);
+    }
+
+    let missing: Vec<String> = snapshot_ids_of_file
+        .keys()
+        .filter(|sha256| !present.contains(*sha256))
+        .cloned()
+        .collect();
+    if !missing.is_empty() {
+        // the snapshots might have been deleted since we looked, and GC removed their files
+        let snapshot_ids: HashSet<i64> = snapshot::Entity::find()
+            .select_only()
+            .column(snapshot::Column::Id)
+            .filter(
+                snapshot::Column::Id.is_in(
+                    missing
+                        .iter()
+                        .flat_map(|sha256| snapshot_ids_of_file[sha256].iter().copied()),
+                ),
+            )
+            .into_tuple()
+            .all(conn)
+            .await?
+            .into_iter()
+            .collect();
+        for sha256 in missing {
+            let ids = snapshot_ids_of_file.get_mut(&sha256).unwrap();
+            ids.retain(|id| snapshot_ids.contains(id));
+            if !ids.is_empty() && !sync_file_storage.has_file(user, &sha256).await? {
+                corrupt_files.push((sha256, "missing".into()));
+            }
+        }
+    }
+
+    // the quota is checked against the records. They are briefly off while a sync is saving
+    // files, otherwise GC should have put them right.
+    let recorded = sync_blob::usage(conn, user).await?;
+    let stored = sync_file_storage.usage(user).await?;
+    if recorded != stored {
+        warn!(
+            "[sync_file_scrub] user {} has {} bytes of sync files, but {} bytes are recorded",
+            user.uid, stored, recorded
+        );
+    }
+
+    for (sha256, reason) in corrupt_files {
+        report.corrupt_files.push(CorruptFile {
+            user_id: user.uid,
+            snapshot_ids: snapshot_ids_of_file.remove(&sha256).unwrap_or_default(),
+            sha256,
+            reason,
+        });
+    }
+    Ok(())
+}
+
+/// `scrub` every user that has files, errors are logged and don't stop the others.
+pub async fn scrub_all(conn: &DatabaseConnection, sync_file_storage: &SyncFileStorage) -> Report {
+    let mut report = Report::default();
+    match sync_file_storage.list_users().await {
+        Err(error) => error!("[sync_file_scrub] failed to list users: {}", error),
+        Ok(users) => {
+            for user in users {
+                if let Err(error) = scrub(conn, sync_file_storage, &user, &mut report).await {
+                    error!("[sync_file_scrub] failed for user {}: {}", user.uid, error);
+                }
+            }
+        }
+    }
+    report
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct BackgroundScrubStatus {
+    pub started_at: DateTime<Utc>,
+    /// `None` while it is running
+    pub finished_at: Option<DateTime<Utc>>,
+    pub report: Option<Report>,
+}
+
+/// `scrub_all` started from the admin API, it takes too long to wait for. Only one runs at a time.
+#[derive(Clone, Default)]
+pub struct BackgroundScrub(Arc<Mutex<Option<BackgroundScrubStatus>>>);
+
+impl BackgroundScrub {
+    /// `false` if one is already running.
+    pub fn start(&self, conn: DatabaseConnection, sync_file_storage: SyncFileStorage) -> bool {
+        {
+            let mut status = self.0.lock().unwrap();
+            if status
+                .as_ref()
+                .is_some_and(|status| status.finished_at.is_none())
+            {
+                return false;
+            }
+            *status = Some(BackgroundScrubStatus {
+                started_at: Utc::now(),
+                finished_at: None,
+                report: None,
+            });
+        }
+        let this = self.clone();
+        tokio::spawn(async move {
+            let report = scrub_all(&conn, &sync_file_storage).await;
+            if let Some(status) = this.0.lock().unwrap().as_mut() {
+                status.finished_at = Some(Utc::now());
+                status.report = Some(report);
+            }
+        });
+        true
+    }
+
+    /// The last one, `None` if there was none.
+    pub fn status(&self) -> Option<BackgroundScrubStatus> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+pub async fn scrub_all_and_log(conn: &DatabaseConnection, sync_file_storage: &SyncFileStorage) {
+    let report = scrub_all(conn, sync_file_storage).await;
+    info!(
+        "[sync_file_scrub] checked {} files ({} bytes)",
+        report.checked_files, report.checked_bytes
+    );
+    for file in report.corrupt_files {
+        error!(
+            "[sync_file_scrub] corrupt file {} of user {}: {}, used by snapshots {:?}",
+            file.sha256, file.user_id, file.reason, file.snapshot_ids
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::needs_database;
+    use std::collections::HashMap;
+    use std::fs;
+
+    #[tokio::test]
+    #[ignore = needs_database!()]
+    async fn test_scrub() {
+        let server = crate::test_utils::TestServer::new().await;
+        let storage = &server.sync_file_storage;
+        let user = User { uid: 1 };
+        let tmp_dir = storage.get_tmp_dir().unwrap();
+        let mut files = Vec::new();
+        for (name, content) in [
+            (
+                "good",
+                fs::read("../editor/src/__tests__/data/23e4lltkkoke").unwrap(),
+            ),
+            ("not_a_tile", b"not a tile".to_vec()),
+            ("rotten", b"rotten".to_vec()),
+        ] {
+            let path = tmp_dir.path().join(name);
+            fs::write(&path, content).unwrap();
+            let sha256 = crate::file_storage::sha256_of_file(&path).unwrap();
+            files.push((sha256, path));
+        }
+        storage.add_files(&user, &files).await.unwrap();
+        let sha256 = |i: usize| files[i].0.clone();
+        fs::write(
+            server.data_dir().join("users/1/sync_files").join(sha256(2)),
+            "bit rot",
+        )
+        .unwrap();
+        let missing = "0".repeat(64);
+        let sync_files = snapshot::SyncFiles(HashMap::from([
+            (1, sha256(0)),
+            (2, sha256(2)),
+            (3, missing.clone()),
+        ]));
+        let snapshot = crate::test_utils::snapshot(&user, sync_files)
+            .insert(&server.conn)
+            .await
+            .unwrap();
+
+        let report = scrub_all(&server.conn, storage).await;
+        assert_eq!(report.checked_files, 3);
+        let mut corrupt_files: Vec<_> = report
+            .corrupt_files
+            .iter()
+            .map(|file| (file.sha256.clone(), file.snapshot_ids.clone()))
+            .collect();
+        corrupt_files.sort();
+        let mut expected = vec![
+            (sha256(1), vec![]),
+            (sha256(2), vec![snapshot.id]),
+            (missing, vec![snapshot.id]),
+        ];
+        expected.sort();
+        assert_eq!(corrupt_files, expected);
+        let reason = |sha256: &str| {
+            report
+                .corrupt_files
+                .iter()
+                .find(|file| file.sha256 == sha256)
+                .unwrap()
+                .reason
+                .clone()
+        };
+        assert!(reason(&sha256(1)).starts_with("invalid zlib data"));
+        assert!(reason(&sha256(2)).starts_with("hash mismatch"));
+    }
+}
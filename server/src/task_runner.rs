@@ -1,26 +1,198 @@
 use crate::data_fetcher;
 use crate::file_storage;
-use crate::pool;
+use crate::limit;
+use crate::notification;
+use crate::pool::Db;
+use crate::schedule;
+use crate::snapshot_stats;
+use crate::sync_blob;
+use crate::sync_file_gc;
+use crate::sync_file_scrub;
 use crate::user_handler;
+use crate::Config;
 use anyhow::Result;
 use chrono::prelude::*;
 use entity::sea_orm;
+use entity::sea_orm::sea_query::{Expr, Query};
 use entity::sea_orm::{entity::*, query::*};
-use entity::{snapshot, snapshot_log, snapshot_task};
-use rocket::{Orbit, Rocket};
-use sea_orm_rocket::Pool;
+use entity::{snapshot, snapshot_job, snapshot_log, snapshot_task, user};
+use futures::FutureExt;
+use rocket::{Orbit, Rocket, Shutdown};
+use sea_orm_rocket::Database;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
 use tokio::task;
 use tokio::time::sleep;
 
+/// How failed syncs are requeued. Errors are retried with exponential backoff and the task is
+/// stopped after `max_errors` errors in a row or on the first permanent error. A locked source is
+/// not an error, FoW will finish syncing eventually, so locked syncs are counted on their own
+/// (`snapshot_task::locked_count`) and never stop the task.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub base_delay: chrono::Duration,
+    pub max_delay: chrono::Duration,
+    pub max_errors: i16,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RetryDecision {
+    RetryAfter(chrono::Duration),
+    Stop,
+}
+
+impl RetryPolicy {
+    pub fn from_config(config: &Config) -> RetryPolicy {
+        RetryPolicy {
+            base_delay: chrono::Duration::minutes(config.sync_retry_base_delay_minutes),
+            max_delay: chrono::Duration::minutes(config.sync_retry_max_delay_minutes),
+            max_errors: config.sync_max_errors,
+        }
+    }
+
+    /// `error_count` includes the current error, for `ErrorClass::Locked` it is the number of
+    /// locked syncs in a row instead.
+    pub fn decide(&self, error_class: data_fetcher::ErrorClass, error_count: i16) -> RetryDecision {
+        use data_fetcher::ErrorClass;
+        if error_class == ErrorClass::Permanent
+            || error_class == ErrorClass::QuotaExceeded
+            || (error_class != ErrorClass::Locked && error_count >= self.max_errors)
+        {
+            return RetryDecision::Stop;
+        }
+        let exponent = (error_count.max(1) - 1).min(16) as u32;
+        let backoff = (self.base_delay * 2_i32.pow(exponent)).min(self.max_delay);
+        match error_class {
+            // the source knows better, but we don't wait forever.
+            ErrorClass::Throttled {
+                retry_after: Some(retry_after),
+            } => RetryDecision::RetryAfter(backoff.max(retry_after.min(self.max_delay))),
+            _ => RetryDecision::RetryAfter(backoff),
+        }
+    }
+}
+
+/// Which snapshot logs of a task are kept. Logs of failed syncs are kept longer since they are
+/// what users look at when something goes wrong.
+#[derive(Clone)]
+pub struct LogRetention {
+    pub keep_succeeded: u64,
+    pub keep_failed: u64,
+    pub max_age_succeeded: chrono::Duration,
+    pub max_age_failed: chrono::Duration,
+}
+
+impl LogRetention {
+    pub fn from_config(config: &Config) -> LogRetention {
+        LogRetention {
+            keep_succeeded: config.snapshot_log_keep_succeeded,
+            keep_failed: config.snapshot_log_keep_failed,
+            max_age_succeeded: chrono::Duration::days(config.snapshot_log_max_age_days_succeeded),
+            max_age_failed: chrono::Duration::days(config.snapshot_log_max_age_days_failed),
+        }
+    }
+}
+
+/// Everything a worker needs other than the database.
+#[derive(Clone)]
+pub struct Context {
+    pub sync_file_storage: file_storage::SyncFileStorage,
+    pub source_options: data_fetcher::SourceOptions,
+    pub limits: limit::Limits,
+    pub retry_policy: RetryPolicy,
+    pub log_retention: LogRetention,
+    pub sync_file_gc_interval: std::time::Duration,
+    pub sync_file_scrub_interval: std::time::Duration,
+    pub notifier: notification::Notifier,
+}
+
+/// Tasks that are being worked on by workers of this server.
+#[derive(Clone, Default)]
+pub struct InFlightTasks(Arc<Mutex<HashSet<i64>>>);
+
+/// Removes the task from `InFlightTasks` when the job is done (or panics).
+pub struct InFlightGuard {
+    tasks: InFlightTasks,
+    task_id: i64,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.tasks.0.lock().unwrap().remove(&self.task_id);
+    }
+}
+
+impl InFlightTasks {
+    /// `None` if the task is already running.
+    pub fn try_start(&self, task_id: i64) -> Option<InFlightGuard> {
+        if self.0.lock().unwrap().insert(task_id) {
+            Some(InFlightGuard {
+                tasks: self.clone(),
+                task_id,
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    /// sorted
+    pub fn task_ids(&self) -> Vec<i64> {
+        let mut task_ids: Vec<i64> = self.0.lock().unwrap().iter().copied().collect();
+        task_ids.sort_unstable();
+        task_ids
+    }
+}
+
+// A worker renews its lease every `LEASE_HEARTBEAT`, so the lease only expires if the worker is
+// gone (or stuck).
+const LEASE_DURATION: chrono::Duration = chrono::Duration::minutes(2);
+const LEASE_HEARTBEAT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// So the shutdown fairing can wait for workers to finish their current job.
+#[derive(Clone, Default)]
+pub struct WorkerHandles(Arc<Mutex<Vec<task::JoinHandle<()>>>>);
+
+impl WorkerHandles {
+    /// Workers stop on their own once shutdown is triggered, this waits for them, up to `timeout`.
+    pub async fn join(&self, timeout: std::time::Duration) {
+        let handles = std::mem::take(&mut *self.0.lock().unwrap());
+        if tokio::time::timeout(timeout, futures::future::join_all(handles))
+            .await
+            .is_err()
+        {
+            warn!("[task_runner] timed out waiting for workers to stop");
+        }
+    }
+}
+
+/// Tasks that are still running on this server are never claimed, even if their lease expired
+/// (e.g. we failed to renew it), so a task doesn't run twice at once.
 pub async fn fetch_and_lock_one_task(
     conn: &sea_orm::DatabaseConnection,
-) -> Result<Option<snapshot_task::Model>> {
+    in_flight_tasks: &InFlightTasks,
+    worker_id: &str,
+) -> Result<Option<(snapshot_task::Model, InFlightGuard)>> {
     let txn = conn.begin().await?;
+    let now = Utc::now();
     let task = snapshot_task::Entity::find()
         .filter(snapshot_task::Column::Status.eq(snapshot_task::Status::Running))
-        .filter(snapshot_task::Column::NextSync.lte(Utc::now()))
+        .filter(snapshot_task::Column::Id.is_not_in(in_flight_tasks.task_ids()))
+        .filter(snapshot_task::Column::NextSync.lte(now))
+        .filter(
+            Condition::any()
+                .add(snapshot_task::Column::LeaseExpiresAt.is_null())
+                .add(snapshot_task::Column::LeaseExpiresAt.lt(now)),
+        )
         .order_by_asc(snapshot_task::Column::NextSync)
-        .lock_exclusive()
+        // other workers just move on to the next task instead of waiting for this one.
+        .lock_with_behavior(
+            sea_orm::sea_query::LockType::Update,
+            sea_orm::sea_query::LockBehavior::SkipLocked,
+        )
         .one(&txn)
         .await?;
     match task {
@@ -30,82 +202,473 @@ pub async fn fetch_and_lock_one_task(
             Ok(None)
         }
         Some(task) => {
+            // another worker of ours might have started it after we listed in-flight tasks.
+            let in_flight_guard = match in_flight_tasks.try_start(task.id) {
+                None => {
+                    txn.rollback().await?;
+                    return Ok(None);
+                }
+                Some(guard) => guard,
+            };
+            if let Some(lease_owner) = &task.lease_owner {
+                info!(
+                    "[task_runner] reclaiming expired lease of task {} from {}",
+                    task.id, lease_owner
+                );
+            }
             snapshot_task::Entity::update(snapshot_task::ActiveModel {
-                user_id: Set(task.user_id),
-                // we "lock" a task by setting its `next_sync` to 20 mins later
-                // if the job succeed, we will update the `next_sync` agian, if not
-                // we will retry it 20 mins later.
-                // Note that the lock is not super safe, user can reset it, but it
-                // is good enough.
-                next_sync: Set(Utc::now() + chrono::Duration::minutes(20)),
+                id: Set(task.id),
+                lease_owner: Set(Some(worker_id.to_string())),
+                lease_expires_at: Set(Some(now + LEASE_DURATION)),
                 ..Default::default()
             })
             .exec(&txn)
             .await?;
+            // jobs requested so far are handled by this run, including the ones of a run that
+            // lost its lease.
+            snapshot_job::Entity::update_many()
+                .col_expr(
+                    snapshot_job::Column::Status,
+                    Expr::value(snapshot_job::Status::Running),
+                )
+                .filter(snapshot_job::Column::SnapshotTaskId.eq(task.id))
+                .filter(snapshot_job::Column::Status.ne(snapshot_job::Status::Done))
+                .exec(&txn)
+                .await?;
             txn.commit().await?;
-            Ok(Some(task))
+            Ok(Some((task, in_flight_guard)))
         }
     }
 }
 
-// error returned here are internal error, job error is handled within this function
+/// `false` if the lease is no longer ours.
+async fn renew_lease(
+    conn: &sea_orm::DatabaseConnection,
+    task_id: i64,
+    worker_id: &str,
+) -> Result<bool> {
+    let res = snapshot_task::Entity::update_many()
+        .col_expr(
+            snapshot_task::Column::LeaseExpiresAt,
+            Expr::value(Utc::now() + LEASE_DURATION),
+        )
+        .filter(snapshot_task::Column::Id.eq(task_id))
+        .filter(snapshot_task::Column::LeaseOwner.eq(worker_id))
+        .exec(conn)
+        .await?;
+    Ok(res.rows_affected > 0)
+}
+
+async fn release_lease<C: sea_orm::ConnectionTrait>(
+    conn: &C,
+    task_id: i64,
+    worker_id: &str,
+) -> Result<()> {
+    snapshot_task::Entity::update_many()
+        .col_expr(
+            snapshot_task::Column::LeaseOwner,
+            Expr::value(Option::<String>::None),
+        )
+        .col_expr(
+            snapshot_task::Column::LeaseExpiresAt,
+            Expr::value(Option::<DateTime<Utc>>::None),
+        )
+        .filter(snapshot_task::Column::Id.eq(task_id))
+        .filter(snapshot_task::Column::LeaseOwner.eq(worker_id))
+        .exec(conn)
+        .await?;
+    Ok(())
+}
+
+/// Logs that jobs point to are kept, so the result of a requested sync can still be found.
+async fn prune_logs(
+    txn: &sea_orm::DatabaseTransaction,
+    retention: &LogRetention,
+    task_id: i64,
+) -> Result<()> {
+    let now = Utc::now();
+    let logs_of_jobs = Query::select()
+        .column(snapshot_job::Column::SnapshotLogId)
+        .from(snapshot_job::Entity)
+        .and_where(snapshot_job::Column::SnapshotTaskId.eq(task_id))
+        .and_where(snapshot_job::Column::SnapshotLogId.is_not_null())
+        .to_owned();
+    for (succeed, keep, max_age) in [
+        (true, retention.keep_succeeded, retention.max_age_succeeded),
+        (false, retention.keep_failed, retention.max_age_failed),
+    ] {
+        let most_recent_log_to_delete = snapshot_log::Entity::find()
+            .filter(snapshot_log::Column::SnapshotTaskId.eq(task_id))
+            .filter(snapshot_log::Column::Succeed.eq(succeed))
+            .order_by_desc(snapshot_log::Column::Id)
+            .offset(keep)
+            .one(txn)
+            .await?;
+        let mut condition = Condition::any().add(snapshot_log::Column::Timestamp.lt(now - max_age));
+        if let Some(log) = most_recent_log_to_delete {
+            condition = condition.add(snapshot_log::Column::Id.lte(log.id));
+        }
+        snapshot_log::Entity::delete_many()
+            .filter(snapshot_log::Column::SnapshotTaskId.eq(task_id))
+            .filter(snapshot_log::Column::Succeed.eq(succeed))
+            .filter(condition)
+            .filter(snapshot_log::Column::Id.not_in_subquery(logs_of_jobs.clone()))
+            .exec(txn)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Logs are pruned when a task syncs, so logs of deleted tasks are removed separately.
+async fn prune_logs_of_deleted_tasks(conn: &sea_orm::DatabaseConnection) -> Result<u64> {
+    let res = snapshot_log::Entity::delete_many()
+        .filter(snapshot_log::Column::SnapshotTaskId.is_not_null())
+        .filter(
+            snapshot_log::Column::SnapshotTaskId.not_in_subquery(
+                Query::select()
+                    .column(snapshot_task::Column::Id)
+                    .from(snapshot_task::Entity)
+                    .to_owned(),
+            ),
+        )
+        .exec(conn)
+        .await?;
+    Ok(res.rows_affected)
+}
+
+async fn insert_log(
+    txn: &sea_orm::DatabaseTransaction,
+    retention: &LogRetention,
+    task: &snapshot_task::Model,
+    snapshot_id: Option<i64>,
+    succeed: bool,
+    report: snapshot_log::Report,
+) -> Result<i64> {
+    let log = snapshot_log::ActiveModel {
+        id: NotSet,
+        user_id: Set(task.user_id),
+        snapshot_task_id: Set(Some(task.id)),
+        snapshot_id: Set(snapshot_id),
+        timestamp: Set(Utc::now()),
+        succeed: Set(succeed),
+        details: Set(report.to_string()),
+        report: Set(Some(report)),
+    }
+    .insert(txn)
+    .await?;
+
+    prune_logs(txn, retention, task.id).await?;
+    Ok(log.id)
+}
+
+/// Marks jobs handled by this run as done. Jobs requested while we were syncing are left for the
+/// next run, which is moved closer if needed.
+async fn finish_jobs(
+    txn: &sea_orm::DatabaseTransaction,
+    task_id: i64,
+    snapshot_log_id: Option<i64>,
+) -> Result<()> {
+    let now = Utc::now();
+    snapshot_job::Entity::update_many()
+        .col_expr(
+            snapshot_job::Column::Status,
+            Expr::value(snapshot_job::Status::Done),
+        )
+        .col_expr(snapshot_job::Column::FinishedAt, Expr::value(now))
+        .col_expr(
+            snapshot_job::Column::SnapshotLogId,
+            Expr::value(snapshot_log_id),
+        )
+        .filter(snapshot_job::Column::SnapshotTaskId.eq(task_id))
+        .filter(snapshot_job::Column::Status.eq(snapshot_job::Status::Running))
+        .exec(txn)
+        .await?;
+    let queued_jobs = snapshot_job::Entity::find()
+        .filter(snapshot_job::Column::SnapshotTaskId.eq(task_id))
+        .filter(snapshot_job::Column::Status.eq(snapshot_job::Status::Queued))
+        .count(txn)
+        .await?;
+    if queued_jobs > 0 {
+        // same as the minimum spacing between syncs in `snapshot_task_handler`
+        let next_sync = now + chrono::Duration::minutes(20);
+        snapshot_task::Entity::update_many()
+            .col_expr(snapshot_task::Column::NextSync, Expr::value(next_sync))
+            .filter(snapshot_task::Column::Id.eq(task_id))
+            .filter(snapshot_task::Column::NextSync.gt(next_sync))
+            .exec(txn)
+            .await?;
+    }
+    Ok(())
+}
+
+// Error returned here are internal error, job error is handled within this function.
+// A job uses separate short transactions for claiming the task (`fetch_and_lock_one_task`),
+// renewing the lease and saving the result. Nothing is held while talking to the source or
+// reading the sync file storage, the result is checked against `sync_blob` records instead.
 pub async fn do_one_task(
     conn: &sea_orm::DatabaseConnection,
-    sync_file_storage: &file_storage::SyncFileStorage,
+    context: &Context,
+    in_flight_tasks: &InFlightTasks,
+    worker_id: &str,
+    shutdown: &Shutdown,
 ) -> Result<()> {
-    match fetch_and_lock_one_task(conn).await? {
+    match fetch_and_lock_one_task(conn, in_flight_tasks, worker_id).await? {
         None => {
-            sleep(std::time::Duration::from_secs(30)).await;
+            tokio::select! {
+                _ = sleep(std::time::Duration::from_secs(30)) => (),
+                _ = shutdown.clone() => (),
+            }
         }
-        Some(task) => {
-            info!("[task_runner] task for user {}", task.user_id);
+        Some((task, _in_flight_guard)) => {
+            info!(
+                "[task_runner] task {} for user {}, in flight: {}",
+                task.id,
+                task.user_id,
+                in_flight_tasks.count()
+            );
             let user = user_handler::User { uid: task.user_id };
-            let snapshot_result =
-                data_fetcher::snapshot(&task.source, &user, sync_file_storage).await;
+            let quota_left = sync_blob::quota_left(conn, &context.limits, &user).await?;
+            let snapshot = data_fetcher::snapshot(
+                &task.source,
+                task.sync_state.as_ref(),
+                &user,
+                &context.sync_file_storage,
+                &context.source_options,
+                &context.limits,
+                quota_left,
+            );
+            tokio::pin!(snapshot);
+            let mut heartbeat = tokio::time::interval(LEASE_HEARTBEAT);
+            // the first tick completes immediately
+            heartbeat.tick().await;
+            let mut snapshot_result = loop {
+                tokio::select! {
+                    snapshot_result = &mut snapshot => break snapshot_result,
+                    _ = heartbeat.tick() => {
+                        match renew_lease(conn, task.id, worker_id).await {
+                            Ok(true) => (),
+                            Ok(false) => {
+                                // someone else owns the task now, dropping the job cancels it.
+                                info!(
+                                    "[task_runner] lost the lease of task {}, job aborted",
+                                    task.id
+                                );
+                                return Ok(());
+                            }
+                            Err(error) => {
+                                warn!("[task_runner] failed to renew lease: {}", error);
+                            }
+                        }
+                    }
+                    _ = shutdown.clone() => {
+                        // Dropping the job cancels it, downloaded files are in a tmp dir so they
+                        // are cleaned up as well. `next_sync` is untouched, so the task will be
+                        // picked up again after restart.
+                        info!(
+                            "[task_runner] job of task {} interrupted by shutdown",
+                            task.id
+                        );
+                        let txn = conn.begin().await?;
+                        let log_id = insert_log(
+                            &txn,
+                            &context.log_retention,
+                            &task,
+                            None,
+                            false,
+                            snapshot_log::Report::new(snapshot_log::Outcome::Interrupted),
+                        )
+                        .await?;
+                        finish_jobs(&txn, task.id, Some(log_id)).await?;
+                        release_lease(&txn, task.id, worker_id).await?;
+                        txn.commit().await?;
+                        return Ok(());
+                    }
+                }
+            };
 
+            // Reading sync files is slow, so it's done before the transaction below, which holds
+            // the lock of the task row and the GC lock of the user. Only files that we don't have
+            // stats of are read, which are mostly the new ones.
+            let blob_stats = match &snapshot_result.result {
+                Ok(data_fetcher::SnapshotOutput::Synced { sync_files, .. }) => {
+                    match snapshot_stats::compute_missing(
+                        conn,
+                        &context.sync_file_storage,
+                        &user,
+                        sync_files,
+                    )
+                    .await
+                    {
+                        Ok(blob_stats) => blob_stats,
+                        Err(error) => {
+                            snapshot_result.fail(
+                                data_fetcher::ErrorClass::Transient,
+                                format!("failed to read sync files: {}", error),
+                            );
+                            HashMap::new()
+                        }
+                    }
+                }
+                _ => HashMap::new(),
+            };
+
+            // set if the sync used files that we have no record of
+            let mut untracked_files = false;
             let txn = conn.begin().await?;
             let current_task = snapshot_task::Entity::find()
                 .filter(snapshot_task::Column::Status.eq(snapshot_task::Status::Running))
-                .filter(snapshot_task::Column::UserId.eq(task.user_id))
+                .filter(snapshot_task::Column::Id.eq(task.id))
                 .lock_exclusive()
                 .one(&txn)
                 .await?;
-            let saved = match current_task {
-                None => false,
+            let mut notification = None;
+            // `None` if the result is discarded
+            let log_id = match current_task {
+                None => None,
                 Some(current_task) => {
-                    if current_task.source != task.source {
-                        false
+                    if current_task.source != task.source
+                        || current_task.lease_owner.as_deref() != Some(worker_id)
+                    {
+                        None
                     } else {
+                        if let Ok(data_fetcher::SnapshotOutput::Synced {
+                            sync_files,
+                            new_files,
+                            ..
+                        }) = &snapshot_result.result
+                        {
+                            // new files are recorded first, then every file must have a record.
+                            // We skip downloading files we already have, but GC might have
+                            // removed them since then.
+                            sync_file_gc::lock_shared(&txn, &user).await?;
+                            // new files that don't fit are left for GC to remove
+                            let error =
+                                match sync_blob::add_files(&txn, &user, new_files, &context.limits)
+                                    .await
+                                {
+                                    Ok(()) => {
+                                        match sync_blob::find_missing(
+                                            &txn,
+                                            &user,
+                                            sync_files.0.values(),
+                                        )
+                                        .await?
+                                        {
+                                            None => None,
+                                            Some(sha256) => {
+                                                untracked_files = true;
+                                                Some((
+                                                    data_fetcher::ErrorClass::Transient,
+                                                    format!(
+                                                        "sync file removed while syncing: {}",
+                                                        sha256
+                                                    ),
+                                                ))
+                                            }
+                                        }
+                                    }
+                                    Err(error) if error.is::<limit::QuotaExceeded>() => Some((
+                                        data_fetcher::ErrorClass::QuotaExceeded,
+                                        error.to_string(),
+                                    )),
+                                    Err(error) => return Err(error),
+                                };
+                            if let Some((class, message)) = error {
+                                snapshot_result.fail(class, message);
+                            }
+                        }
                         let (succeed, snapshot_id) = match snapshot_result.result {
-                            Err(()) => {
-                                let error_count = current_task.error_count + 1;
+                            Err(error_class) => {
+                                let locked = error_class == data_fetcher::ErrorClass::Locked;
+                                let (error_count, locked_count) = if locked {
+                                    (
+                                        current_task.error_count,
+                                        current_task.locked_count.saturating_add(1),
+                                    )
+                                } else {
+                                    (current_task.error_count.saturating_add(1), 0)
+                                };
+                                let (status, next_sync, event) = match context.retry_policy.decide(
+                                    error_class,
+                                    if locked { locked_count } else { error_count },
+                                ) {
+                                    RetryDecision::RetryAfter(delay) => {
+                                        snapshot_result.report.retry =
+                                            Some(snapshot_log::Retry::After {
+                                                minutes: delay.num_minutes(),
+                                            });
+                                        let event = (!locked
+                                            && error_count == notification::NOTIFY_AFTER_ERRORS)
+                                            .then_some(notification::Event::SyncFailed);
+                                        (NotSet, Set(Utc::now() + delay), event)
+                                    }
+                                    RetryDecision::Stop => {
+                                        snapshot_result.report.retry =
+                                            Some(snapshot_log::Retry::Stopped);
+                                        let event = if error_class
+                                            == data_fetcher::ErrorClass::QuotaExceeded
+                                        {
+                                            notification::Event::QuotaExceeded
+                                        } else {
+                                            notification::Event::TaskStopped
+                                        };
+                                        (Set(snapshot_task::Status::Stopped), NotSet, Some(event))
+                                    }
+                                };
+                                notification = event.map(|event| (event, error_count));
                                 snapshot_task::Entity::update(snapshot_task::ActiveModel {
-                                    user_id: Set(task.user_id),
-                                    status: if error_count >= 3 {
-                                        Set(snapshot_task::Status::Stopped)
-                                    } else {
-                                        NotSet
-                                    },
+                                    id: Set(task.id),
+                                    status,
+                                    next_sync,
                                     error_count: Set(error_count),
+                                    locked_count: Set(locked_count),
                                     ..Default::default()
                                 })
                                 .exec(&txn)
                                 .await?;
                                 (false, None)
                             }
-                            Ok((sync_files, snapshot_time)) => {
+                            Ok(data_fetcher::SnapshotOutput::Unchanged) => {
                                 snapshot_task::Entity::update(snapshot_task::ActiveModel {
-                                    user_id: Set(task.user_id),
-                                    next_sync: Set(Utc::now()
-                                        + chrono::Duration::minutes(current_task.interval.into())),
+                                    id: Set(task.id),
+                                    next_sync: Set(schedule::next_sync(
+                                        current_task.interval,
+                                        current_task.schedule.as_ref(),
+                                        task.next_sync,
+                                        Utc::now(),
+                                    )),
                                     error_count: Set(0),
+                                    locked_count: Set(0),
+                                    ..Default::default()
+                                })
+                                .exec(&txn)
+                                .await?;
+                                (true, None)
+                            }
+                            Ok(data_fetcher::SnapshotOutput::Synced {
+                                sync_files,
+                                time: snapshot_time,
+                                sync_state,
+                                ..
+                            }) => {
+                                snapshot_task::Entity::update(snapshot_task::ActiveModel {
+                                    id: Set(task.id),
+                                    next_sync: Set(schedule::next_sync(
+                                        current_task.interval,
+                                        current_task.schedule.as_ref(),
+                                        task.next_sync,
+                                        Utc::now(),
+                                    )),
+                                    error_count: Set(0),
+                                    locked_count: Set(0),
+                                    sync_state: Set(Some(sync_state)),
                                     ..Default::default()
                                 })
                                 .exec(&txn)
                                 .await?;
 
                                 let last_snapshot = snapshot::Entity::find()
-                                    .filter(snapshot::Column::UserId.eq(task.user_id))
+                                    .filter(snapshot::Column::SnapshotTaskId.eq(task.id))
                                     .order_by_desc(snapshot::Column::Timestamp)
                                     .one(&txn)
                                     .await?;
@@ -116,13 +679,22 @@ pub async fn do_one_task(
                                 };
 
                                 if changed {
+                                    sync_blob::add_references(&txn, &user, &sync_files).await?;
+                                    snapshot_stats::set_blob_stats(&txn, &user, &blob_stats)
+                                        .await?;
+                                    let stats =
+                                        snapshot_stats::sum(&txn, &user, &sync_files).await?;
                                     let snapshot = snapshot::ActiveModel {
                                         id: NotSet,
                                         user_id: Set(task.user_id),
                                         timestamp: Set(snapshot_time),
                                         sync_files: Set(sync_files),
                                         source_kind: Set(snapshot::SourceKind::Sync),
+                                        snapshot_task_id: Set(Some(task.id)),
                                         note: Set(None),
+                                        ..stats
+                                            .map(|stats| stats.to_active_model())
+                                            .unwrap_or_default()
                                     }
                                     .insert(&txn)
                                     .await?;
@@ -132,73 +704,516 @@ pub async fn do_one_task(
                                 }
                             }
                         };
-                        snapshot_log::ActiveModel {
-                            id: NotSet,
-                            user_id: Set(task.user_id),
-                            snapshot_id: Set(snapshot_id),
-                            timestamp: Set(Utc::now()),
-                            succeed: Set(succeed),
-                            details: Set(snapshot_result.logs.join("\n")),
-                        }
-                        .insert(&txn)
+                        let notification =
+                            notification.map(|(event, error_count)| notification::Notification {
+                                event,
+                                snapshot_task_id: task.id,
+                                task_name: current_task.name.clone(),
+                                error_count,
+                                details: snapshot_result.report.to_string(),
+                                timestamp: Utc::now(),
+                            });
+                        let log_id = insert_log(
+                            &txn,
+                            &context.log_retention,
+                            &task,
+                            snapshot_id,
+                            succeed,
+                            snapshot_result.report,
+                        )
                         .await?;
-
-                        // only keep recent 10 logs
-                        let most_recent_log_to_delete = snapshot_log::Entity::find()
-                            .filter(snapshot_log::Column::UserId.eq(task.user_id))
-                            .order_by_desc(snapshot_log::Column::Timestamp)
-                            .offset(10)
-                            .one(&txn)
-                            .await?;
-                        match most_recent_log_to_delete {
-                            None => (),
-                            Some(most_recent_log_to_delete) => {
-                                snapshot_log::Entity::delete_many()
-                                    .filter(snapshot_log::Column::UserId.eq(task.user_id))
-                                    .filter(
-                                        snapshot_log::Column::Timestamp
-                                            .lte(most_recent_log_to_delete.timestamp),
-                                    )
-                                    .exec(&txn)
-                                    .await?;
-                            }
-                        }
-
-                        true
+                        Some((log_id, notification))
                     }
                 }
             };
-            if !saved {
-                info!("snapshot task done but discarded, uid: {}", task.user_id);
-            }
+            let (log_id, notification) = match log_id {
+                None => {
+                    info!("snapshot task done but discarded, task: {}", task.id);
+                    (None, None)
+                }
+                Some((log_id, notification)) => (Some(log_id), notification),
+            };
+            finish_jobs(&txn, task.id, log_id).await?;
+            release_lease(&txn, task.id, worker_id).await?;
             txn.commit().await?;
+
+            // Files in the storage without a record are left by syncs and uploads that didn't
+            // finish. GC either records them or removes them, so the retry can succeed.
+            if untracked_files {
+                sync_file_gc::collect_and_log(conn, &context.sync_file_storage, &user).await;
+            }
+
+            if let Some(notification) = notification {
+                if let Some(user) = user::Entity::find_by_id(task.user_id).one(conn).await? {
+                    context.notifier.notify(&user, &notification).await;
+                }
+            }
         }
     }
     Ok(())
 }
 
-pub async fn run(rocket: &Rocket<Orbit>, sync_file_storage: file_storage::SyncFileStorage) {
-    // TODO: it is a terrible idea to create another connection pool for the background job.
-    // I could get the main pool by `let conn = Db::fetch(&rocket).unwrap().conn.clone();`
-    // But somehow I encounter race condition with that (having a chance raising errors like
-    // incorrect binary data format in bind parameter 1).
-    // I'll revist this later and maybe report this to upstream if it is a bug. We don't care
-    // about performance at the moment.
-    let db = pool::SeaOrmPool::init(&rocket.figment().focus("databases.main"))
-        .await
-        .unwrap();
-    task::spawn(async move {
-        // TODO: run job in parallel
-        sleep(std::time::Duration::from_secs(10)).await;
+pub async fn run(
+    rocket: &Rocket<Orbit>,
+    context: Context,
+    workers: usize,
+    in_flight_tasks: InFlightTasks,
+    worker_handles: WorkerHandles,
+) {
+    let shutdown = rocket.shutdown();
+    // Workers share the main pool with the API. A job never holds a transaction while syncing,
+    // see `do_one_task`, so a slow source doesn't hold a connection or row locks for long.
+    // The "incorrect binary data format in bind parameter" errors we used to get with a shared
+    // pool come from sqlx caching prepared statements per connection by their SQL only: the
+    // parameter types of the first use are kept, so running the same query with e.g. an `i32`
+    // where it was prepared with an `i64` fails. Values must always be bound with the type of
+    // their column (`i64` for ids).
+    let conn = Db::fetch(rocket).unwrap().conn.clone();
+    // unique across servers and restarts, so a restarted server doesn't think it owns leases of
+    // the previous run.
+    let instance_id: u32 = rand::random();
+    for worker_index in 0..workers.max(1) {
+        let worker_id = format!("{:08x}-{}", instance_id, worker_index);
+        let conn = conn.clone();
+        let context = context.clone();
+        let in_flight_tasks = in_flight_tasks.clone();
+        let shutdown = shutdown.clone();
+        let handle = task::spawn(async move {
+            // spread out the workers a bit
+            tokio::select! {
+                _ = sleep(std::time::Duration::from_secs(10 + worker_index as u64)) => (),
+                _ = shutdown.clone() => (),
+            }
 
-        loop {
-            match do_one_task(&db.conn, &sync_file_storage).await {
-                Ok(()) => (),
-                Err(error) => {
-                    error!("internal error: {}", error);
-                    sleep(std::time::Duration::from_secs(60)).await;
+            // stop claiming new tasks once shutdown is triggered
+            while shutdown.clone().now_or_never().is_none() {
+                match do_one_task(&conn, &context, &in_flight_tasks, &worker_id, &shutdown).await {
+                    Ok(()) => (),
+                    Err(error) => {
+                        error!(
+                            "[task_runner] worker {} internal error: {}",
+                            worker_id, error
+                        );
+                        tokio::select! {
+                            _ = sleep(std::time::Duration::from_secs(60)) => (),
+                            _ = shutdown.clone() => (),
+                        }
+                    }
                 }
             }
+            info!("[task_runner] worker {} stopped", worker_id);
+        });
+        worker_handles.0.lock().unwrap().push(handle);
+    }
+
+    let handle = task::spawn({
+        let conn = conn.clone();
+        let context = context.clone();
+        let shutdown = shutdown.clone();
+        async move {
+            snapshot_stats::backfill(&conn, &context.sync_file_storage).await;
+            loop {
+                match prune_logs_of_deleted_tasks(&conn).await {
+                    Ok(0) => (),
+                    Ok(removed) => info!("[task_runner] removed {} logs of deleted tasks", removed),
+                    Err(error) => error!("[task_runner] failed to prune logs: {}", error),
+                }
+                sync_file_gc::collect_all(&conn, &context.sync_file_storage).await;
+                tokio::select! {
+                    _ = sleep(context.sync_file_gc_interval) => (),
+                    _ = shutdown.clone() => break,
+                }
+            }
+            info!("[task_runner] sync file gc stopped");
         }
     });
+    worker_handles.0.lock().unwrap().push(handle);
+
+    let handle = task::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = sleep(context.sync_file_scrub_interval) => (),
+                _ = shutdown.clone() => break,
+            }
+            sync_file_scrub::scrub_all_and_log(&conn, &context.sync_file_storage).await;
+        }
+        info!("[task_runner] sync file scrub stopped");
+    });
+    worker_handles.0.lock().unwrap().push(handle);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{self, needs_database};
+    use chrono::Duration;
+    use data_fetcher::ErrorClass;
+
+    #[test]
+    fn test_in_flight_tasks() {
+        let in_flight_tasks = InFlightTasks::default();
+        let guard = in_flight_tasks.try_start(1).unwrap();
+        assert!(in_flight_tasks.try_start(1).is_none());
+        let _other = in_flight_tasks.try_start(2).unwrap();
+        assert_eq!(in_flight_tasks.count(), 2);
+        assert_eq!(in_flight_tasks.task_ids(), vec![1, 2]);
+        drop(guard);
+        assert_eq!(in_flight_tasks.count(), 1);
+        assert!(in_flight_tasks.try_start(1).is_some());
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let policy = RetryPolicy {
+            base_delay: Duration::minutes(2),
+            max_delay: Duration::hours(1),
+            max_errors: 8,
+        };
+        let retry_after = |minutes| RetryDecision::RetryAfter(Duration::minutes(minutes));
+        assert_eq!(policy.decide(ErrorClass::Transient, 1), retry_after(2));
+        assert_eq!(policy.decide(ErrorClass::Transient, 3), retry_after(8));
+        assert_eq!(policy.decide(ErrorClass::Locked, 2), retry_after(4));
+        // locked for days
+        assert_eq!(policy.decide(ErrorClass::Locked, 100), retry_after(60));
+        assert_eq!(policy.decide(ErrorClass::Transient, 7), retry_after(60));
+        assert_eq!(policy.decide(ErrorClass::Transient, 8), RetryDecision::Stop);
+        assert_eq!(policy.decide(ErrorClass::Permanent, 1), RetryDecision::Stop);
+        assert_eq!(
+            policy.decide(ErrorClass::QuotaExceeded, 1),
+            RetryDecision::Stop
+        );
+        assert_eq!(
+            policy.decide(
+                ErrorClass::Throttled {
+                    retry_after: Some(Duration::minutes(30))
+                },
+                1
+            ),
+            retry_after(30)
+        );
+        assert_eq!(
+            policy.decide(
+                ErrorClass::Throttled {
+                    retry_after: Some(Duration::days(1))
+                },
+                1
+            ),
+            retry_after(60)
+        );
+        assert_eq!(
+            policy.decide(ErrorClass::Throttled { retry_after: None }, 2),
+            retry_after(4)
+        );
+    }
+
+    // Workers and API requests used to have their own pools, this makes sure they can share one.
+    #[tokio::test]
+    #[ignore = needs_database!()]
+    async fn test_workers_and_api_share_one_pool() {
+        use rocket::http::Status;
+
+        let server = crate::test_utils::TestServer::new().await;
+        let conn = &server.conn;
+        let client = &server.client;
+        let user_ids: Vec<i64> = (1000..1016).collect();
+        for user_id in &user_ids {
+            test_utils::snapshot_task(*user_id, server.source_of(*user_id))
+                .insert(conn)
+                .await
+                .unwrap();
+        }
+
+        let shutdown = client.rocket().shutdown();
+        let context = server.task_runner_context();
+        let in_flight_tasks = InFlightTasks::default();
+        let mut workers = Vec::new();
+        for worker_index in 0..4 {
+            let conn = conn.clone();
+            let context = context.clone();
+            let in_flight_tasks = in_flight_tasks.clone();
+            let shutdown = shutdown.clone();
+            workers.push(task::spawn(async move {
+                let worker_id = format!("test-{}", worker_index);
+                while shutdown.clone().now_or_never().is_none() {
+                    do_one_task(&conn, &context, &in_flight_tasks, &worker_id, &shutdown)
+                        .await
+                        .unwrap();
+                }
+            }));
+        }
+
+        let auth = || server.auth(1);
+        let resp = client
+            .post("/api/v1/snapshot_task")
+            .header(auth())
+            .json(&serde_json::json!({ "name": "test", "interval": 24 * 60, "source": server.source }))
+            .dispatch()
+            .await;
+        assert_eq!(resp.status(), Status::Ok);
+        let task_id = resp.into_json::<serde_json::Value>().await.unwrap()["id"]
+            .as_i64()
+            .unwrap();
+        for interval in [12 * 60, 24 * 60].iter().cycle().take(20) {
+            let get = client
+                .get(format!("/api/v1/snapshot_task/{}", task_id))
+                .header(auth())
+                .dispatch();
+            let update = client
+                .patch(format!("/api/v1/snapshot_task/{}", task_id))
+                .header(auth())
+                .json(&serde_json::json!({ "interval": interval }))
+                .dispatch();
+            let (get, update) = tokio::join!(get, update);
+            assert_eq!(get.status(), Status::Ok);
+            assert_eq!(update.status(), Status::Ok);
+        }
+
+        let deadline = Utc::now() + chrono::Duration::minutes(1);
+        loop {
+            let synced = snapshot_log::Entity::find()
+                .filter(snapshot_log::Column::UserId.is_in(user_ids.clone()))
+                .filter(snapshot_log::Column::Succeed.eq(true))
+                .count(conn)
+                .await
+                .unwrap();
+            if synced == user_ids.len() as u64 {
+                break;
+            }
+            assert!(Utc::now() < deadline, "only {} tasks synced", synced);
+            sleep(std::time::Duration::from_millis(200)).await;
+        }
+        shutdown.notify();
+        for worker in workers {
+            worker.await.unwrap();
+        }
+        assert_eq!(
+            snapshot_task::Entity::find()
+                .filter(snapshot_task::Column::LeaseOwner.is_not_null())
+                .filter(snapshot_task::Column::UserId.is_in(user_ids))
+                .count(conn)
+                .await
+                .unwrap(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = needs_database!()]
+    async fn test_in_flight_task_is_not_claimed() {
+        let server = crate::test_utils::TestServer::new().await;
+        let task = snapshot_task::ActiveModel {
+            // e.g. we failed to renew it
+            lease_owner: Set(Some("test-0".into())),
+            lease_expires_at: Set(Some(Utc::now() - chrono::Duration::minutes(1))),
+            ..test_utils::snapshot_task(1, server.source.clone())
+        }
+        .insert(&server.conn)
+        .await
+        .unwrap();
+
+        let in_flight_tasks = InFlightTasks::default();
+        let guard = in_flight_tasks.try_start(task.id).unwrap();
+        assert!(
+            fetch_and_lock_one_task(&server.conn, &in_flight_tasks, "test-1")
+                .await
+                .unwrap()
+                .is_none()
+        );
+        let lease_owner = snapshot_task::Entity::find_by_id(task.id)
+            .one(&server.conn)
+            .await
+            .unwrap()
+            .unwrap()
+            .lease_owner;
+        assert_eq!(lease_owner.as_deref(), Some("test-0"));
+
+        drop(guard);
+        let (claimed, _guard) = fetch_and_lock_one_task(&server.conn, &in_flight_tasks, "test-1")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(claimed.id, task.id);
+        assert_eq!(in_flight_tasks.task_ids(), vec![task.id]);
+    }
+
+    // A task that keeps failing is stopped in the end, and the user is told about it.
+    #[tokio::test]
+    #[ignore = needs_database!()]
+    async fn test_notify_when_task_stopped() {
+        use crate::test_utils::{SmtpStandIn, TestServer};
+
+        let server = TestServer::new().await;
+        let smtp = SmtpStandIn::start().await;
+        let context = Context {
+            notifier: notification::Notifier::new(
+                Some(&smtp.url()),
+                Some("Fog Machine <noreply@example.com>"),
+                "secret",
+                true,
+            )
+            .unwrap(),
+            ..server.task_runner_context()
+        };
+        user::ActiveModel {
+            language: Set(user::Language::ZhCn),
+            ..test_utils::user(1)
+        }
+        .insert(&server.conn)
+        .await
+        .unwrap();
+        let snapshot_task::Source::LocalPath { path } = &server.source else {
+            unreachable!()
+        };
+        let gone = std::path::Path::new(path).parent().unwrap().join("gone");
+        let source = snapshot_task::Source::LocalPath {
+            path: gone.to_str().unwrap().to_string(),
+        };
+        let task = snapshot_task::ActiveModel {
+            error_count: Set(context.retry_policy.max_errors - 1),
+            ..test_utils::snapshot_task(1, source)
+        }
+        .insert(&server.conn)
+        .await
+        .unwrap();
+
+        do_one_task(
+            &server.conn,
+            &context,
+            &InFlightTasks::default(),
+            "test",
+            &server.client.rocket().shutdown(),
+        )
+        .await
+        .unwrap();
+
+        let task = snapshot_task::Entity::find_by_id(task.id)
+            .one(&server.conn)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(task.status, snapshot_task::Status::Stopped);
+        let emails = smtp.emails();
+        assert_eq!(emails.len(), 1);
+        assert_eq!(emails[0].to, vec!["<user@example.com>"]);
+    }
+
+    // FoW can keep the source locked for hours, that shouldn't stop the task.
+    #[tokio::test]
+    #[ignore = needs_database!()]
+    async fn test_locked_does_not_stop_task() {
+        use crate::test_utils::TestServer;
+
+        let server = TestServer::new().await;
+        let context = server.task_runner_context();
+        let snapshot_task::Source::LocalPath { path } = &server.source else {
+            unreachable!()
+        };
+        std::fs::write(
+            std::path::Path::new(path)
+                .join("Sync")
+                .join(data_fetcher::LOCK_FILE_NAME),
+            "locked",
+        )
+        .unwrap();
+        let task = snapshot_task::ActiveModel {
+            error_count: Set(context.retry_policy.max_errors - 1),
+            locked_count: Set(context.retry_policy.max_errors),
+            ..test_utils::snapshot_task(1, server.source.clone())
+        }
+        .insert(&server.conn)
+        .await
+        .unwrap();
+
+        do_one_task(
+            &server.conn,
+            &context,
+            &InFlightTasks::default(),
+            "test",
+            &server.client.rocket().shutdown(),
+        )
+        .await
+        .unwrap();
+
+        let task = snapshot_task::Entity::find_by_id(task.id)
+            .one(&server.conn)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(task.status, snapshot_task::Status::Running);
+        assert_eq!(task.error_count, context.retry_policy.max_errors - 1);
+        assert_eq!(task.locked_count, context.retry_policy.max_errors + 1);
+        assert!(task.next_sync > Utc::now());
+    }
+
+    #[tokio::test]
+    #[ignore = needs_database!()]
+    async fn test_prune_logs() {
+        use crate::test_utils::TestServer;
+
+        let server = TestServer::new().await;
+        let retention = LogRetention {
+            keep_succeeded: 2,
+            keep_failed: 3,
+            max_age_succeeded: Duration::days(30),
+            max_age_failed: Duration::days(90),
+        };
+        // failed logs are kept longer
+        let days_ago = [60, 5, 4, 3, 2, 1, 100, 50, 4];
+        let mut log_ids = Vec::new();
+        for (i, days_ago) in days_ago.into_iter().enumerate() {
+            let log = snapshot_log::ActiveModel {
+                id: NotSet,
+                user_id: Set(1),
+                snapshot_task_id: Set(Some(1)),
+                snapshot_id: Set(None),
+                timestamp: Set(Utc::now() - Duration::days(days_ago)),
+                succeed: Set(i < 6),
+                details: Set(String::new()),
+                report: Set(None),
+            }
+            .insert(&server.conn)
+            .await
+            .unwrap();
+            log_ids.push(log.id);
+        }
+        // the result of a job is kept even if it is too old
+        snapshot_job::ActiveModel {
+            id: NotSet,
+            user_id: Set(1),
+            snapshot_task_id: Set(1),
+            status: Set(snapshot_job::Status::Done),
+            created_at: Set(Utc::now()),
+            finished_at: Set(Some(Utc::now())),
+            snapshot_log_id: Set(Some(log_ids[6])),
+        }
+        .insert(&server.conn)
+        .await
+        .unwrap();
+        let txn = server.conn.begin().await.unwrap();
+        prune_logs(&txn, &retention, 1).await.unwrap();
+        txn.commit().await.unwrap();
+
+        let ages: Vec<(bool, i64)> = snapshot_log::Entity::find()
+            .order_by_asc(snapshot_log::Column::Id)
+            .all(&server.conn)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|log| (log.succeed, (Utc::now() - log.timestamp).num_days()))
+            .collect();
+        assert_eq!(
+            ages,
+            vec![(true, 2), (true, 1), (false, 100), (false, 50), (false, 4)]
+        );
+
+        // there is no task 1
+        assert_eq!(prune_logs_of_deleted_tasks(&server.conn).await.unwrap(), 5);
+        assert_eq!(
+            snapshot_log::Entity::find()
+                .count(&server.conn)
+                .await
+                .unwrap(),
+            0
+        );
+    }
 }
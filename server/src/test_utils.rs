@@ -0,0 +1,393 @@
+// Helpers for tests that need a real database or other services. Tests that need a database are
+// `#[ignore = needs_database!()]`d by default, run them with
+// `TEST_DATABASE_URL=postgres://... cargo test -- --ignored`. The same goes for the ones that need
+// an S3 compatible storage, see `s3_config`.
+// Every `TestServer` creates its own database on that server, so tests don't step on each other.
+
+use crate::pool::Db;
+use crate::user_handler::User;
+use crate::{
+    admin_handler, data_fetcher, file_storage, limit, notification, snapshot_handler,
+    snapshot_log_handler, snapshot_task_handler, sync_file_gc, task_runner, user_handler, Config,
+    ServerState,
+};
+use chrono::Utc;
+use entity::sea_orm::{self, ActiveValue::NotSet, ConnectionTrait, Set};
+use entity::{snapshot, snapshot_task, sync_blob, user};
+use envconfig::Envconfig;
+use migration::MigratorTrait;
+use rocket::http::Header;
+use rocket::local::asynchronous::Client;
+use sea_orm_rocket::Database;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+/// The `#[ignore]` reason of tests that need a database.
+macro_rules! needs_database {
+    () => {
+        "needs a postgres database, e.g. `TEST_DATABASE_URL=postgres://... cargo test -- --ignored`"
+    };
+}
+pub(crate) use needs_database;
+
+/// `Config` with the defaults, fields without a default are made up.
+pub fn config() -> Config {
+    let vars = HashMap::from(
+        [
+            ("DATABASE_URL", ""),
+            ("GITHUB_CLIENT_ID", ""),
+            ("GITHUB_CLIENT_SECRET", ""),
+            ("JWT_SECRET", "secret"),
+            ("CORS_ALLOWED_ORIGINS", "*"),
+            ("DATA_BASE_DIR", ""),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string())),
+    );
+    Config::init_from_hashmap(&vars).unwrap()
+}
+
+/// the default `limit::Limits`, which is also what `TestServer` uses.
+pub fn limits() -> limit::Limits {
+    limit::Limits::from_config(&config())
+}
+
+/// A user with nothing special, override fields with `..user(id)`.
+pub fn user(id: i64) -> user::ActiveModel {
+    user::ActiveModel {
+        id: Set(id),
+        email: Set(None),
+        password: Set(None),
+        contact_email: Set("user@example.com".into()),
+        github_uid: Set(None),
+        language: Set(user::Language::EnUs),
+        notify_by_email: Set(true),
+        notification_webhook_url: Set(None),
+        sync_file_storage_limit_mib: Set(None),
+        created_at: Set(Utc::now()),
+        updated_at: Set(Utc::now()),
+    }
+}
+
+/// A running task that is due now.
+pub fn snapshot_task(user_id: i64, source: snapshot_task::Source) -> snapshot_task::ActiveModel {
+    snapshot_task::ActiveModel {
+        id: NotSet,
+        user_id: Set(user_id),
+        name: Set("phone".into()),
+        status: Set(snapshot_task::Status::Running),
+        interval: Set(24 * 60),
+        source: Set(source),
+        next_sync: Set(Utc::now()),
+        error_count: Set(0),
+        locked_count: Set(0),
+        sync_state: Set(None),
+        lease_owner: Set(None),
+        lease_expires_at: Set(None),
+        schedule: Set(None),
+    }
+}
+
+/// The record of a sync file.
+pub fn sync_blob(user: &User, sha256: &str, size: i64, ref_count: i32) -> sync_blob::ActiveModel {
+    sync_blob::ActiveModel {
+        user_id: Set(user.uid),
+        sha256: Set(sha256.to_string()),
+        size: Set(size),
+        ref_count: Set(ref_count),
+    }
+}
+
+/// An uploaded snapshot taken now, without stats.
+pub fn snapshot(user: &User, sync_files: snapshot::SyncFiles) -> snapshot::ActiveModel {
+    snapshot::ActiveModel {
+        id: NotSet,
+        user_id: Set(user.uid),
+        timestamp: Set(Utc::now()),
+        sync_files: Set(sync_files),
+        source_kind: Set(snapshot::SourceKind::Upload),
+        snapshot_task_id: Set(None),
+        note: Set(None),
+        ..Default::default()
+    }
+}
+
+/// A "Fog of World" folder with one sync file under `<root>/<uid>`.
+fn setup_source(root: &std::path::Path, uid: i64) -> snapshot_task::Source {
+    let fog_of_world = root.join(uid.to_string()).join("Fog of World");
+    std::fs::create_dir_all(fog_of_world.join("Sync")).unwrap();
+    std::fs::copy(
+        "../editor/src/__tests__/data/23e4lltkkoke",
+        fog_of_world.join("Sync").join("23e4lltkkoke"),
+    )
+    .unwrap();
+    snapshot_task::Source::LocalPath {
+        path: fog_of_world.to_str().unwrap().to_string(),
+    }
+}
+
+pub struct TestServer {
+    pub client: Client,
+    pub conn: sea_orm::DatabaseConnection,
+    pub sync_file_storage: file_storage::SyncFileStorage,
+    pub source_options: data_fetcher::SourceOptions,
+    /// a "Fog of World" folder of user 1 with one sync file (`23e4lltkkoke`).
+    pub source: snapshot_task::Source,
+    /// `TEST_DATABASE_URL`, the database of this server is dropped with it.
+    server_database_url: String,
+    database_name: String,
+    _data_dir: tempfile::TempDir,
+    _source_root: tempfile::TempDir,
+}
+
+impl TestServer {
+    pub async fn new() -> TestServer {
+        let server_database_url = std::env::var("TEST_DATABASE_URL").unwrap();
+        let database_name = format!("fog_test_{:016x}", rand::random::<u64>());
+        let server_conn = sea_orm::Database::connect(&server_database_url)
+            .await
+            .unwrap();
+        server_conn
+            .execute_unprepared(&format!("CREATE DATABASE {}", database_name))
+            .await
+            .unwrap();
+        server_conn.close().await.unwrap();
+        let mut database_url = reqwest::Url::parse(&server_database_url).unwrap();
+        database_url.set_path(&database_name);
+
+        let data_dir = tempfile::tempdir().unwrap();
+        let source_root = tempfile::tempdir().unwrap();
+        let source = setup_source(source_root.path(), 1);
+
+        let config = Config {
+            database_url: database_url.to_string(),
+            data_base_dir: data_dir.path().to_str().unwrap().to_string(),
+            local_path_source_root: Some(source_root.path().to_str().unwrap().to_string()),
+            admin_user_ids: "100".into(),
+            ..config()
+        };
+        let figment = rocket::Config::figment().merge((
+            "databases.main",
+            sea_orm_rocket::Config {
+                url: database_url.to_string(),
+                min_connections: None,
+                max_connections: 8,
+                connect_timeout: 3,
+                idle_timeout: None,
+                sqlx_logging: false,
+            },
+        ));
+        let server_state = ServerState::from_config(config);
+        let sync_file_storage = server_state.file_storage.clone();
+        let source_options = server_state.source_options.clone();
+        let rocket = rocket::custom(figment)
+            .attach(Db::init())
+            .manage(server_state)
+            .mount("/api/v1/user", user_handler::routes())
+            .mount("/api/v1/snapshot_task", snapshot_task_handler::routes())
+            .mount("/api/v1/snapshot_log", snapshot_log_handler::routes())
+            .mount("/api/v1/snapshot", snapshot_handler::routes())
+            .mount("/api/v1/admin", admin_handler::routes());
+        let client = Client::untracked(rocket).await.unwrap();
+        let conn = Db::fetch(client.rocket()).unwrap().conn.clone();
+        migration::Migrator::up(&conn, None).await.unwrap();
+        // like the server on its first start, there is nothing to backfill though.
+        sync_file_gc::backfill_all(&conn, &sync_file_storage)
+            .await
+            .unwrap();
+
+        TestServer {
+            client,
+            conn,
+            sync_file_storage,
+            source_options,
+            source,
+            server_database_url,
+            database_name,
+            _data_dir: data_dir,
+            _source_root: source_root,
+        }
+    }
+
+    pub fn data_dir(&self) -> &std::path::Path {
+        self._data_dir.path()
+    }
+
+    fn server_state(&self) -> &ServerState {
+        self.client.rocket().state::<ServerState>().unwrap()
+    }
+
+    pub fn auth(&self, user_id: i64) -> Header<'static> {
+        Header::new(
+            "Authorization",
+            format!(
+                "Bearer {}",
+                user_handler::generate_user_token(self.server_state(), user_id)
+            ),
+        )
+    }
+
+    /// Like `source`, but for user `uid`.
+    pub fn source_of(&self, uid: i64) -> snapshot_task::Source {
+        setup_source(self._source_root.path(), uid)
+    }
+
+    pub fn task_runner_context(&self) -> task_runner::Context {
+        let server_state = self.server_state();
+        let config = &server_state.config;
+        task_runner::Context {
+            sync_file_storage: self.sync_file_storage.clone(),
+            source_options: self.source_options.clone(),
+            limits: server_state.limits.clone(),
+            retry_policy: task_runner::RetryPolicy::from_config(config),
+            log_retention: task_runner::LogRetention::from_config(config),
+            sync_file_gc_interval: std::time::Duration::from_secs(
+                config.sync_file_gc_interval_hours * 60 * 60,
+            ),
+            sync_file_scrub_interval: std::time::Duration::from_secs(
+                config.sync_file_scrub_interval_hours * 60 * 60,
+            ),
+            notifier: notification::Notifier::new(None, None, "secret", true).unwrap(),
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        // `Drop` can't be async and we might be on a single threaded runtime, so the database is
+        // dropped on its own thread. Our connections are still open, hence `FORCE`.
+        let server_database_url = self.server_database_url.clone();
+        let sql = format!(
+            "DROP DATABASE IF EXISTS {} WITH (FORCE)",
+            self.database_name
+        );
+        let result = std::thread::spawn(move || {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?
+                .block_on(async {
+                    let server_conn = sea_orm::Database::connect(&server_database_url).await?;
+                    server_conn.execute_unprepared(&sql).await?;
+                    server_conn.close().await?;
+                    Ok::<_, anyhow::Error>(())
+                })
+        })
+        .join();
+        if !matches!(result, Ok(Ok(()))) {
+            eprintln!("failed to drop test database {}", self.database_name);
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ReceivedEmail {
+    pub from: String,
+    pub to: Vec<String>,
+    pub data: String,
+}
+
+/// Just enough of a SMTP server to receive emails sent by `lettre` over plain SMTP.
+pub struct SmtpStandIn {
+    port: u16,
+    emails: Arc<Mutex<Vec<ReceivedEmail>>>,
+    _handle: AbortOnDrop,
+}
+
+struct AbortOnDrop(tokio::task::JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+impl SmtpStandIn {
+    pub async fn start() -> SmtpStandIn {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let emails: Arc<Mutex<Vec<ReceivedEmail>>> = Default::default();
+        let emails_for_server = emails.clone();
+        let handle = tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let emails = emails_for_server.clone();
+                tokio::spawn(async move {
+                    let _ = Self::handle_connection(stream, emails).await;
+                });
+            }
+        });
+        SmtpStandIn {
+            port,
+            emails,
+            _handle: AbortOnDrop(handle),
+        }
+    }
+
+    async fn handle_connection(
+        stream: tokio::net::TcpStream,
+        emails: Arc<Mutex<Vec<ReceivedEmail>>>,
+    ) -> std::io::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        writer.write_all(b"220 localhost ESMTP\r\n").await?;
+        let mut email = ReceivedEmail {
+            from: String::new(),
+            to: Vec::new(),
+            data: String::new(),
+        };
+        while let Some(line) = lines.next_line().await? {
+            let command = line.to_ascii_uppercase();
+            let reply: &[u8] = if command.starts_with("EHLO") || command.starts_with("HELO") {
+                b"250 localhost\r\n"
+            } else if command.starts_with("MAIL FROM:") {
+                email.from = line["MAIL FROM:".len()..].trim().to_string();
+                b"250 OK\r\n"
+            } else if command.starts_with("RCPT TO:") {
+                email.to.push(line["RCPT TO:".len()..].trim().to_string());
+                b"250 OK\r\n"
+            } else if command == "DATA" {
+                writer
+                    .write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n")
+                    .await?;
+                while let Some(line) = lines.next_line().await? {
+                    if line == "." {
+                        break;
+                    }
+                    email.data.push_str(&line);
+                    email.data.push('\n');
+                }
+                emails.lock().unwrap().push(email.clone());
+                b"250 OK\r\n"
+            } else if command == "QUIT" {
+                writer.write_all(b"221 Bye\r\n").await?;
+                return Ok(());
+            } else {
+                b"250 OK\r\n"
+            };
+            writer.write_all(reply).await?;
+        }
+        Ok(())
+    }
+
+    pub fn url(&self) -> String {
+        format!("smtp://127.0.0.1:{}", self.port)
+    }
+
+    pub fn emails(&self) -> Vec<ReceivedEmail> {
+        self.emails.lock().unwrap().clone()
+    }
+}
+
+/// Where the tests that need an S3 compatible storage put their objects, e.g. a MinIO with
+/// `TEST_S3_ENDPOINT=http://127.0.0.1:9000`. The bucket must exist.
+pub fn s3_config() -> file_storage::S3Config {
+    let var = |name: &str, default: &str| std::env::var(name).unwrap_or(default.to_string());
+    file_storage::S3Config {
+        endpoint: std::env::var("TEST_S3_ENDPOINT").unwrap(),
+        bucket: var("TEST_S3_BUCKET", "fog-machine"),
+        region: var("TEST_S3_REGION", "us-east-1"),
+        access_key_id: var("TEST_S3_ACCESS_KEY_ID", "minioadmin"),
+        secret_access_key: var("TEST_S3_SECRET_ACCESS_KEY", "minioadmin"),
+        allow_http: true,
+    }
+}
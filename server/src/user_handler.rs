@@ -1,4 +1,5 @@
 use crate::pool::Db;
+use crate::sync_blob;
 use crate::user_handler::PendingRegistration::Github;
 use crate::utils;
 use crate::{APIResponse, InternalError, ServerState};
@@ -41,7 +42,7 @@ struct JwtData {
     exp: i64,
 }
 
-fn generate_user_token(server_state: &ServerState, user_id: i64) -> String {
+pub(crate) fn generate_user_token(server_state: &ServerState, user_id: i64) -> String {
     let exp = chrono::Utc::now()
         .checked_add_signed(chrono::Duration::days(7))
         .expect("valid timestamp")
@@ -258,6 +259,9 @@ async fn sso(
                 contact_email: Set(contact_email),
                 github_uid: Set(Some(github_uid)),
                 language: Set(data.language),
+                notify_by_email: Set(true),
+                notification_webhook_url: Set(None),
+                sync_file_storage_limit_mib: Set(None),
                 created_at: Set(chrono::offset::Utc::now()),
                 updated_at: Set(chrono::offset::Utc::now()),
             };
@@ -300,6 +304,9 @@ async fn user(
                 contact_email: Set("user@example.com".to_owned()),
                 github_uid: Set(None),
                 language: Set(entity::user::Language::EnUs),
+                notify_by_email: Set(true),
+                notification_webhook_url: Set(None),
+                sync_file_storage_limit_mib: Set(None),
                 created_at: Set(chrono::offset::Utc::now()),
                 updated_at: Set(chrono::offset::Utc::now()),
             };
@@ -307,6 +314,8 @@ async fn user(
         }
     }
 
+    let storage_usage = sync_blob::usage(db, &user).await?;
+    let storage_limit = sync_blob::quota(db, &server_state.limits, &user).await?;
     let user = entity::user::Entity::find()
         .filter(entity::user::Column::Id.eq(user.uid))
         .one(db)
@@ -315,10 +324,53 @@ async fn user(
 
     Ok((
         Status::Ok,
-        json!({"email": user.email, "contact_email": user.contact_email, "language": user.language, "created_at": user.created_at, "updated_at": user.updated_at }),
+        json!({"email": user.email, "contact_email": user.contact_email, "language": user.language, "notify_by_email": user.notify_by_email, "notification_webhook_url": user.notification_webhook_url, "notification_webhook_secret": server_state.notifier.webhook_secret(user.id), "storage_usage": storage_usage, "storage_limit": storage_limit, "created_at": user.created_at, "updated_at": user.updated_at }),
     ))
 }
 
+#[derive(Deserialize)]
+struct UpdateData {
+    notify_by_email: Option<bool>,
+    // `null` removes the webhook, a missing field leaves it untouched.
+    #[serde(default, deserialize_with = "crate::utils::deserialize_some")]
+    notification_webhook_url: Option<Option<String>>,
+}
+
+#[patch("/", data = "<data>")]
+async fn update(
+    conn: Connection<'_, Db>,
+    server_state: &rocket::State<ServerState>,
+    user: User,
+    data: Json<UpdateData>,
+) -> APIResponse {
+    if let Some(Some(url)) = &data.notification_webhook_url {
+        if !server_state.notifier.is_valid_webhook_url(url).await {
+            return Ok((Status::BadRequest, json!({"error": "invalid_webhook_url"})));
+        }
+    }
+    let db = conn.into_inner();
+    let res = entity::user::Entity::update(entity::user::ActiveModel {
+        id: Set(user.uid),
+        notify_by_email: match data.notify_by_email {
+            None => NotSet,
+            Some(notify_by_email) => Set(notify_by_email),
+        },
+        notification_webhook_url: match &data.notification_webhook_url {
+            None => NotSet,
+            Some(url) => Set(url.clone()),
+        },
+        updated_at: Set(chrono::offset::Utc::now()),
+        ..Default::default()
+    })
+    .exec(db)
+    .await;
+    match res {
+        Ok(_) => Ok((Status::Ok, json!({}))),
+        Err(sea_orm::DbErr::RecordNotUpdated) => Ok((Status::NotFound, json!({}))),
+        Err(error) => Err(error.into()),
+    }
+}
+
 pub fn routes() -> Vec<rocket::Route> {
-    routes![sso_github, sso_github_redirect, sso, user]
+    routes![sso_github, sso_github_redirect, sso, user, update]
 }
@@ -1,3 +1,7 @@
+use reqwest::Url;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
 pub fn random_token(validate_token: impl Fn(&str) -> bool) -> String {
     use rand::distributions::{Alphanumeric, DistString};
     loop {
@@ -7,3 +11,161 @@ pub fn random_token(validate_token: impl Fn(&str) -> bool) -> String {
         }
     }
 }
+
+/// Tells a missing field apart from an explicit `null` when used with `#[serde(default)]` on an
+/// `Option<Option<T>>` field.
+pub fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: serde::Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    serde::Deserialize::deserialize(deserializer).map(Some)
+}
+
+/// Whether `ip` is reachable from the public internet. Urls provided by users must not point to
+/// anything else (loopback, private networks, link-local etc.), otherwise they could make the
+/// server talk to internal services.
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                // "this network" (0.0.0.0/8) and shared address space (100.64.0.0/10)
+                || octets[0] == 0
+                || (octets[0] == 100 && (octets[1] & 0xc0) == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_ip(IpAddr::V4(ip)),
+            None => {
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local())
+            }
+        },
+    }
+}
+
+/// Whether the host of `url` is a public address or a domain that resolves to one.
+pub async fn is_public_url(url: &str) -> bool {
+    let url = match Url::parse(url) {
+        Ok(url) => url,
+        Err(_) => return false,
+    };
+    if let Some(ip) = host_ip(&url) {
+        return is_public_ip(ip);
+    }
+    match url.host_str() {
+        None => false,
+        Some(domain) => match tokio::net::lookup_host((domain, 0)).await {
+            Ok(mut addrs) => addrs.any(|addr| is_public_ip(addr.ip())),
+            Err(_) => false,
+        },
+    }
+}
+
+fn host_ip(url: &Url) -> Option<IpAddr> {
+    url.host_str()?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .ok()
+}
+
+/// Fails if the host of `url` is an ip address that is not public, domains are checked by
+/// `public_http_client` when they are resolved.
+pub fn check_url_host(url: &Url) -> Result<(), anyhow::Error> {
+    match host_ip(url) {
+        Some(ip) if !is_public_ip(ip) => Err(anyhow!("{} is not a public address", ip)),
+        _ => Ok(()),
+    }
+}
+
+struct PublicOnlyResolver;
+
+impl reqwest::dns::Resolve for PublicOnlyResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| is_public_ip(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", host).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// A client for user provided urls, it only connects to public addresses (see `is_public_ip`),
+/// including when following redirects. Requests to an ip address must still be checked with
+/// `check_url_host` first.
+pub fn public_http_client(allow_private_addresses: bool) -> reqwest::Client {
+    public_http_client_builder(allow_private_addresses)
+        .build()
+        .unwrap()
+}
+
+/// `public_http_client`, for clients that need more settings.
+pub fn public_http_client_builder(allow_private_addresses: bool) -> reqwest::ClientBuilder {
+    if allow_private_addresses {
+        return reqwest::Client::builder();
+    }
+    reqwest::Client::builder()
+        .dns_resolver(Arc::new(PublicOnlyResolver))
+        .redirect(reqwest::redirect::Policy::custom(|attempt| {
+            if attempt.previous().len() >= 10 {
+                attempt.error("too many redirects")
+            } else if let Err(error) = check_url_host(attempt.url()) {
+                attempt.error(error)
+            } else {
+                attempt.follow()
+            }
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_public_ip() {
+        for ip in ["1.1.1.1", "2606:4700:4700::1111", "::ffff:8.8.8.8"] {
+            assert!(is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_is_public_url() {
+        assert!(is_public_url("https://1.1.1.1/dav").await);
+        assert!(!is_public_url("http://127.0.0.1:8080/dav").await);
+        assert!(!is_public_url("http://[::1]/dav").await);
+        assert!(!is_public_url("http://localhost/dav").await);
+        assert!(!is_public_url("not a url").await);
+    }
+}